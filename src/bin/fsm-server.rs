@@ -0,0 +1,72 @@
+//! Hosts named lathes and mills and serves them over the line-delimited JSON protocol.
+//!
+//! ```text
+//! fsm-server [--tcp ADDR | --unix PATH] [--lathe NAME]... [--mill NAME]...
+//! ```
+//! Without machine arguments one lathe `lathe-1` and one mill `mill-1` are hosted.
+
+use fsm::machines::lathe::LatheController;
+use fsm::machines::mill::FsmController;
+use fsm::remote::server::{Registry, Server};
+
+use std::net::TcpListener;
+use std::process::ExitCode;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(String),
+}
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("fsm-server: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut endpoint = Endpoint::Tcp(String::from(DEFAULT_ADDRESS));
+    let mut registry = Registry::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--tcp" => endpoint = Endpoint::Tcp(value()?),
+            #[cfg(unix)]
+            "--unix" => endpoint = Endpoint::Unix(value()?),
+            "--lathe" => registry.add(&value()?, LatheController::create(Box::default())),
+            "--mill" => registry.add(&value()?, FsmController::create(Box::default())),
+            _ => return Err(format!("unknown argument {}", arg).into()),
+        }
+    }
+
+    if registry.names().next().is_none() {
+        registry.add("lathe-1", LatheController::create(Box::default()));
+        registry.add("mill-1", FsmController::create(Box::default()));
+    }
+
+    let mut names: Vec<_> = registry.names().map(String::from).collect();
+    names.sort();
+    let server = Server::new(registry);
+
+    match endpoint {
+        Endpoint::Tcp(address) => {
+            let listener = TcpListener::bind(&address)?;
+            println!("Serving {} on tcp://{}", names.join(", "), address);
+            server.serve_tcp(listener)?;
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            let listener = std::os::unix::net::UnixListener::bind(&path)?;
+            println!("Serving {} on unix://{}", names.join(", "), path);
+            server.serve_unix(listener)?;
+        }
+    }
+    Ok(())
+}
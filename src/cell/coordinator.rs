@@ -0,0 +1,625 @@
+//! Coordinator for several machines working in one cell
+//!
+//! Each machine keeps its own controller and thread; the cell only routes commands to them by
+//! id and tags whatever comes back, so lathes, mills and remote machines can be mixed freely.
+//! Commands and responses cross the cell as JSON values, the same representation the wire
+//! protocol uses.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::TypeId;
+use std::sync::{Mutex, MutexGuard};
+
+use super::interlock::{Interlock, variant};
+use super::zone::{Guard, Zone};
+use crate::machines::shared::{
+    Controller, EmergencyCommands, KindedCommand, SpindleCommands, StateName,
+};
+use crate::remote::erased::{Erased, ErasedController, to_value};
+use crate::remote::protocol::{Outcome, ProtocolError};
+
+/// A response or error together with the machine it came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tagged {
+    pub machine: String,
+    #[serde(flatten)]
+    pub outcome: Outcome<Value>,
+}
+
+/// A machine of the cell with the commands that stop its spindle, if it has one
+struct Member {
+    controller: Box<dyn ErasedController + Send>,
+    stop_spindle: Vec<Value>,
+}
+
+impl Member {
+    /// Runs the spindle stop sequence and yields the last answer, or `None` for a machine
+    /// added without one.
+    fn stop_spindle(&self) -> Option<Outcome<Value>> {
+        let mut last = None;
+        for cmd in &self.stop_spindle {
+            let outcome = self.controller.request(cmd.clone());
+            if let Outcome::Error(_) = outcome {
+                return Some(outcome);
+            }
+            last = Some(outcome);
+        }
+        last
+    }
+}
+
+/// Machines of one cell, addressed by id
+///
+/// A cell can be shared between threads. Every command it sends to a machine goes through one
+/// lock, see the [`interlock`](super::interlock) module for what that guarantees.
+#[derive(Default)]
+pub struct Cell {
+    /// Ids of the machines, in the same order as `Machines::members`
+    ids: Vec<String>,
+    zones: Vec<Zone>,
+    interlocks: Vec<Interlock>,
+    machines: Mutex<Machines>,
+}
+
+/// The part of a cell only reached under its lock
+#[derive(Default)]
+struct Machines {
+    members: Vec<Member>,
+    /// Answers to emergency stops sent by a zone, not yet collected
+    alarms: Vec<Tagged>,
+    /// Responses of zone members the cell already observed, not yet collected
+    observed: Vec<Tagged>,
+}
+
+impl Cell {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a machine under `id`, replacing any machine added before under the same id.
+    ///
+    /// [`Cell::stop_all_spindles`] leaves the machine alone, see [`Cell::add_spindle`].
+    pub fn add<Command, Response>(
+        &mut self,
+        id: &str,
+        controller: impl Controller<Command, Response> + Send + 'static,
+    ) where
+        Command: Clone + DeserializeOwned + 'static,
+        Response: Serialize + 'static,
+    {
+        self.insert(id, controller, Vec::new());
+    }
+
+    /// Adds a machine with a spindle under `id`, replacing any machine added before under the
+    /// same id.
+    pub fn add_spindle<Command, Response>(
+        &mut self,
+        id: &str,
+        controller: impl Controller<Command, Response> + Send + 'static,
+    ) where
+        Command: SpindleCommands + Serialize + DeserializeOwned,
+        Response: Serialize + 'static,
+    {
+        let stop_spindle = Command::STOP_SPINDLE.iter().map(to_value).collect();
+        self.insert(id, controller, stop_spindle);
+    }
+
+    fn insert<Command, Response>(
+        &mut self,
+        id: &str,
+        controller: impl Controller<Command, Response> + Send + 'static,
+        stop_spindle: Vec<Value>,
+    ) where
+        Command: DeserializeOwned + 'static,
+        Response: Serialize + 'static,
+    {
+        let member = Member {
+            controller: Box::new(Erased::new(controller)),
+            stop_spindle,
+        };
+        let members = &mut self.machines.get_mut().expect(POISONED).members;
+        match self.ids.iter().position(|known| known == id) {
+            Some(index) => members[index] = member,
+            None => {
+                self.ids.push(id.to_string());
+                members.push(member);
+            }
+        }
+    }
+
+    /// Ids of all machines in the order they were added
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.ids.iter().map(String::as_str)
+    }
+
+    /// Puts a machine of the cell into the safety zone `zone`, creating the zone on first use.
+    ///
+    /// `Command` must be the command type of the machine added under `machine`, otherwise the
+    /// zone would send it an emergency stop it can't take.
+    pub fn add_to_zone<Command>(&mut self, zone: &str, machine: &str) -> Result<(), ProtocolError>
+    where
+        Command: EmergencyCommands + Serialize + 'static,
+    {
+        self.check_command_type::<Command>(machine)?;
+        let index = match self.zones.iter().position(|known| known.name() == zone) {
+            Some(index) => index,
+            None => {
+                self.zones.push(Zone::new(zone));
+                self.zones.len() - 1
+            }
+        };
+        let zone = &mut self.zones[index];
+        if zone.guard(machine).is_none() {
+            zone.guards.push(Guard::new::<Command>(machine));
+        }
+        Ok(())
+    }
+
+    pub fn zone(&self, name: &str) -> Option<&Zone> {
+        self.zones.iter().find(|zone| zone.name() == name)
+    }
+
+    /// Lets `machine` take commands of kind `command` only while `allows` accepts the states
+    /// of `other`.
+    ///
+    /// `Command` must be the command type of the machine added under `machine`. The states are
+    /// read once `other` has handled everything the cell sent it, and a machine that cannot
+    /// report its states never allows anything. `allows` runs under the cell's lock and must
+    /// not use the cell itself.
+    pub fn add_interlock<Command>(
+        &mut self,
+        machine: &str,
+        command: Command::Kind,
+        other: &str,
+        allows: impl Fn(&[StateName]) -> bool + Send + Sync + 'static,
+    ) -> Result<(), ProtocolError>
+    where
+        Command: KindedCommand + 'static,
+    {
+        self.check_command_type::<Command>(machine)?;
+        self.index(other)?;
+        self.interlocks.push(Interlock {
+            machine: machine.to_string(),
+            command: Command::kind_name(command).to_string(),
+            other: other.to_string(),
+            allows: Box::new(allows),
+        });
+        Ok(())
+    }
+
+    fn check_command_type<Command: 'static>(&mut self, machine: &str) -> Result<(), ProtocolError> {
+        let index = self.index(machine)?;
+        let members = &self.machines.get_mut().expect(POISONED).members;
+        if members[index].controller.command_type() != TypeId::of::<Command>() {
+            return Err(ProtocolError::Malformed(format!(
+                "{} takes other commands than {}",
+                machine,
+                std::any::type_name::<Command>()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sends a command without waiting; its response shows up in [`Cell::check_responses`].
+    ///
+    /// A member of a safety zone handles the command before this returns, so an emergency stop
+    /// reaches the rest of its zone right away.
+    pub fn send_command(&self, machine: &str, cmd: impl Serialize) -> Result<(), ProtocolError> {
+        let cmd = to_value(&cmd);
+        let mut guard = self.lock();
+        let machines = &mut *guard;
+        self.observe_zones(machines);
+        self.check_released(machine, &cmd)?;
+        self.check_interlocks(&machines.members, machine, &cmd)?;
+        let member = self.member(&machines.members, machine)?;
+        if !self.in_zone(machine) {
+            return member.send_command(cmd);
+        }
+        let reply = Tagged {
+            machine: machine.to_string(),
+            outcome: member.request(cmd),
+        };
+        if let Outcome::Error(err) = reply.outcome {
+            return Err(err);
+        }
+        self.observe(&machines.members, &mut machines.alarms, &reply);
+        machines.observed.push(reply);
+        self.observe_zones(machines);
+        Ok(())
+    }
+
+    /// Sends a command to one machine and waits for its answer.
+    pub fn request(&self, machine: &str, cmd: impl Serialize) -> Tagged {
+        let cmd = to_value(&cmd);
+        let mut guard = self.lock();
+        let machines = &mut *guard;
+        self.observe_zones(machines);
+        let outcome = match self
+            .check_released(machine, &cmd)
+            .and_then(|_| self.check_interlocks(&machines.members, machine, &cmd))
+            .and_then(|_| self.member(&machines.members, machine))
+        {
+            Ok(member) => member.request(cmd),
+            Err(err) => Outcome::Error(err),
+        };
+        let reply = Tagged {
+            machine: machine.to_string(),
+            outcome,
+        };
+        self.observe(&machines.members, &mut machines.alarms, &reply);
+        self.observe_zones(machines);
+        reply
+    }
+
+    /// Collects the responses of all machines, grouped by machine in the order they were added.
+    ///
+    /// Answers to emergency stops a safety zone sent come first.
+    pub fn check_responses(&self) -> Vec<Tagged> {
+        let mut guard = self.lock();
+        let machines = &mut *guard;
+        self.observe_zones(machines);
+        let mut responses = std::mem::take(&mut machines.observed);
+        for (id, member) in self.ids.iter().zip(&machines.members) {
+            for response in member.controller.check_responses() {
+                let response = Tagged {
+                    machine: id.clone(),
+                    outcome: Outcome::Response(response),
+                };
+                self.observe(&machines.members, &mut machines.alarms, &response);
+                responses.push(response);
+            }
+        }
+        responses.sort_by_key(|response| self.ids.iter().position(|id| *id == response.machine));
+        let mut alarms = std::mem::take(&mut machines.alarms);
+        alarms.append(&mut responses);
+        alarms
+    }
+
+    /// Acknowledges every machine of the zone, releasing the zone only once none of them
+    /// remains in its emergency state.
+    pub fn acknowledge_zone(&self, zone: &str) -> Result<Vec<Tagged>, ProtocolError> {
+        let mut guard = self.lock();
+        let machines = &mut *guard;
+        self.observe_zones(machines);
+        let zone = self
+            .zone(zone)
+            .ok_or_else(|| ProtocolError::UnknownZone(zone.to_string()))?;
+        let replies: Vec<Tagged> = zone
+            .guards
+            .iter()
+            .map(|guard| Tagged {
+                machine: guard.machine.clone(),
+                outcome: match self.member(&machines.members, &guard.machine) {
+                    Ok(member) => member.request(guard.acknowledge.clone()),
+                    Err(err) => Outcome::Error(err),
+                },
+            })
+            .collect();
+        let released = zone.guards.iter().zip(&replies).all(|(guard, reply)| {
+            matches!(&reply.outcome, Outcome::Response(response) if !guard.in_emergency(response))
+        });
+        if released {
+            zone.release();
+        }
+        self.observe_zones(machines);
+        Ok(replies)
+    }
+
+    /// Stops every spindle in the cell, yielding one answer per machine with a spindle.
+    ///
+    /// A machine that cannot be reached does not keep the others running.
+    pub fn stop_all_spindles(&self) -> Vec<Tagged> {
+        let mut guard = self.lock();
+        let machines = &mut *guard;
+        self.observe_zones(machines);
+        let replies = self
+            .ids
+            .iter()
+            .zip(&machines.members)
+            .filter_map(|(id, member)| {
+                member.stop_spindle().map(|outcome| Tagged {
+                    machine: id.clone(),
+                    outcome,
+                })
+            })
+            .collect();
+        self.observe_zones(machines);
+        replies
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Machines> {
+        self.machines.lock().expect(POISONED)
+    }
+
+    /// Observes the responses zone members sent since the cell last looked, like those to
+    /// internal events and deferred commands, and keeps them for [`Cell::check_responses`].
+    fn observe_zones(&self, machines: &mut Machines) {
+        for (id, member) in self.ids.iter().zip(&machines.members) {
+            if !self.in_zone(id) {
+                continue;
+            }
+            for response in member.controller.check_responses() {
+                let response = Tagged {
+                    machine: id.clone(),
+                    outcome: Outcome::Response(response),
+                };
+                self.observe(&machines.members, &mut machines.alarms, &response);
+                machines.observed.push(response);
+            }
+        }
+    }
+
+    fn in_zone(&self, machine: &str) -> bool {
+        self.zones.iter().any(|zone| zone.guard(machine).is_some())
+    }
+
+    /// Trips the zones of a machine that entered its emergency state and stops their other
+    /// machines, following on into the zones those machines share with others.
+    fn observe(&self, members: &[Member], alarms: &mut Vec<Tagged>, reply: &Tagged) {
+        let Outcome::Response(response) = &reply.outcome else {
+            return;
+        };
+        for zone in &self.zones {
+            let emergency = zone
+                .guard(&reply.machine)
+                .is_some_and(|guard| guard.entered_emergency(response));
+            if !emergency || !zone.trip() {
+                continue;
+            }
+            for guard in zone
+                .guards
+                .iter()
+                .filter(|guard| guard.machine != reply.machine)
+            {
+                let outcome = match self.member(members, &guard.machine) {
+                    Ok(member) => member.request_priority(guard.emergency.clone()),
+                    Err(err) => Outcome::Error(err),
+                };
+                let alarm = Tagged {
+                    machine: guard.machine.clone(),
+                    outcome,
+                };
+                self.observe(members, alarms, &alarm);
+                alarms.push(alarm);
+            }
+        }
+    }
+
+    /// Refuses every command but the emergency stop to a machine of a tripped zone, so only
+    /// [`Cell::acknowledge_zone`] can release it.
+    fn check_released(&self, machine: &str, cmd: &Value) -> Result<(), ProtocolError> {
+        let tripped = self.zones.iter().find(|zone| {
+            zone.is_tripped()
+                && zone
+                    .guard(machine)
+                    .is_some_and(|guard| &guard.emergency != cmd)
+        });
+        match tripped {
+            Some(zone) => Err(ProtocolError::ZoneTripped(zone.name().to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses a command while the states of another machine forbid it.
+    ///
+    /// Runs under the cell's lock like the command it guards, taking the states of every
+    /// machine the command's interlocks name before judging any of them.
+    fn check_interlocks(
+        &self,
+        members: &[Member],
+        machine: &str,
+        cmd: &Value,
+    ) -> Result<(), ProtocolError> {
+        let command = variant(cmd);
+        let guarding: Vec<&Interlock> = self
+            .interlocks
+            .iter()
+            .filter(|interlock| interlock.machine == machine && interlock.command == command)
+            .collect();
+        let mut snapshot: Vec<(&str, Vec<StateName>)> = Vec::new();
+        for interlock in &guarding {
+            if snapshot.iter().all(|(other, _)| *other != interlock.other) {
+                let states = self.member(members, &interlock.other)?.active_states()?;
+                snapshot.push((&interlock.other, states));
+            }
+        }
+        for interlock in guarding {
+            let (_, states) = snapshot
+                .iter()
+                .find(|(other, _)| *other == interlock.other)
+                .expect("states of every guarding machine are taken");
+            if states.is_empty() || !(interlock.allows)(states) {
+                return Err(ProtocolError::Interlocked(interlock.other.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self, machine: &str) -> Result<usize, ProtocolError> {
+        self.ids
+            .iter()
+            .position(|id| id == machine)
+            .ok_or_else(|| ProtocolError::UnknownMachine(machine.to_string()))
+    }
+
+    fn member<'m>(
+        &self,
+        members: &'m [Member],
+        machine: &str,
+    ) -> Result<&'m dyn ErasedController, ProtocolError> {
+        Ok(members[self.index(machine)?].controller.as_ref())
+    }
+}
+
+const POISONED: &str = "a thread panicked while sending through the cell";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::{LatheCommand, LatheController};
+    use crate::machines::mill::{FsmController, MillCommand};
+    use serde_json::json;
+
+    fn setup() -> Cell {
+        let mut cell = Cell::new();
+        cell.add_spindle("lathe-1", LatheController::create(Box::default()));
+        cell.add_spindle("mill-1", FsmController::create(Box::default()));
+        cell
+    }
+
+    fn response(machine: &str, response: Value) -> Tagged {
+        Tagged {
+            machine: machine.to_string(),
+            outcome: Outcome::Response(response),
+        }
+    }
+
+    mod routing {
+        use super::*;
+
+        #[test]
+        fn request_reaches_addressed_machine() {
+            let cell = setup();
+
+            let reply = cell.request("mill-1", MillCommand::StartSpinning(800));
+
+            assert_eq!(
+                reply,
+                response("mill-1", json!({"Status": {"state": "Spinning"}}))
+            );
+            assert_eq!(
+                cell.request("lathe-1", LatheCommand::Feed(5)),
+                response(
+                    "lathe-1",
+                    json!({"InvalidTransition": {"current_state": "Off", "attempted_command": {"Feed": 5}}})
+                )
+            );
+        }
+
+        #[test]
+        fn unknown_machine_is_reported() {
+            let cell = setup();
+
+            let reply = cell.request("lathe-9", LatheCommand::Notaus);
+
+            assert_eq!(
+                reply.outcome,
+                Outcome::Error(ProtocolError::UnknownMachine("lathe-9".to_string()))
+            );
+            assert_eq!(
+                cell.send_command("lathe-9", LatheCommand::Notaus),
+                Err(ProtocolError::UnknownMachine("lathe-9".to_string()))
+            );
+        }
+
+        #[test]
+        fn command_of_another_machine_is_malformed() {
+            let cell = setup();
+
+            let reply = cell.request("lathe-1", MillCommand::Move(3));
+
+            assert!(matches!(
+                reply.outcome,
+                Outcome::Error(ProtocolError::Malformed(_))
+            ));
+        }
+
+        #[test]
+        fn responses_are_tagged_with_their_machine() {
+            let cell = setup();
+
+            cell.send_command("mill-1", MillCommand::StartSpinning(800))
+                .unwrap();
+            cell.send_command("lathe-1", json!({"StartSpinning": 1000}))
+                .unwrap();
+            cell.send_command("mill-1", MillCommand::Move(-50)).unwrap();
+
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            assert_eq!(
+                cell.check_responses(),
+                [
+                    response("lathe-1", json!({"Status": {"state": "Spinning"}})),
+                    response("mill-1", json!({"Status": {"state": "Spinning"}})),
+                    response("mill-1", json!({"Status": {"state": "Moving"}})),
+                ]
+            );
+        }
+
+        #[test]
+        fn adding_an_id_again_replaces_the_machine() {
+            let mut cell = setup();
+            cell.request("mill-1", MillCommand::StartSpinning(800));
+
+            cell.add_spindle("mill-1", FsmController::create(Box::default()));
+
+            assert_eq!(cell.ids().collect::<Vec<_>>(), ["lathe-1", "mill-1"]);
+            assert_eq!(
+                cell.request("mill-1", MillCommand::StopSpinning),
+                response(
+                    "mill-1",
+                    json!({"InvalidTransition": {"current_state": "Off", "attempted_command": "StopSpinning"}})
+                )
+            );
+        }
+    }
+
+    mod stop_all_spindles {
+        use super::*;
+
+        #[test]
+        fn running_machines_end_up_off() {
+            let cell = setup();
+            cell.request("lathe-1", LatheCommand::StartSpinning(1000));
+            cell.request("lathe-1", LatheCommand::Feed(200));
+            cell.request("mill-1", MillCommand::StartSpinning(800));
+
+            let replies = cell.stop_all_spindles();
+
+            assert_eq!(
+                replies,
+                [
+                    response("lathe-1", json!({"Status": {"state": "Off"}})),
+                    response("mill-1", json!({"Status": {"state": "Off"}})),
+                ]
+            );
+        }
+
+        #[test]
+        fn stopped_machines_only_reject() {
+            let cell = setup();
+
+            let replies = cell.stop_all_spindles();
+
+            assert_eq!(replies.len(), 2);
+            assert_eq!(
+                replies[0],
+                response(
+                    "lathe-1",
+                    json!({"InvalidTransition": {"current_state": "Off", "attempted_command": "StopSpinning"}})
+                )
+            );
+        }
+
+        #[test]
+        fn machines_without_spindle_are_left_alone() {
+            let mut cell = setup();
+            cell.add("mill-2", FsmController::create(Box::default()));
+            cell.request("mill-2", MillCommand::StartSpinning(800));
+
+            let replies = cell.stop_all_spindles();
+
+            assert_eq!(
+                replies
+                    .iter()
+                    .map(|reply| reply.machine.as_str())
+                    .collect::<Vec<_>>(),
+                ["lathe-1", "mill-1"]
+            );
+            assert_eq!(
+                cell.request("mill-2", MillCommand::Move(-50)),
+                response("mill-2", json!({"Status": {"state": "Moving"}}))
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Interlocks between the machines of a cell
+//!
+//! An interlock guards one command of a machine with a predicate over the states of another
+//! machine, like "the lathe may only start spinning while the door is closed".
+//!
+//! The check and the guarded command are atomic. Every command the cell sends goes through
+//! one lock, which is held from reading the states until the guarded command is forwarded.
+//! The states of all machines a command's interlocks name are taken under that lock, once
+//! each machine handled everything the cell sent it: its internal events have run and its
+//! deferred commands wait for a transition, which only another command could cause. The cell
+//! owns the controllers of its machines, so no such command reaches them in between; a
+//! machine behind a server must have the cell as its only client.
+
+use serde_json::Value;
+
+use crate::machines::shared::StateName;
+
+/// Decides from another machine's states whether a command may pass
+pub(crate) type Predicate = Box<dyn Fn(&[StateName]) -> bool + Send + Sync>;
+
+/// A command of one machine guarded by the states of another
+pub(crate) struct Interlock {
+    pub(crate) machine: String,
+    pub(crate) command: String,
+    pub(crate) other: String,
+    pub(crate) allows: Predicate,
+}
+
+/// The variant name of a serialized command, like `StartSpinning` in `{"StartSpinning":1000}`
+pub(crate) fn variant(cmd: &Value) -> &str {
+    match cmd {
+        Value::String(name) => name,
+        Value::Object(fields) => fields.keys().next().map_or("", String::as_str),
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cell::coordinator::{Cell, Tagged};
+    use crate::machines::lathe::{LatheCommand, LatheCommandKind, LatheController};
+    use crate::machines::shared::{
+        FSM, MachineController, StateHandler, StateInfo, StateName, Transition, command_kind, fsm,
+    };
+    use crate::remote::client::RemoteLatheController;
+    use crate::remote::protocol::{Outcome, ProtocolError};
+    use crate::remote::server::{Registry, Server};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use std::marker::PhantomData;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub struct Open;
+    #[derive(Debug)]
+    pub struct Closed;
+
+    #[derive(Default, Debug)]
+    pub struct DoorData;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub enum DoorCommand {
+        Open,
+        Close,
+    }
+
+    command_kind! {
+        DoorCommand => DoorCommandKind { Open, Close }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub enum DoorResponse {
+        Status {
+            state: StateName,
+        },
+        InvalidTransition {
+            current_state: StateName,
+            attempted_command: DoorCommand,
+        },
+    }
+
+    fsm! {
+        StartState: Open,
+        MachineData: DoorData,
+        MachineCommand: DoorCommand,
+        CommandKind: DoorCommandKind,
+        MachineResponse: DoorResponse,
+        StateHandlerTrait: StateHandler,
+        Controller: MachineController,
+        Open: {
+            Close => close(self) -> Closed,
+        },
+        Closed: {
+            Open => open(self) -> Open,
+        },
+    }
+
+    fn setup() -> Cell {
+        let mut cell = Cell::new();
+        cell.add("lathe-1", LatheController::create(Box::default()));
+        cell.add("door", FsmController::create(Box::default()));
+        cell.add_interlock::<LatheCommand>(
+            "lathe-1",
+            LatheCommandKind::StartSpinning,
+            "door",
+            |states| states.contains(&"Closed"),
+        )
+        .unwrap();
+        cell
+    }
+
+    fn status(machine: &str, state: &str) -> Tagged {
+        Tagged {
+            machine: machine.to_string(),
+            outcome: Outcome::Response(json!({"Status": {"state": state}})),
+        }
+    }
+
+    #[test]
+    fn guarded_command_is_refused_while_other_machine_forbids_it() {
+        let cell = setup();
+
+        let reply = cell.request("lathe-1", LatheCommand::StartSpinning(1000));
+
+        assert_eq!(
+            reply.outcome,
+            Outcome::Error(ProtocolError::Interlocked("door".to_string()))
+        );
+        assert_eq!(
+            cell.send_command("lathe-1", LatheCommand::StartSpinning(1000)),
+            Err(ProtocolError::Interlocked("door".to_string()))
+        );
+    }
+
+    #[test]
+    fn guarded_command_passes_once_other_machine_allows_it() {
+        let cell = setup();
+        cell.request("door", DoorCommand::Close);
+
+        let reply = cell.request("lathe-1", LatheCommand::StartSpinning(1000));
+
+        assert_eq!(reply, status("lathe-1", "Spinning"));
+    }
+
+    #[test]
+    fn guard_sees_commands_still_queued_for_other_machine() {
+        let cell = setup();
+        cell.send_command("door", DoorCommand::Close).unwrap();
+
+        let reply = cell.request("lathe-1", LatheCommand::StartSpinning(1000));
+
+        assert_eq!(reply, status("lathe-1", "Spinning"));
+    }
+
+    #[test]
+    fn racing_command_waits_for_the_guarded_one() {
+        let (checking_tx, checking_rx) = mpsc::channel();
+        let mut cell = Cell::new();
+        cell.add("lathe-1", LatheController::create(Box::default()));
+        cell.add("door", FsmController::create(Box::default()));
+        cell.add_interlock::<LatheCommand>(
+            "lathe-1",
+            LatheCommandKind::StartSpinning,
+            "door",
+            move |states| {
+                let _ = checking_tx.send(());
+                thread::sleep(Duration::from_millis(50));
+                states.contains(&"Closed")
+            },
+        )
+        .unwrap();
+        cell.add_interlock::<DoorCommand>("door", DoorCommandKind::Open, "lathe-1", |states| {
+            !states.contains(&"Spinning")
+        })
+        .unwrap();
+        cell.request("door", DoorCommand::Close);
+
+        let (started, opened) = thread::scope(|scope| {
+            let started =
+                scope.spawn(|| cell.request("lathe-1", LatheCommand::StartSpinning(1000)));
+            checking_rx.recv().unwrap();
+            let opened = cell.request("door", DoorCommand::Open);
+            (started.join().unwrap(), opened)
+        });
+
+        assert_eq!(started, status("lathe-1", "Spinning"));
+        assert_eq!(
+            opened.outcome,
+            Outcome::Error(ProtocolError::Interlocked("lathe-1".to_string()))
+        );
+    }
+
+    #[test]
+    fn other_commands_are_not_guarded() {
+        let cell = setup();
+
+        let reply = cell.request("lathe-1", LatheCommand::Notaus);
+
+        assert_eq!(reply, status("lathe-1", "Notaus"));
+    }
+
+    #[test]
+    fn forbidding_states_can_be_named() {
+        let mut cell = setup();
+        cell.add_interlock::<DoorCommand>("door", DoorCommandKind::Open, "lathe-1", |states| {
+            !states.contains(&"Spinning")
+        })
+        .unwrap();
+        cell.request("door", DoorCommand::Close);
+        cell.request("lathe-1", LatheCommand::StartSpinning(1000));
+
+        let reply = cell.request("door", DoorCommand::Open);
+
+        assert_eq!(
+            reply.outcome,
+            Outcome::Error(ProtocolError::Interlocked("lathe-1".to_string()))
+        );
+    }
+
+    #[test]
+    fn unknown_machines_cannot_be_interlocked() {
+        let mut cell = setup();
+
+        let result =
+            cell.add_interlock::<LatheCommand>("lathe-1", LatheCommandKind::Feed, "robot", |_| {
+                true
+            });
+
+        assert_eq!(
+            result,
+            Err(ProtocolError::UnknownMachine("robot".to_string()))
+        );
+    }
+
+    #[test]
+    fn command_kind_of_another_machine_is_refused() {
+        let mut cell = setup();
+
+        let result =
+            cell.add_interlock::<DoorCommand>("lathe-1", DoorCommandKind::Open, "door", |_| true);
+
+        assert!(matches!(result, Err(ProtocolError::Malformed(_))));
+        assert_eq!(
+            cell.request("lathe-1", LatheCommand::Notaus),
+            status("lathe-1", "Notaus")
+        );
+    }
+
+    #[test]
+    fn remote_machine_reports_its_states() {
+        let mut registry = Registry::new();
+        registry.add("lathe-2", LatheController::create(Box::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(registry);
+        std::thread::spawn(move || server.serve_tcp(listener));
+        let mut cell = setup();
+        cell.add(
+            "lathe-2",
+            RemoteLatheController::connect_tcp(address, "lathe-2").unwrap(),
+        );
+        cell.add_interlock::<DoorCommand>("door", DoorCommandKind::Open, "lathe-2", |states| {
+            !states.contains(&"Spinning")
+        })
+        .unwrap();
+        cell.request("door", DoorCommand::Close);
+        cell.request("lathe-2", LatheCommand::StartSpinning(1000));
+
+        let refused = cell.request("door", DoorCommand::Open);
+        cell.request("lathe-2", LatheCommand::StopSpinning);
+        let passed = cell.request("door", DoorCommand::Open);
+
+        assert_eq!(
+            refused.outcome,
+            Outcome::Error(ProtocolError::Interlocked("lathe-2".to_string()))
+        );
+        assert_eq!(passed, status("door", "Open"));
+    }
+}
@@ -0,0 +1,388 @@
+//! Safety zones of a cell
+//!
+//! Machines sharing a safety zone stop together: as soon as the cell sees one of them report
+//! its emergency state, the others receive their emergency command ahead of anything still
+//! queued. The cell looks at the responses of zone members on every call, including those to
+//! internal events, and commands sent to them are handled before `send_command` returns.
+//!
+//! The zone stays tripped until it is acknowledged as a whole. Until then its machines refuse
+//! every command but the emergency stop, so a single machine cannot be restarted while its
+//! neighbours are still down.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::machines::shared::{EmergencyCommands, StateName};
+use crate::remote::erased::to_value;
+
+/// How to stop and release one machine of a zone
+#[derive(Debug)]
+pub(crate) struct Guard {
+    pub(crate) machine: String,
+    pub(crate) emergency: Value,
+    pub(crate) acknowledge: Value,
+    pub(crate) state: StateName,
+}
+
+impl Guard {
+    pub(crate) fn new<Command: EmergencyCommands + Serialize>(machine: &str) -> Self {
+        Self {
+            machine: machine.to_string(),
+            emergency: to_value(&Command::EMERGENCY),
+            acknowledge: to_value(&Command::ACKNOWLEDGE),
+            state: Command::EMERGENCY_STATE,
+        }
+    }
+
+    /// Whether `response` reports a transition into the emergency state
+    pub(crate) fn entered_emergency(&self, response: &Value) -> bool {
+        field(response, "state") == Some(self.state)
+    }
+
+    /// Whether `response` reports the machine in its emergency state, even if it rejected
+    /// the command
+    pub(crate) fn in_emergency(&self, response: &Value) -> bool {
+        self.entered_emergency(response) || field(response, "current_state") == Some(self.state)
+    }
+}
+
+/// Machines that must stop together
+#[derive(Debug)]
+pub struct Zone {
+    name: String,
+    pub(crate) guards: Vec<Guard>,
+    tripped: AtomicBool,
+}
+
+impl Zone {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            guards: Vec::new(),
+            tripped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Ids of the machines in the zone in the order they joined
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.guards.iter().map(|guard| guard.machine.as_str())
+    }
+
+    /// Whether a member went into its emergency state since the last acknowledge
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn guard(&self, machine: &str) -> Option<&Guard> {
+        self.guards.iter().find(|guard| guard.machine == machine)
+    }
+
+    /// Marks the zone tripped, returning `false` if it already was.
+    pub(crate) fn trip(&self) -> bool {
+        !self.tripped.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn release(&self) {
+        self.tripped.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A string field of a serialized response, like `state` in `{"Status":{"state":"Notaus"}}`
+fn field<'a>(response: &'a Value, name: &str) -> Option<&'a str> {
+    let (_, fields) = response.as_object()?.iter().next()?;
+    fields.get(name)?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cell::coordinator::{Cell, Tagged};
+    use crate::machines::lathe::{LatheCommand, LatheController, LatheResponse};
+    use crate::machines::mill::{FsmController, MillCommand};
+    use crate::machines::shared::Controller;
+    use crate::remote::client::RemoteLatheController;
+    use crate::remote::protocol::{Outcome, ProtocolError};
+    use crate::remote::server::{Registry, Server};
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    fn setup() -> Cell {
+        let mut cell = Cell::new();
+        for id in ["lathe-1", "lathe-2", "lathe-3"] {
+            cell.add(id, LatheController::create(Box::default()));
+        }
+        cell.add_to_zone::<LatheCommand>("a", "lathe-1").unwrap();
+        cell.add_to_zone::<LatheCommand>("a", "lathe-2").unwrap();
+        cell
+    }
+
+    fn status(machine: &str, state: &str) -> Tagged {
+        Tagged {
+            machine: machine.to_string(),
+            outcome: Outcome::Response(json!({"Status": {"state": state}})),
+        }
+    }
+
+    mod tripping {
+        use super::*;
+
+        #[test]
+        fn emergency_on_one_member_stops_the_others() {
+            let cell = setup();
+            cell.request("lathe-2", LatheCommand::StartSpinning(1000));
+            cell.request("lathe-3", LatheCommand::StartSpinning(1000));
+
+            let reply = cell.request("lathe-1", LatheCommand::Notaus);
+
+            assert_eq!(reply, status("lathe-1", "Notaus"));
+            assert!(cell.zone("a").unwrap().is_tripped());
+            assert_eq!(cell.check_responses(), [status("lathe-2", "Notaus")]);
+            assert_eq!(
+                cell.request("lathe-3", LatheCommand::Feed(10)),
+                status("lathe-3", "Feeding")
+            );
+        }
+
+        #[test]
+        fn emergency_seen_among_collected_responses_trips_the_zone() {
+            let cell = setup();
+
+            cell.send_command("lathe-2", LatheCommand::Notaus).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+
+            assert_eq!(
+                cell.check_responses(),
+                [status("lathe-1", "Notaus"), status("lathe-2", "Notaus")]
+            );
+            assert!(cell.zone("a").unwrap().is_tripped());
+        }
+
+        #[test]
+        fn sent_emergency_stops_the_others_without_polling() {
+            let cell = setup();
+            cell.request("lathe-2", LatheCommand::StartSpinning(1000));
+
+            cell.send_command("lathe-1", LatheCommand::Notaus).unwrap();
+
+            assert!(cell.zone("a").unwrap().is_tripped());
+            assert_eq!(
+                cell.check_responses(),
+                [status("lathe-2", "Notaus"), status("lathe-1", "Notaus")]
+            );
+        }
+
+        #[test]
+        fn rejected_command_in_emergency_does_not_trip() {
+            let mut cell = Cell::new();
+            for id in ["lathe-1", "lathe-2"] {
+                cell.add(id, LatheController::create(Box::default()));
+            }
+            cell.request("lathe-1", LatheCommand::Notaus);
+            cell.add_to_zone::<LatheCommand>("a", "lathe-1").unwrap();
+            cell.add_to_zone::<LatheCommand>("a", "lathe-2").unwrap();
+            cell.request("lathe-2", LatheCommand::StartSpinning(1000));
+
+            cell.request("lathe-1", LatheCommand::Feed(1));
+
+            assert!(!cell.zone("a").unwrap().is_tripped());
+            assert_eq!(cell.check_responses(), []);
+            assert_eq!(
+                cell.request("lathe-2", LatheCommand::Feed(10)),
+                status("lathe-2", "Feeding")
+            );
+        }
+
+        #[test]
+        fn overlapping_zones_trip_each_other() {
+            let mut cell = setup();
+            cell.add_to_zone::<LatheCommand>("b", "lathe-2").unwrap();
+            cell.add_to_zone::<LatheCommand>("b", "lathe-3").unwrap();
+
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            assert!(cell.zone("b").unwrap().is_tripped());
+            assert_eq!(
+                cell.check_responses(),
+                [status("lathe-3", "Notaus"), status("lathe-2", "Notaus")]
+            );
+        }
+
+        #[test]
+        fn remote_member_is_stopped() {
+            let mut registry = Registry::new();
+            registry.add("lathe-4", LatheController::create(Box::default()));
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = Server::new(registry);
+            std::thread::spawn(move || server.serve_tcp(listener));
+            let mut cell = setup();
+            cell.add(
+                "lathe-4",
+                RemoteLatheController::connect_tcp(address, "lathe-4").unwrap(),
+            );
+            cell.add_to_zone::<LatheCommand>("a", "lathe-4").unwrap();
+
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            assert_eq!(
+                cell.check_responses(),
+                [status("lathe-2", "Notaus"), status("lathe-4", "Notaus")]
+            );
+        }
+
+        #[test]
+        fn mill_and_lathe_stop_together() {
+            let mut cell = setup();
+            cell.add("mill-1", FsmController::create(Box::default()));
+            cell.add_to_zone::<MillCommand>("a", "mill-1").unwrap();
+            cell.request("mill-1", MillCommand::StartSpinning(800));
+
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            assert_eq!(
+                cell.check_responses(),
+                [status("lathe-2", "Notaus"), status("mill-1", "Notaus")]
+            );
+            assert_eq!(
+                cell.acknowledge_zone("a").unwrap(),
+                [
+                    status("lathe-1", "Off"),
+                    status("lathe-2", "Off"),
+                    status("mill-1", "Off")
+                ]
+            );
+        }
+    }
+
+    mod acknowledge {
+        use super::*;
+
+        struct Unacknowledgeable(LatheController);
+
+        impl Controller<LatheCommand, LatheResponse> for Unacknowledgeable {
+            fn send_command(&self, cmd: LatheCommand) -> Result<(), &'static str> {
+                self.0.send_command(cmd)
+            }
+
+            fn check_responses(&self) -> Vec<LatheResponse> {
+                self.0.check_responses()
+            }
+
+            fn request(&self, cmd: LatheCommand) -> Result<LatheResponse, &'static str> {
+                match cmd {
+                    LatheCommand::Acknowledge => Err("Acknowledge lost"),
+                    cmd => self.0.request(cmd),
+                }
+            }
+        }
+
+        #[test]
+        fn single_member_cannot_be_acknowledged_while_tripped() {
+            let cell = setup();
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            let reply = cell.request("lathe-1", LatheCommand::Acknowledge);
+
+            assert_eq!(
+                reply.outcome,
+                Outcome::Error(ProtocolError::ZoneTripped("a".to_string()))
+            );
+            assert_eq!(
+                cell.send_command("lathe-2", LatheCommand::Acknowledge),
+                Err(ProtocolError::ZoneTripped("a".to_string()))
+            );
+        }
+
+        #[test]
+        fn tripped_zone_refuses_every_command_but_the_emergency() {
+            let cell = setup();
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            assert_eq!(
+                cell.request("lathe-2", LatheCommand::StartSpinning(10))
+                    .outcome,
+                Outcome::Error(ProtocolError::ZoneTripped("a".to_string()))
+            );
+            assert_eq!(
+                cell.request("lathe-2", LatheCommand::Notaus).outcome,
+                Outcome::Response(
+                    json!({"InvalidTransition": {"current_state": "Notaus", "attempted_command": "Notaus"}})
+                )
+            );
+        }
+
+        #[test]
+        fn partial_acknowledge_keeps_the_zone_tripped() {
+            let mut cell = setup();
+            cell.add(
+                "lathe-4",
+                Unacknowledgeable(LatheController::create(Box::default())),
+            );
+            cell.add_to_zone::<LatheCommand>("a", "lathe-4").unwrap();
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            let replies = cell.acknowledge_zone("a").unwrap();
+
+            assert_eq!(
+                replies[..2],
+                [status("lathe-1", "Off"), status("lathe-2", "Off")]
+            );
+            assert!(cell.zone("a").unwrap().is_tripped());
+            assert_eq!(
+                cell.request("lathe-1", LatheCommand::StartSpinning(10))
+                    .outcome,
+                Outcome::Error(ProtocolError::ZoneTripped("a".to_string()))
+            );
+        }
+
+        #[test]
+        fn zone_acknowledge_releases_every_member() {
+            let cell = setup();
+            cell.request("lathe-1", LatheCommand::Notaus);
+
+            let replies = cell.acknowledge_zone("a").unwrap();
+
+            assert_eq!(
+                replies,
+                [status("lathe-1", "Off"), status("lathe-2", "Off")]
+            );
+            assert!(!cell.zone("a").unwrap().is_tripped());
+            assert_eq!(
+                cell.request("lathe-1", LatheCommand::StartSpinning(10)),
+                status("lathe-1", "Spinning")
+            );
+        }
+
+        #[test]
+        fn unknown_zone_and_machine_are_reported() {
+            let mut cell = setup();
+
+            assert_eq!(
+                cell.acknowledge_zone("z"),
+                Err(ProtocolError::UnknownZone("z".to_string()))
+            );
+            assert_eq!(
+                cell.add_to_zone::<LatheCommand>("a", "lathe-9"),
+                Err(ProtocolError::UnknownMachine("lathe-9".to_string()))
+            );
+            assert_eq!(
+                cell.zone("a").unwrap().members().collect::<Vec<_>>(),
+                ["lathe-1", "lathe-2"]
+            );
+        }
+
+        #[test]
+        fn command_type_of_another_machine_is_refused() {
+            let mut cell = setup();
+
+            let result = cell.add_to_zone::<MillCommand>("b", "lathe-1");
+
+            assert!(matches!(result, Err(ProtocolError::Malformed(_))));
+            assert!(cell.zone("b").is_none());
+        }
+    }
+}
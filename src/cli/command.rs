@@ -0,0 +1,137 @@
+//! Text syntax for machine commands
+//!
+//! Operators type commands as `start_spinning 1000` instead of `StartSpinning(1000)`. The text
+//! is mapped onto the serde representation of the command enum, so any machine whose commands
+//! derive `Deserialize` can be driven without writing a parser.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Parses a line like `feed 500` into a command of the machine.
+pub fn parse_command<Command: DeserializeOwned>(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or("empty command")?;
+    let variant = variant_name(name);
+    let mut args: Vec<Value> = words.map(argument).collect();
+
+    let value = match args.len() {
+        0 => Value::String(variant),
+        1 => Value::Object([(variant, args.remove(0))].into_iter().collect()),
+        _ => Value::Object([(variant, Value::Array(args))].into_iter().collect()),
+    };
+
+    serde_json::from_value(value).map_err(|err| format!("{}: {}", name, err))
+}
+
+/// Text form of a command as `parse_command` reads it, e.g. `feed 500` for `Feed(500)`.
+pub fn format_command<Command: Serialize>(cmd: &Command) -> String {
+    match serde_json::to_value(cmd) {
+        Ok(Value::String(variant)) => command_name(&variant),
+        Ok(Value::Object(fields)) => fields
+            .into_iter()
+            .map(|(variant, args)| {
+                let args = match args {
+                    Value::Array(args) => args,
+                    arg => vec![arg],
+                };
+                std::iter::once(command_name(&variant))
+                    .chain(args.iter().map(Value::to_string))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+/// Operator facing name of a command variant, e.g. `start_spinning` for `StartSpinning`.
+pub fn command_name(variant: &str) -> String {
+    let mut name = String::new();
+    for (i, c) in variant.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.extend(c.to_lowercase());
+    }
+    name
+}
+
+fn variant_name(command: &str) -> String {
+    command
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn argument(word: &str) -> Value {
+    serde_json::from_str(word).unwrap_or_else(|_| Value::String(word.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::LatheCommand;
+    use crate::machines::mill::MillCommand;
+
+    #[test]
+    fn command_with_argument() {
+        let cmd = parse_command::<LatheCommand>("start_spinning 1000");
+
+        assert_eq!(cmd, Ok(LatheCommand::StartSpinning(1000)));
+    }
+
+    #[test]
+    fn command_without_argument() {
+        let cmd = parse_command::<LatheCommand>("  notaus ");
+
+        assert_eq!(cmd, Ok(LatheCommand::Notaus));
+    }
+
+    #[test]
+    fn negative_argument() {
+        let cmd = parse_command::<MillCommand>("move -50");
+
+        assert_eq!(cmd, Ok(MillCommand::Move(-50)));
+    }
+
+    #[test]
+    fn unknown_command() {
+        let cmd = parse_command::<MillCommand>("feed 300");
+
+        assert!(cmd.is_err());
+    }
+
+    #[test]
+    fn missing_argument() {
+        let cmd = parse_command::<LatheCommand>("feed");
+
+        assert!(cmd.is_err());
+    }
+
+    #[test]
+    fn formatted_command_parses_back() {
+        for cmd in [
+            MillCommand::StartSpinning(1000),
+            MillCommand::Move(-50),
+            MillCommand::Notaus,
+        ] {
+            let text = format_command(&cmd);
+
+            assert_eq!(parse_command::<MillCommand>(&text), Ok(cmd));
+        }
+        assert_eq!(format_command(&LatheCommand::Feed(500)), "feed 500");
+    }
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(command_name("StartSpinning"), "start_spinning");
+        assert_eq!(variant_name("start_spinning"), "StartSpinning");
+    }
+}
@@ -0,0 +1,132 @@
+//! Interactive shell for driving a single machine
+//!
+//! The machine runs in the shell's own thread, so every response is shown as soon as the
+//! command is entered and the current state can always be queried.
+
+use std::fmt::Debug;
+use std::io::{self, BufRead, Write};
+
+use serde::de::DeserializeOwned;
+
+use super::command::{command_name, parse_command};
+use crate::machines::shared::{StateHandler, StateInfo};
+
+const HELP: &str = "\
+state     show the current state
+commands  list the commands valid in the current state
+help      show this help
+quit      leave the shell
+Any other input is sent to the machine, e.g. `start_spinning 1000`.";
+
+/// Reads operator input until `quit` or end of input and applies it to `machine`.
+pub fn run<Command, Response, Wrapper>(
+    name: &str,
+    mut machine: Wrapper,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()>
+where
+    Command: DeserializeOwned,
+    Response: Debug,
+    Wrapper: StateHandler<Command, Response, Wrapper> + StateInfo,
+    Wrapper::CommandKind: Debug,
+{
+    let mut lines = input.lines();
+    loop {
+        write!(output, "{} [{}]> ", name, machine.state_name())?;
+        output.flush()?;
+
+        let Some(line) = lines.next() else {
+            writeln!(output)?;
+            return Ok(());
+        };
+        let line = line?;
+
+        match line.trim() {
+            "" => {}
+            "quit" | "exit" => return Ok(()),
+            "help" => writeln!(output, "{}", HELP)?,
+            "state" => writeln!(output, "{}", machine.state_name())?,
+            "commands" => {
+                let commands: Vec<String> = machine
+                    .allowed_commands()
+                    .iter()
+                    .map(|kind| command_name(&format!("{:?}", kind)))
+                    .collect();
+                writeln!(output, "{}", commands.join(", "))?;
+            }
+            text => match parse_command::<Command>(text) {
+                Ok(cmd) => {
+                    let (next, response) = machine.handle_cmd(cmd);
+                    machine = next;
+                    writeln!(output, "{:?}", response)?;
+                }
+                Err(err) => writeln!(output, "error: {}", err)?,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::LatheWrapper;
+    use crate::machines::mill::FsmWrapper;
+
+    fn run_lathe(input: &str) -> String {
+        let mut output = Vec::new();
+        run(
+            "lathe",
+            LatheWrapper::new(Box::default()),
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn command_changes_prompt() {
+        let output = run_lathe("start_spinning 1000\nquit\n");
+
+        assert_eq!(
+            output,
+            "lathe [Off]> Status { state: \"Spinning\" }\nlathe [Spinning]> "
+        );
+    }
+
+    #[test]
+    fn lists_commands_of_current_state() {
+        let output = run_lathe("commands\nstart_spinning 1000\ncommands\n");
+
+        assert!(output.contains("start_spinning, notaus\n"));
+        assert!(output.contains("feed, stop_spinning, notaus\n"));
+    }
+
+    #[test]
+    fn invalid_transition_is_shown() {
+        let output = run_lathe("feed 300\nstate\n");
+
+        assert!(output.contains(
+            "InvalidTransition { current_state: \"Off\", attempted_command: Feed(300) }"
+        ));
+        assert!(output.contains("lathe [Off]> Off\n"));
+    }
+
+    #[test]
+    fn parse_error_keeps_state() {
+        let mut output = Vec::new();
+
+        run(
+            "mill",
+            FsmWrapper::new(Box::default()),
+            "start_spinning fast\nstate\n".as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("error: start_spinning"));
+        assert!(output.contains("mill [Off]> Off\n"));
+    }
+}
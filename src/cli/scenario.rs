@@ -0,0 +1,366 @@
+//! Plain-text acceptance scenarios
+//!
+//! QA engineers describe machine behavior as a list of commands and the outcome each one must
+//! have, using the same command syntax as the REPL:
+//! ```text
+//! # Emergency stop while feeding
+//! machine lathe
+//! start_spinning 1000 -> Spinning
+//! feed 500            -> Feeding
+//! acknowledge         -> invalid
+//! notaus              -> Notaus
+//! state Notaus
+//! ```
+//! * `<command> -> <State>` expects the machine to report `<State>`
+//! * `<command> -> invalid` expects the command to be rejected
+//! * `<command>` alone sends the command without checking the response
+//! * `state <State>` checks that the machine is in `<State>` once it handled every command
+//! * `machine <name>` names the machine type the scenario is written for
+
+use std::fmt::{self, Debug, Display};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::command::parse_command;
+use crate::machines::shared::Controller;
+
+/// A parsed scenario file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub machine: Option<String>,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Send {
+        line: usize,
+        command: String,
+        expect: Option<Expectation>,
+    },
+    State {
+        line: usize,
+        state: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expectation {
+    State(String),
+    Invalid,
+}
+
+/// A step whose outcome differs from the scenario
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Scenario {
+    /// Parses a scenario, reporting the first malformed line.
+    pub fn parse(text: &str) -> Result<Self, Mismatch> {
+        let mut scenario = Scenario {
+            machine: None,
+            steps: Vec::new(),
+        };
+
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let content = raw.split('#').next().unwrap_or_default().trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            let malformed = |message: &str| Mismatch {
+                line,
+                message: message.to_string(),
+            };
+            let mut words = content.split_whitespace();
+            match words.next() {
+                Some("machine") => {
+                    let name = words
+                        .next()
+                        .ok_or_else(|| malformed("machine needs a name"))?;
+                    scenario.machine = Some(name.to_string());
+                }
+                Some("state") => {
+                    let state = words
+                        .next()
+                        .ok_or_else(|| malformed("state needs a name"))?;
+                    scenario.steps.push(Step::State {
+                        line,
+                        state: state.to_string(),
+                    });
+                }
+                _ => {
+                    let (command, expect) = match content.split_once("->") {
+                        None => (content, None),
+                        Some((command, state)) => match state.trim() {
+                            "" => return Err(malformed("-> needs a state or `invalid`")),
+                            "invalid" => (command, Some(Expectation::Invalid)),
+                            state => (command, Some(Expectation::State(state.to_string()))),
+                        },
+                    };
+                    scenario.steps.push(Step::Send {
+                        line,
+                        command: command.trim().to_string(),
+                        expect,
+                    });
+                }
+            }
+        }
+        Ok(scenario)
+    }
+
+    /// Number of commands and state checks
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Plays the scenario against a controller and collects every deviation.
+    ///
+    /// All steps are executed even after a mismatch, so one run reports all problems.
+    pub fn run<Command, Response>(
+        &self,
+        controller: &impl Controller<Command, Response>,
+    ) -> Vec<Mismatch>
+    where
+        Command: DeserializeOwned,
+        Response: Serialize + Debug,
+    {
+        let mut mismatches = Vec::new();
+
+        for step in &self.steps {
+            match step {
+                Step::Send {
+                    line,
+                    command,
+                    expect,
+                } => {
+                    let mismatch = |message: String| Mismatch {
+                        line: *line,
+                        message,
+                    };
+                    let cmd = match parse_command::<Command>(command) {
+                        Ok(cmd) => cmd,
+                        Err(err) => {
+                            mismatches.push(mismatch(err));
+                            continue;
+                        }
+                    };
+                    let response = match controller.request(cmd) {
+                        Ok(response) => response,
+                        Err(err) => {
+                            mismatches.push(mismatch(err.to_string()));
+                            continue;
+                        }
+                    };
+
+                    let outcome = Outcome::of(&response);
+                    match (expect, &outcome) {
+                        (None, _) => {}
+                        (Some(Expectation::Invalid), Outcome::Invalid) => {}
+                        (Some(Expectation::State(expected)), Outcome::Status(state))
+                            if expected == state => {}
+                        (Some(expected), _) => mismatches.push(mismatch(format!(
+                            "expected {}, got {:?}",
+                            expected, response
+                        ))),
+                    }
+                }
+                Step::State { line, state } => {
+                    let message = match controller.active_states() {
+                        Ok(states) if states.contains(&state.as_str()) => continue,
+                        Ok(states) if states.is_empty() => {
+                            format!("expected state {}, machine reported no states", state)
+                        }
+                        Ok(states) => {
+                            format!(
+                                "expected state {}, machine is in {}",
+                                state,
+                                states.join("+")
+                            )
+                        }
+                        Err(err) => err.to_string(),
+                    };
+                    mismatches.push(Mismatch {
+                        line: *line,
+                        message,
+                    });
+                }
+            }
+        }
+        mismatches
+    }
+}
+
+/// Scenario line expecting what `response` reports for `command`, e.g. `feed 500 -> Feeding`.
+pub fn step_line(command: &str, response: &impl Serialize) -> String {
+    match Outcome::of(response) {
+        Outcome::Status(state) => format!("{} -> {}", command, state),
+        Outcome::Invalid => format!("{} -> invalid", command),
+        Outcome::Other => command.to_string(),
+    }
+}
+
+impl Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::State(state) => write!(f, "{}", state),
+            Expectation::Invalid => write!(f, "invalid transition"),
+        }
+    }
+}
+
+/// The parts of a machine response a scenario can check
+enum Outcome {
+    Status(String),
+    Invalid,
+    Other,
+}
+
+impl Outcome {
+    /// Reads the response through its serde representation, which every machine response
+    /// shares.
+    fn of(response: &impl Serialize) -> Self {
+        let value = serde_json::to_value(response).unwrap_or_default();
+        let field = |variant: &str, field: &str| {
+            value
+                .get(variant)
+                .and_then(|fields| fields.get(field))
+                .and_then(Value::as_str)
+                .map(String::from)
+        };
+
+        if let Some(state) = field("Status", "state") {
+            Outcome::Status(state)
+        } else if value.get("InvalidTransition").is_some() {
+            Outcome::Invalid
+        } else {
+            Outcome::Other
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::{LatheCommand, LatheController, LatheResponse};
+    use crate::machines::mill::FsmController;
+
+    #[test]
+    fn lathe_scenario_passes() {
+        let scenario = Scenario::parse(include_str!("../../scenarios/lathe.scenario")).unwrap();
+        let controller = LatheController::create(Box::default());
+
+        let mismatches = scenario.run(&controller);
+
+        assert_eq!(scenario.machine.as_deref(), Some("lathe"));
+        assert_eq!(mismatches, vec![]);
+        controller.shutdown().unwrap();
+    }
+
+    #[test]
+    fn mill_scenario_passes() {
+        let scenario = Scenario::parse(include_str!("../../scenarios/mill.scenario")).unwrap();
+        let controller = FsmController::create(Box::default());
+
+        let mismatches = scenario.run(&controller);
+
+        assert_eq!(scenario.machine.as_deref(), Some("mill"));
+        assert_eq!(mismatches, vec![]);
+        controller.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reports_every_mismatch() {
+        let scenario = Scenario::parse(
+            "start_spinning 1000 -> Feeding\n\
+             feed 500 -> invalid\n\
+             state Spinning\n\
+             fly_away\n",
+        )
+        .unwrap();
+        let controller = LatheController::create(Box::default());
+
+        let mismatches = scenario.run(&controller);
+
+        let lines: Vec<usize> = mismatches.iter().map(|m| m.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4]);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "line 1: expected Feeding, got Status { state: \"Spinning\" }"
+        );
+        controller.shutdown().unwrap();
+    }
+
+    #[test]
+    fn state_before_first_command() {
+        let scenario = Scenario::parse("state Off").unwrap();
+        let controller = LatheController::create(Box::default());
+
+        let mismatches = scenario.run(&controller);
+
+        assert_eq!(mismatches, vec![]);
+        controller.shutdown().unwrap();
+    }
+
+    #[test]
+    fn state_asks_the_machine() {
+        let scenario = Scenario::parse(
+            "start_spinning 1000
+state Feeding",
+        )
+        .unwrap();
+        let controller = LatheController::create(Box::default());
+
+        let mismatches = scenario.run(&controller);
+
+        assert_eq!(
+            mismatches[0].message,
+            "expected state Feeding, machine is in Spinning"
+        );
+        controller.shutdown().unwrap();
+    }
+
+    #[test]
+    fn step_line_expects_the_reported_outcome() {
+        let spinning = LatheResponse::Status { state: "Spinning" };
+        let rejected = LatheResponse::InvalidTransition {
+            current_state: "Off",
+            attempted_command: LatheCommand::Feed(1),
+        };
+
+        assert_eq!(
+            step_line("start_spinning 10", &spinning),
+            "start_spinning 10 -> Spinning"
+        );
+        assert_eq!(step_line("feed 1", &rejected), "feed 1 -> invalid");
+    }
+
+    #[test]
+    fn malformed_line() {
+        let result = Scenario::parse("# comment\n\nfeed 1 ->");
+
+        assert_eq!(
+            result,
+            Err(Mismatch {
+                line: 3,
+                message: String::from("-> needs a state or `invalid`"),
+            })
+        );
+    }
+}
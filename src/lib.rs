@@ -1,2 +1,5 @@
 #![doc = include_str!("../README.md")]
+pub mod cell;
+pub mod cli;
 pub mod machines;
+pub mod remote;
@@ -0,0 +1,149 @@
+//! Safety scenarios every machine with an emergency stop has to pass
+//!
+//! The cell trips and releases safety zones relying on the same behavior from each machine,
+//! so the scenarios are written once against [`SafetySubject`] and run for every machine.
+
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use serde::Serialize;
+
+use super::model::ModelChecker;
+use super::property::{Property, Rng};
+use super::shared::{EmergencyCommands, StateHandler, StateInfo, StateName, drive};
+
+/// A machine wrapper the safety scenarios can drive
+pub(super) trait SafetySubject:
+    StateHandler<Self::Command, Self::Response, Self> + StateInfo<CommandKind: Copy> + Sized
+{
+    type Command: EmergencyCommands + Clone + PartialEq + Debug + Serialize;
+    type Response: Debug + Serialize;
+    /// The part of the machine data the model checker tells apart
+    type Abstract: Eq + Hash;
+
+    /// Name `fsm run-scenario` knows the machine by
+    const MACHINE: &'static str;
+
+    /// Commands leading from the start state into each state outside the emergency
+    const PATHS: &'static [&'static [Self::Command]];
+
+    /// Every command kind of the machine
+    const KINDS: &'static [Self::CommandKind];
+
+    /// Commands standing in for a kind
+    fn commands(kind: Self::CommandKind) -> Vec<Self::Command>;
+
+    /// Any command of the machine, for random sequences
+    fn random_command(rng: &mut Rng) -> Self::Command;
+
+    fn abstraction(&self) -> Self::Abstract;
+
+    fn fresh() -> Self;
+
+    /// Whether the machine data is back to its defaults
+    fn is_reset(&self) -> bool;
+}
+
+fn start_state<M: SafetySubject>() -> StateName {
+    M::fresh().state_name()
+}
+
+pub(super) fn emergency_stops_every_state<M: SafetySubject>() {
+    for path in M::PATHS {
+        let machine = drive(M::fresh(), path.iter().cloned()).0;
+        assert_ne!(
+            machine.state_name(),
+            M::Command::EMERGENCY_STATE,
+            "{path:?}"
+        );
+
+        let (machine, _) = machine.handle_cmd(M::Command::EMERGENCY);
+
+        assert_eq!(
+            machine.state_name(),
+            M::Command::EMERGENCY_STATE,
+            "{path:?}"
+        );
+    }
+}
+
+pub(super) fn acknowledge_resets_to_start<M: SafetySubject>() {
+    for path in M::PATHS {
+        let machine = drive(M::fresh(), path.iter().cloned()).0;
+        let machine = drive(machine, [M::Command::EMERGENCY, M::Command::ACKNOWLEDGE]).0;
+
+        assert_eq!(machine.state_name(), start_state::<M>(), "{path:?}");
+        assert!(machine.is_reset(), "{path:?}");
+    }
+}
+
+pub(super) fn emergency_only_accepts_acknowledge<M: SafetySubject>() {
+    let leaving: Vec<_> = M::TRANSITIONS
+        .iter()
+        .filter(|transition| transition.from == M::Command::EMERGENCY_STATE)
+        .collect();
+    assert_eq!(leaving.len(), 1);
+    assert_eq!(leaving[0].to, start_state::<M>());
+
+    let commands = M::KINDS
+        .iter()
+        .flat_map(|&kind| M::commands(kind))
+        .filter(|cmd| *cmd != M::Command::ACKNOWLEDGE);
+    for cmd in commands {
+        let machine = drive(M::fresh(), [M::Command::EMERGENCY]).0;
+
+        let (machine, _) = machine.handle_cmd(cmd.clone());
+
+        assert_eq!(machine.state_name(), M::Command::EMERGENCY_STATE, "{cmd:?}");
+    }
+}
+
+pub(super) fn paths_cover_every_state<M: SafetySubject>() {
+    let declared: BTreeSet<StateName> = M::TRANSITIONS
+        .iter()
+        .filter(|transition| transition.history.is_none())
+        .map(|transition| transition.to)
+        .chain([start_state::<M>()])
+        .collect();
+
+    let reached: BTreeSet<StateName> = M::PATHS
+        .iter()
+        .map(|path| drive(M::fresh(), path.iter().cloned()).0.state_name())
+        .chain([M::Command::EMERGENCY_STATE])
+        .collect();
+
+    assert_eq!(reached, declared);
+}
+
+/// The emergency stop invariants on random command sequences.
+pub(super) fn property_safety<M: SafetySubject>() {
+    Property::new(M::fresh, M::random_command)
+        .invariant("off means reset", |machine| {
+            machine.state_name() != start_state::<M>() || machine.is_reset()
+        })
+        .reachable("notaus reachable", &[M::Command::EMERGENCY], |machine| {
+            machine.state_name() == M::Command::EMERGENCY_STATE
+        })
+        .reachable(
+            "acknowledge resets",
+            &[M::Command::EMERGENCY, M::Command::ACKNOWLEDGE],
+            M::is_reset,
+        )
+        .check();
+}
+
+/// The emergency stop invariants on every reachable combination, returning how many there
+/// are.
+pub(super) fn model_safety<M: SafetySubject>() -> usize {
+    ModelChecker::new(M::MACHINE, M::fresh, M::commands, M::abstraction)
+        .invariant("off means reset", |machine| {
+            machine.state_name() != start_state::<M>() || machine.is_reset()
+        })
+        .reachable(
+            "notaus and acknowledge reset",
+            &[M::Command::EMERGENCY, M::Command::ACKNOWLEDGE],
+            M::is_reset,
+        )
+        .check()
+}
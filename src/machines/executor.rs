@@ -0,0 +1,328 @@
+//! Worker pool running many machines on a few threads
+//!
+//! A machine on an [`Executor`] has no thread of its own. Sending it a message schedules the
+//! machine, and the next free worker handles everything that is waiting for it. A machine is
+//! handled by one worker at a time, so its messages keep their order while hundreds of machines
+//! share a handful of threads.
+//!
+//! A worker hands a busy machine back to the queue after a batch of messages, so a machine that
+//! is flooded with commands cannot keep the others waiting. A machine that panics is dropped,
+//! which makes every later call on its controller fail, and its worker carries on.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, mpsc};
+use std::thread::{self, JoinHandle};
+
+/// A machine as seen by the workers
+pub(super) trait Runnable {
+    /// Handles the next waiting message, `false` if there is none.
+    fn run_one(&mut self) -> bool;
+}
+
+/// Messages a worker handles for one machine before the other machines get a turn
+const BATCH: usize = 64;
+
+struct Task {
+    /// `None` once the machine panicked
+    machine: Mutex<Option<Box<dyn Runnable + Send>>>,
+    /// Set while the task waits in the queue or a worker is about to handle it
+    scheduled: AtomicBool,
+    queue: mpsc::Sender<Arc<Task>>,
+}
+
+/// Schedules one machine after a message was sent to it
+pub(super) struct Waker {
+    task: Arc<Task>,
+}
+
+impl Waker {
+    pub(super) fn wake(&self) {
+        if !self.task.scheduled.swap(true, Ordering::SeqCst) {
+            let _ = self.task.queue.send(Arc::clone(&self.task));
+        }
+    }
+}
+
+/// Fixed pool of worker threads shared by many machines
+pub struct Executor {
+    queue: mpsc::Sender<Arc<Task>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Executor {
+    /// Starts `workers` threads, at least one.
+    pub fn new(workers: usize) -> Self {
+        let (queue, tasks) = mpsc::channel::<Arc<Task>>();
+        let tasks = Arc::new(Mutex::new(tasks));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let tasks = Arc::clone(&tasks);
+                thread::spawn(move || {
+                    loop {
+                        let next = tasks.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                        match next {
+                            Ok(task) => run(&task),
+                            Err(_) => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self { queue, workers }
+    }
+
+    pub(super) fn spawn(&self, machine: Box<dyn Runnable + Send>) -> Waker {
+        Waker {
+            task: Arc::new(Task {
+                machine: Mutex::new(Some(machine)),
+                scheduled: AtomicBool::new(false),
+                queue: self.queue.clone(),
+            }),
+        }
+    }
+
+    /// Stops the workers once every machine on the executor has been shut down or dropped.
+    pub fn shutdown(self) -> Result<(), Box<dyn std::error::Error>> {
+        drop(self.queue);
+        for worker in self.workers {
+            worker.join().map_err(|_| "Thread join failed")?;
+        }
+        Ok(())
+    }
+}
+
+/// Handles a batch of the messages waiting for a machine and queues it again if more are left.
+///
+/// Messages are only taken while holding the machine's lock, which keeps their order. Clearing
+/// `scheduled` before the last look makes sure a message sent meanwhile is never left behind.
+/// A dead machine stays scheduled, so it is never queued again.
+fn run(task: &Arc<Task>) {
+    let mut machine = task.machine.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut handled = 0;
+    while handled < BATCH {
+        if step(&mut machine) {
+            handled += 1;
+            continue;
+        }
+        if machine.is_none() {
+            return;
+        }
+        task.scheduled.store(false, Ordering::SeqCst);
+        if !step(&mut machine) {
+            return;
+        }
+        task.scheduled.store(true, Ordering::SeqCst);
+        handled += 1;
+    }
+    let _ = task.queue.send(Arc::clone(task));
+}
+
+/// Handles the next message, dropping the machine if it panics. Dropping it closes its
+/// channels, so its controller fails instead of waiting for an answer that never comes.
+fn step(machine: &mut Option<Box<dyn Runnable + Send>>) -> bool {
+    let Some(runnable) = machine.as_mut() else {
+        return false;
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| runnable.run_one())) {
+        Ok(handled) => handled,
+        Err(_) => {
+            *machine = None;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::{LatheCommand, LatheController, LatheResponse};
+    use crate::machines::mill::{FsmController, MillCommand, MillResponse};
+
+    #[test]
+    fn many_machines_share_few_workers() {
+        let executor = Executor::new(4);
+        let mills: Vec<FsmController> = (0..200)
+            .map(|_| FsmController::create_on(&executor, Box::default()))
+            .collect();
+
+        for mill in &mills {
+            mill.send_command(MillCommand::StartSpinning(800)).unwrap();
+            mill.send_command(MillCommand::Move(-50)).unwrap();
+            mill.send_command(MillCommand::StopMoving).unwrap();
+        }
+
+        for mill in &mills {
+            assert_eq!(
+                mill.request(MillCommand::StopSpinning).unwrap(),
+                MillResponse::Status { state: "Off" }
+            );
+            assert_eq!(
+                mill.check_responses(),
+                [
+                    MillResponse::Status { state: "Spinning" },
+                    MillResponse::Status { state: "Moving" },
+                    MillResponse::Status { state: "Spinning" },
+                ]
+            );
+        }
+        for mill in mills {
+            mill.shutdown().unwrap();
+        }
+        executor.shutdown().unwrap();
+    }
+
+    #[test]
+    fn machine_keeps_its_order_across_workers() {
+        let executor = Executor::new(8);
+        let lathe = LatheController::create_on(&executor, Box::default());
+
+        for _ in 0..100 {
+            lathe.send_command(LatheCommand::StartSpinning(1)).unwrap();
+            lathe.send_command(LatheCommand::StopSpinning).unwrap();
+        }
+
+        assert_eq!(lathe.active_states().unwrap(), ["Off"]);
+        let responses = lathe.check_responses();
+        assert_eq!(responses.len(), 200);
+        assert!(responses.chunks(2).all(|pair| pair
+            == [
+                LatheResponse::Status { state: "Spinning" },
+                LatheResponse::Status { state: "Off" },
+            ]));
+        lathe.shutdown().unwrap();
+        executor.shutdown().unwrap();
+    }
+
+    #[test]
+    fn priority_commands_work_on_pooled_machines() {
+        let executor = Executor::new(1);
+        let lathe = LatheController::create_on(&executor, Box::default());
+        lathe.send_command(LatheCommand::StartSpinning(1)).unwrap();
+
+        let response = lathe.request_priority(LatheCommand::Notaus).unwrap();
+
+        assert!(matches!(response, LatheResponse::Status { .. }));
+        assert_eq!(lathe.active_states().unwrap(), ["Notaus"]);
+        lathe.shutdown().unwrap();
+        executor.shutdown().unwrap();
+    }
+
+    struct Busy(Arc<AtomicBool>);
+
+    impl Runnable for Busy {
+        fn run_one(&mut self) -> bool {
+            !self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn busy_machine_lets_others_take_turns() {
+        let executor = Executor::new(1);
+        let done = Arc::new(AtomicBool::new(false));
+        let busy = executor.spawn(Box::new(Busy(Arc::clone(&done))));
+        let lathe = LatheController::create_on(&executor, Box::default());
+        busy.wake();
+
+        let response = lathe.request(LatheCommand::StartSpinning(1)).unwrap();
+
+        assert_eq!(response, LatheResponse::Status { state: "Spinning" });
+        done.store(true, Ordering::SeqCst);
+        drop(busy);
+        lathe.shutdown().unwrap();
+        executor.shutdown().unwrap();
+    }
+
+    mod panicking {
+        use super::*;
+        use crate::machines::shared::{
+            FSM, MachineController, StateHandler, StateInfo, StateName, Transition, command_kind,
+            fsm,
+        };
+        use serde::{Deserialize, Serialize};
+        use std::marker::PhantomData;
+
+        #[derive(Debug)]
+        pub struct Ready;
+        #[derive(Debug)]
+        pub struct Loaded;
+
+        #[derive(Default, Debug)]
+        pub struct FuseData;
+
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum FuseCommand {
+            Load(u32),
+            Unload,
+        }
+
+        command_kind! {
+            FuseCommand => FuseCommandKind { Load, Unload }
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum FuseResponse {
+            Status {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: FuseCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Ready,
+            MachineData: FuseData,
+            MachineCommand: FuseCommand,
+            CommandKind: FuseCommandKind,
+            MachineResponse: FuseResponse,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Ready: {
+                Load(amps: u32) => load(self) -> Loaded {
+                    if amps > 16 {
+                        panic!("fuse blown at {amps} A");
+                    }
+                },
+            },
+            Loaded: {
+                Unload => unload(self) -> Ready,
+            },
+        }
+
+        #[test]
+        fn panicking_machine_fails_its_controller() {
+            let executor = Executor::new(1);
+            let fuse = FsmController::create_on(&executor, Box::default());
+            let lathe = LatheController::create_on(&executor, Box::default());
+
+            let blown = fuse.request(FuseCommand::Load(20));
+            let unloaded = fuse.request(FuseCommand::Unload);
+
+            assert!(blown.is_err());
+            assert!(unloaded.is_err());
+            assert_eq!(
+                lathe.request(LatheCommand::StartSpinning(1)).unwrap(),
+                LatheResponse::Status { state: "Spinning" }
+            );
+            fuse.shutdown().unwrap();
+            lathe.shutdown().unwrap();
+            executor.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn shutdown_waits_for_queued_commands() {
+        let executor = Executor::new(2);
+        let lathe = LatheController::create_on(&executor, Box::default());
+        for _ in 0..50 {
+            lathe.send_command(LatheCommand::StartSpinning(1)).unwrap();
+            lathe.send_command(LatheCommand::StopSpinning).unwrap();
+        }
+
+        lathe.shutdown().unwrap();
+
+        executor.shutdown().unwrap();
+    }
+}
@@ -7,13 +7,22 @@
 //! Compare this with `mill.rs` which uses the `fsm!` macro to generate equivalent functionality
 //! with significantly less code. This manual approach provides full control and transparency
 //! over the implementation details at the cost of more verbose code.
+//!
+//! `lathe_fsm.rs` declares this very lathe with the macro; the tests below check that both
+//! behave the same.
 
 use std::marker::PhantomData;
 
-use super::shared::{MachineController, StateHandler};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::executor::Executor;
+use super::shared::{
+    EmergencyCommands, KindedCommand, KnownStates, MachineController, SpindleCommands,
+    StateHandler, StateInfo, StateName, Transition, known_state,
+};
 
 /// Commands that are sent to the lathe FSM
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LatheCommand {
     StartSpinning(u32),
     StopSpinning,
@@ -23,18 +32,92 @@ pub enum LatheCommand {
     Acknowledge,
 }
 
+/// Fieldless discriminant of [`LatheCommand`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LatheCommandKind {
+    StartSpinning,
+    StopSpinning,
+    Feed,
+    StopFeed,
+    Notaus,
+    Acknowledge,
+}
+
+impl LatheCommandKind {
+    /// All kinds in declaration order
+    pub const ALL: &'static [LatheCommandKind] = &[
+        LatheCommandKind::StartSpinning,
+        LatheCommandKind::StopSpinning,
+        LatheCommandKind::Feed,
+        LatheCommandKind::StopFeed,
+        LatheCommandKind::Notaus,
+        LatheCommandKind::Acknowledge,
+    ];
+
+    /// Name of the kind as reported by `allowed_command_names`
+    pub fn name(&self) -> &'static str {
+        match self {
+            LatheCommandKind::StartSpinning => "StartSpinning",
+            LatheCommandKind::StopSpinning => "StopSpinning",
+            LatheCommandKind::Feed => "Feed",
+            LatheCommandKind::StopFeed => "StopFeed",
+            LatheCommandKind::Notaus => "Notaus",
+            LatheCommandKind::Acknowledge => "Acknowledge",
+        }
+    }
+}
+
+impl LatheCommand {
+    /// The kind of this command without its parameters
+    pub fn kind(&self) -> LatheCommandKind {
+        match self {
+            LatheCommand::StartSpinning(_) => LatheCommandKind::StartSpinning,
+            LatheCommand::StopSpinning => LatheCommandKind::StopSpinning,
+            LatheCommand::Feed(_) => LatheCommandKind::Feed,
+            LatheCommand::StopFeed => LatheCommandKind::StopFeed,
+            LatheCommand::Notaus => LatheCommandKind::Notaus,
+            LatheCommand::Acknowledge => LatheCommandKind::Acknowledge,
+        }
+    }
+}
+
+impl KindedCommand for LatheCommand {
+    type Kind = LatheCommandKind;
+
+    fn kind_named(name: &str) -> Option<LatheCommandKind> {
+        LatheCommandKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+    }
+
+    fn kind_name(kind: LatheCommandKind) -> &'static str {
+        kind.name()
+    }
+}
+
 /// Responses returned by the lathe FSM
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum LatheResponse {
     Status {
-        state: &'static str,
+        #[serde(deserialize_with = "lathe_state")]
+        state: StateName,
     },
     InvalidTransition {
-        current_state: &'static str,
-        attempted_command: String,
+        #[serde(deserialize_with = "lathe_state")]
+        current_state: StateName,
+        attempted_command: LatheCommand,
     },
 }
 
+impl KnownStates for LatheResponse {
+    const STATES: &'static [StateName] = LatheWrapper::STATES;
+}
+
+fn lathe_state<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static str, D::Error> {
+    known_state(deserializer, LatheWrapper::STATES)
+}
+
 /// Lathe states - zero-sized types for compile-time state tracking
 #[derive(Debug)]
 pub struct Off;
@@ -46,12 +129,23 @@ pub struct Feeding;
 pub struct Notaus;
 
 /// Business data for the lathe FSM
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 pub struct LatheData {
     revs: u32,
     feed: u32,
 }
 
+/// Setters for the transitions of the `fsm!` lathe in `lathe_fsm.rs`
+impl LatheData {
+    pub(super) fn set_revs(&mut self, revs: u32) {
+        self.revs = revs;
+    }
+
+    pub(super) fn set_feed(&mut self, feed: u32) {
+        self.feed = feed;
+    }
+}
+
 /// Main FSM struct using type-state pattern
 ///
 /// This is manually implemented to show the structure that the `fsm!` macro
@@ -159,6 +253,9 @@ pub enum LatheWrapper {
 
 /// Wrapper implementation for runtime state management
 impl LatheWrapper {
+    /// Names of all states as reported in responses
+    pub const STATES: &'static [&'static str] = &["Off", "Spinning", "Feeding", "Notaus"];
+
     pub fn new(lathe_data: Box<LatheData>) -> Self {
         LatheWrapper::Off(Lathe::<Off>::new(lathe_data))
     }
@@ -184,6 +281,110 @@ impl StateHandler<LatheCommand, LatheResponse, LatheWrapper> for LatheWrapper {
     fn handle_cmd(self, cmd: LatheCommand) -> (LatheWrapper, LatheResponse) {
         self.handle_cmd(cmd)
     }
+
+    fn active_states(&self) -> Vec<StateName> {
+        vec![self.state_name()]
+    }
+
+    fn allowed_command_names(&self) -> Vec<&'static str> {
+        StateInfo::allowed_commands(self)
+            .iter()
+            .map(LatheCommandKind::name)
+            .collect()
+    }
+}
+
+impl StateInfo for LatheWrapper {
+    type CommandKind = LatheCommandKind;
+
+    const TRANSITIONS: &'static [Transition<LatheCommandKind>] = &[
+        Transition {
+            from: "Off",
+            command: LatheCommandKind::StartSpinning,
+            to: "Spinning",
+            internal: false,
+            history: None,
+            method: "start_spinning",
+        },
+        Transition {
+            from: "Off",
+            command: LatheCommandKind::Notaus,
+            to: "Notaus",
+            internal: false,
+            history: None,
+            method: "notaus",
+        },
+        Transition {
+            from: "Spinning",
+            command: LatheCommandKind::Feed,
+            to: "Feeding",
+            internal: false,
+            history: None,
+            method: "feed",
+        },
+        Transition {
+            from: "Spinning",
+            command: LatheCommandKind::StopSpinning,
+            to: "Off",
+            internal: false,
+            history: None,
+            method: "off",
+        },
+        Transition {
+            from: "Spinning",
+            command: LatheCommandKind::Notaus,
+            to: "Notaus",
+            internal: false,
+            history: None,
+            method: "notaus",
+        },
+        Transition {
+            from: "Feeding",
+            command: LatheCommandKind::StopFeed,
+            to: "Spinning",
+            internal: false,
+            history: None,
+            method: "stop_feed",
+        },
+        Transition {
+            from: "Feeding",
+            command: LatheCommandKind::Notaus,
+            to: "Notaus",
+            internal: false,
+            history: None,
+            method: "notaus",
+        },
+        Transition {
+            from: "Notaus",
+            command: LatheCommandKind::Acknowledge,
+            to: "Off",
+            internal: false,
+            history: None,
+            method: "acknowledge",
+        },
+    ];
+
+    fn state_name(&self) -> StateName {
+        match self {
+            LatheWrapper::Off(_) => "Off",
+            LatheWrapper::Spinning(_) => "Spinning",
+            LatheWrapper::Feeding(_) => "Feeding",
+            LatheWrapper::Notaus(_) => "Notaus",
+        }
+    }
+
+    fn allowed_commands(&self) -> &'static [LatheCommandKind] {
+        match self {
+            LatheWrapper::Off(_) => &[LatheCommandKind::StartSpinning, LatheCommandKind::Notaus],
+            LatheWrapper::Spinning(_) => &[
+                LatheCommandKind::Feed,
+                LatheCommandKind::StopSpinning,
+                LatheCommandKind::Notaus,
+            ],
+            LatheWrapper::Feeding(_) => &[LatheCommandKind::StopFeed, LatheCommandKind::Notaus],
+            LatheWrapper::Notaus(_) => &[LatheCommandKind::Acknowledge],
+        }
+    }
 }
 
 /// Type alias for LatheController using the generic MachineController
@@ -192,6 +393,21 @@ impl LatheController {
     pub fn create(lathe_data: Box<LatheData>) -> Self {
         MachineController::new::<Box<LatheData>, LatheWrapper>(lathe_data)
     }
+
+    /// Creates a lathe controller running on the workers of `executor`.
+    pub fn create_on(executor: &Executor, lathe_data: Box<LatheData>) -> Self {
+        MachineController::spawn_on::<Box<LatheData>, LatheWrapper>(executor, lathe_data)
+    }
+}
+
+impl SpindleCommands for LatheCommand {
+    const STOP_SPINDLE: &'static [Self] = &[LatheCommand::StopFeed, LatheCommand::StopSpinning];
+}
+
+impl EmergencyCommands for LatheCommand {
+    const EMERGENCY: Self = LatheCommand::Notaus;
+    const ACKNOWLEDGE: Self = LatheCommand::Acknowledge;
+    const EMERGENCY_STATE: StateName = "Notaus";
 }
 
 /// Manual implementation of state-specific command handlers
@@ -222,7 +438,7 @@ impl StateHandler<LatheCommand, LatheResponse, LatheWrapper> for Lathe<Off> {
                 LatheWrapper::Off(self),
                 LatheResponse::InvalidTransition {
                     current_state: "Off",
-                    attempted_command: format!("{:?}", cmd),
+                    attempted_command: cmd,
                 },
             ),
         }
@@ -258,7 +474,7 @@ impl StateHandler<LatheCommand, LatheResponse, LatheWrapper> for Lathe<Spinning>
                 LatheWrapper::Spinning(self),
                 LatheResponse::InvalidTransition {
                     current_state: "Spinning",
-                    attempted_command: format!("{:?}", cmd),
+                    attempted_command: cmd,
                 },
             ),
         }
@@ -287,7 +503,7 @@ impl StateHandler<LatheCommand, LatheResponse, LatheWrapper> for Lathe<Feeding>
                 LatheWrapper::Feeding(self),
                 LatheResponse::InvalidTransition {
                     current_state: "Feeding",
-                    attempted_command: format!("{:?}", cmd),
+                    attempted_command: cmd,
                 },
             ),
         }
@@ -309,7 +525,7 @@ impl StateHandler<LatheCommand, LatheResponse, LatheWrapper> for Lathe<Notaus> {
                 LatheWrapper::Notaus(self),
                 LatheResponse::InvalidTransition {
                     current_state: "Notaus",
-                    attempted_command: format!("{:?}", cmd),
+                    attempted_command: cmd,
                 },
             ),
         }
@@ -317,8 +533,51 @@ impl StateHandler<LatheCommand, LatheResponse, LatheWrapper> for Lathe<Notaus> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(super) mod tests {
     use super::*;
+    use crate::machines::property::{Property, Rng};
+
+    pub(crate) fn lathe() -> LatheWrapper {
+        LatheWrapper::new(Box::default())
+    }
+
+    pub(crate) fn random_command(rng: &mut Rng) -> LatheCommand {
+        let value = rng.below(1000) as u32;
+        match rng.below(6) {
+            0 => LatheCommand::StartSpinning(value),
+            1 => LatheCommand::StopSpinning,
+            2 => LatheCommand::Feed(value),
+            3 => LatheCommand::StopFeed,
+            4 => LatheCommand::Notaus,
+            _ => LatheCommand::Acknowledge,
+        }
+    }
+
+    /// Commands standing in for each kind, with and without a value where there is one
+    pub(crate) fn commands(kind: LatheCommandKind) -> Vec<LatheCommand> {
+        match kind {
+            LatheCommandKind::StartSpinning => {
+                vec![
+                    LatheCommand::StartSpinning(0),
+                    LatheCommand::StartSpinning(1000),
+                ]
+            }
+            LatheCommandKind::Feed => vec![LatheCommand::Feed(0), LatheCommand::Feed(500)],
+            LatheCommandKind::StopSpinning => vec![LatheCommand::StopSpinning],
+            LatheCommandKind::StopFeed => vec![LatheCommand::StopFeed],
+            LatheCommandKind::Notaus => vec![LatheCommand::Notaus],
+            LatheCommandKind::Acknowledge => vec![LatheCommand::Acknowledge],
+        }
+    }
+
+    fn data(lathe: &LatheWrapper) -> &LatheData {
+        match lathe {
+            LatheWrapper::Off(lathe) => &lathe.lathe_data,
+            LatheWrapper::Spinning(lathe) => &lathe.lathe_data,
+            LatheWrapper::Feeding(lathe) => &lathe.lathe_data,
+            LatheWrapper::Notaus(lathe) => &lathe.lathe_data,
+        }
+    }
 
     mod state_transitions {
         use super::*;
@@ -414,6 +673,19 @@ mod tests {
             controller.shutdown().unwrap();
         }
 
+        #[test]
+        fn request_waits_for_response() {
+            let controller = setup_lathe_controller();
+
+            let response = controller
+                .request(LatheCommand::StartSpinning(1000))
+                .unwrap();
+
+            assert_eq!(response, LatheResponse::Status { state: "Spinning" });
+            assert!(controller.check_responses().is_empty());
+            controller.shutdown().unwrap();
+        }
+
         #[test]
         fn invalid_transition() {
             let controller = setup_lathe_controller();
@@ -427,10 +699,289 @@ mod tests {
                 responses[0],
                 LatheResponse::InvalidTransition {
                     current_state: "Off",
-                    attempted_command: String::from("Feed(200)")
+                    attempted_command: LatheCommand::Feed(200),
                 }
             );
             controller.shutdown().unwrap();
         }
     }
+
+    mod state_info {
+        use super::*;
+        use crate::machines::conformance::SafetySubject;
+        use crate::machines::shared::drive;
+
+        #[test]
+        fn allowed_commands_follow_state() {
+            let lathe = LatheWrapper::new(Box::default());
+            assert_eq!(
+                lathe.allowed_commands(),
+                &[LatheCommandKind::StartSpinning, LatheCommandKind::Notaus]
+            );
+
+            let (lathe, _) = lathe.handle_cmd(LatheCommand::Notaus);
+
+            assert_eq!(lathe.state_name(), "Notaus");
+            assert_eq!(lathe.allowed_commands(), &[LatheCommandKind::Acknowledge]);
+        }
+
+        fn paths() -> impl Iterator<Item = &'static [LatheCommand]> {
+            let notaus: &'static [LatheCommand] = &[LatheCommand::Notaus];
+            LatheWrapper::PATHS.iter().copied().chain([notaus])
+        }
+
+        #[test]
+        fn transition_table_matches_allowed_commands() {
+            for path in paths() {
+                let (lathe, _) = drive(lathe(), path.iter().cloned());
+                let from_table: Vec<LatheCommandKind> = LatheWrapper::TRANSITIONS
+                    .iter()
+                    .filter(|transition| transition.from == lathe.state_name())
+                    .map(|transition| transition.command)
+                    .collect();
+                assert_eq!(from_table, lathe.allowed_commands());
+            }
+        }
+
+        #[test]
+        fn transition_table_targets_match_responses() {
+            let mut checked = 0;
+            for path in paths() {
+                let from = drive(lathe(), path.iter().cloned()).0.state_name();
+                let outgoing = LatheWrapper::TRANSITIONS
+                    .iter()
+                    .filter(|transition| transition.from == from);
+                for transition in outgoing {
+                    for cmd in commands(transition.command) {
+                        let steps = path.iter().cloned().chain([cmd.clone()]);
+                        let (lathe, mut responses) = drive(lathe(), steps);
+
+                        assert_eq!(
+                            responses.pop(),
+                            Some(LatheResponse::Status {
+                                state: transition.to
+                            }),
+                            "{from} {cmd:?}"
+                        );
+                        assert_eq!(lathe.state_name(), transition.to, "{from} {cmd:?}");
+                    }
+                    checked += 1;
+                }
+            }
+            assert_eq!(checked, LatheWrapper::TRANSITIONS.len());
+        }
+
+        #[test]
+        fn kind_drops_parameters() {
+            assert_eq!(LatheCommand::Feed(3).kind(), LatheCommandKind::Feed);
+            assert_eq!(
+                LatheCommand::Acknowledge.kind(),
+                LatheCommandKind::Acknowledge
+            );
+        }
+    }
+
+    mod safety {
+        use super::*;
+        use crate::machines::conformance::{self, SafetySubject};
+
+        impl SafetySubject for LatheWrapper {
+            type Command = LatheCommand;
+            type Response = LatheResponse;
+            type Abstract = (bool, bool);
+
+            const MACHINE: &'static str = "lathe";
+
+            const PATHS: &'static [&'static [LatheCommand]] = &[
+                &[],
+                &[LatheCommand::StartSpinning(800)],
+                &[LatheCommand::StartSpinning(800), LatheCommand::Feed(150)],
+            ];
+
+            const KINDS: &'static [LatheCommandKind] = LatheCommandKind::ALL;
+
+            fn commands(kind: LatheCommandKind) -> Vec<LatheCommand> {
+                super::commands(kind)
+            }
+
+            fn random_command(rng: &mut Rng) -> LatheCommand {
+                super::random_command(rng)
+            }
+
+            fn abstraction(&self) -> (bool, bool) {
+                (data(self).revs > 0, data(self).feed > 0)
+            }
+
+            fn fresh() -> Self {
+                lathe()
+            }
+
+            fn is_reset(&self) -> bool {
+                match self {
+                    LatheWrapper::Off(lathe) => *lathe.lathe_data == LatheData::default(),
+                    _ => false,
+                }
+            }
+        }
+
+        #[test]
+        fn emergency_stops_every_state() {
+            conformance::emergency_stops_every_state::<LatheWrapper>();
+        }
+
+        #[test]
+        fn acknowledge_resets_to_start() {
+            conformance::acknowledge_resets_to_start::<LatheWrapper>();
+        }
+
+        #[test]
+        fn emergency_only_accepts_acknowledge() {
+            conformance::emergency_only_accepts_acknowledge::<LatheWrapper>();
+        }
+
+        #[test]
+        fn paths_cover_every_state() {
+            conformance::paths_cover_every_state::<LatheWrapper>();
+        }
+
+        #[test]
+        fn safety_holds_for_random_sequences() {
+            conformance::property_safety::<LatheWrapper>();
+        }
+
+        #[test]
+        fn safety_holds_in_every_reachable_combination() {
+            assert_eq!(conformance::model_safety::<LatheWrapper>(), 11);
+        }
+    }
+
+    mod differential {
+        use super::*;
+        use crate::machines::lathe_fsm;
+
+        fn generated_data(lathe: &lathe_fsm::FsmWrapper) -> &LatheData {
+            match lathe {
+                lathe_fsm::FsmWrapper::Off(lathe) => &lathe.data,
+                lathe_fsm::FsmWrapper::Spinning(lathe) => &lathe.data,
+                lathe_fsm::FsmWrapper::Feeding(lathe) => &lathe.data,
+                lathe_fsm::FsmWrapper::Notaus(lathe) => &lathe.data,
+            }
+        }
+
+        fn same_kinds(left: &[LatheCommandKind], right: &[LatheCommandKind]) -> bool {
+            left.len() == right.len() && left.iter().all(|kind| right.contains(kind))
+        }
+
+        #[test]
+        fn random_sequences_behave_like_manual_lathe() {
+            let mut rng = Rng::new(0x9E37_79B9_7F4A_7C15);
+
+            for _ in 0..500 {
+                let mut manual = LatheWrapper::new(Box::default());
+                let mut generated = lathe_fsm::FsmWrapper::new(Box::default());
+                let mut trace = Vec::new();
+
+                for _ in 0..40 {
+                    let cmd = random_command(&mut rng);
+                    trace.push(cmd.clone());
+
+                    let (next_manual, expected) = manual.handle_cmd(cmd.clone());
+                    let (next_generated, actual) = generated.handle_cmd(cmd);
+
+                    assert_eq!(actual, expected, "{trace:?}");
+                    assert_eq!(
+                        generated_data(&next_generated),
+                        data(&next_manual),
+                        "{trace:?}"
+                    );
+                    assert!(
+                        same_kinds(
+                            next_generated.allowed_commands(),
+                            next_manual.allowed_commands()
+                        ),
+                        "{trace:?}"
+                    );
+                    manual = next_manual;
+                    generated = next_generated;
+                }
+            }
+        }
+
+        #[test]
+        fn both_lathes_declare_the_same_states() {
+            assert_eq!(lathe_fsm::FsmWrapper::STATES, LatheWrapper::STATES);
+        }
+    }
+
+    mod properties {
+        use super::*;
+
+        #[test]
+        fn invariants_hold_for_random_sequences() {
+            Property::new(lathe, random_command)
+                .invariant("feed only while feeding or stopped", |lathe| {
+                    data(lathe).feed == 0 || matches!(lathe.state_name(), "Feeding" | "Notaus")
+                })
+                .check();
+        }
+
+        #[test]
+        fn feed_outlives_feeding_into_notaus() {
+            let counterexample = Property::new(lathe, random_command)
+                .invariant("feed only while feeding", |lathe| {
+                    data(lathe).feed == 0 || lathe.state_name() == "Feeding"
+                })
+                .counterexample()
+                .unwrap();
+
+            assert!(matches!(
+                counterexample.trace[..],
+                [
+                    LatheCommand::StartSpinning(_),
+                    LatheCommand::Feed(1..),
+                    LatheCommand::Notaus
+                ]
+            ));
+        }
+    }
+
+    mod model {
+        use super::*;
+        use crate::machines::conformance::SafetySubject;
+        use crate::machines::model::ModelChecker;
+
+        fn checker() -> ModelChecker<LatheWrapper, LatheCommand, LatheResponse, (bool, bool)> {
+            ModelChecker::new("lathe", lathe, commands, LatheWrapper::abstraction)
+        }
+
+        #[test]
+        fn feed_stops_in_every_reachable_combination() {
+            let reached = checker()
+                .invariant("feed only while feeding or stopped", |lathe| {
+                    data(lathe).feed == 0 || matches!(lathe.state_name(), "Feeding" | "Notaus")
+                })
+                .check();
+
+            assert_eq!(reached, 11);
+        }
+
+        #[test]
+        fn notaus_keeps_the_feed() {
+            let violation = checker()
+                .invariant("feed only while feeding", |lathe| {
+                    data(lathe).feed == 0 || lathe.state_name() == "Feeding"
+                })
+                .explore()
+                .unwrap_err();
+
+            assert_eq!(
+                violation.scenario,
+                "machine lathe\n\
+                 start_spinning 0 -> Spinning\n\
+                 feed 500 -> Feeding\n\
+                 notaus -> Notaus\n\
+                 # `feed only while feeding` broken here\n"
+            );
+        }
+    }
 }
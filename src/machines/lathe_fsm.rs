@@ -0,0 +1,59 @@
+//! Lathe FSM implementation using the `fsm!` macro
+//!
+//! The same machine as the hand-written one in `lathe.rs`, sharing its commands, responses and
+//! data. The emergency stop is declared once on a superstate holding every other state. Tests
+//! in `lathe.rs` drive both with the same commands to make sure the macro keeps the semantics
+//! of the manual implementation.
+
+use super::lathe::{LatheCommand, LatheCommandKind, LatheData, LatheResponse};
+use super::shared::{FSM, StateHandler, StateInfo, StateName, Transition, fsm};
+
+use std::marker::PhantomData;
+
+/// Lathe states - zero-sized types used for compile-time state tracking
+#[derive(Debug)]
+pub struct Off;
+#[derive(Debug)]
+pub struct Spinning;
+#[derive(Debug)]
+pub struct Feeding;
+#[derive(Debug)]
+pub struct Notaus;
+/// Every state an emergency stop can interrupt
+#[derive(Debug)]
+pub struct Operating;
+
+fsm! {
+  StartState: Off,
+  MachineData: LatheData,
+  MachineCommand: LatheCommand,
+  CommandKind: LatheCommandKind,
+  MachineResponse: LatheResponse,
+  StateHandlerTrait: StateHandler,
+  superstate Operating: {
+    Notaus => notaus(self) -> Notaus,
+  },
+  Off in Operating: {
+    StartSpinning(revs: u32) => start_spinning(self) -> Spinning {
+      self.data.set_revs(revs);
+    },
+  },
+  Spinning in Operating: {
+    Feed(feed: u32) => feed(self) -> Feeding {
+      self.data.set_feed(feed);
+    },
+    StopSpinning => off(self) -> Off {
+      *self.data = LatheData::default();
+    },
+  },
+  Feeding in Operating: {
+    StopFeed => stop_feed(self) -> Spinning {
+      self.data.set_feed(0);
+    },
+  },
+  Notaus: {
+    Acknowledge => acknowledge(self) -> Off {
+      *self.data = LatheData::default();
+    },
+  },
+}
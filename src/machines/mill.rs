@@ -8,8 +8,12 @@
 //! Compare this with `lathe.rs` which implements the same FSM pattern manually to understand
 //! the code generation benefits of the macro approach.
 
-use super::shared::{FSM, MachineController, StateHandler, fsm};
+use super::shared::{
+    EmergencyCommands, FSM, KnownStates, MachineController, SpindleCommands, StateHandler,
+    StateInfo, StateName, Transition, command_kind, fsm, known_state,
+};
 
+use serde::{Deserialize, Deserializer, Serialize};
 use std::marker::PhantomData;
 
 /// Mill states - these are zero-sized types used for compile-time state tracking
@@ -21,35 +25,54 @@ pub struct Spinning;
 pub struct Moving;
 #[derive(Debug)]
 pub struct Notaus;
+/// Every state an emergency stop can interrupt
+#[derive(Debug)]
+pub struct Operating;
 
 /// Business data for the mill FSM
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 pub struct MillData {
     revs: u32,
     linear_move: i32,
 }
 
 /// Commands that can be sent to the mill FSM
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MillCommand {
     StartSpinning(u32),
     StopSpinning,
     Move(i32),
     StopMoving,
+    Notaus,
+    Acknowledge,
+}
+
+command_kind! {
+    MillCommand => MillCommandKind { StartSpinning, StopSpinning, Move, StopMoving, Notaus, Acknowledge }
 }
 
 /// Responses returned by the mill FSM
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MillResponse {
     Status {
-        state: &'static str,
+        #[serde(deserialize_with = "mill_state")]
+        state: StateName,
     },
     InvalidTransition {
-        current_state: &'static str,
-        attempted_command: String,
+        #[serde(deserialize_with = "mill_state")]
+        current_state: StateName,
+        attempted_command: MillCommand,
     },
 }
 
+impl KnownStates for MillResponse {
+    const STATES: &'static [StateName] = FsmWrapper::STATES;
+}
+
+fn mill_state<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static str, D::Error> {
+    known_state(deserializer, FsmWrapper::STATES)
+}
+
 // FSM definition using the `fsm!` macro
 //
 // This macro call generates all the boilerplate code that would otherwise need to be
@@ -66,15 +89,19 @@ fsm! {
   StartState: Off,
   MachineData: MillData,
   MachineCommand: MillCommand,
+  CommandKind: MillCommandKind,
   MachineResponse: MillResponse,
   StateHandlerTrait: StateHandler,
   Controller: MachineController,
-  Off: {
+  superstate Operating: {
+    Notaus => notaus(self) -> Notaus,
+  },
+  Off in Operating: {
     StartSpinning(revs: u32) => start_spinning(self) -> Spinning {
       self.data.revs = revs;
     },
   },
-  Spinning: {
+  Spinning in Operating: {
     StopSpinning => stop_spinning(self) -> Off {
       self.data.revs = 0;
     },
@@ -82,11 +109,26 @@ fsm! {
       self.data.linear_move = linear_move;
     },
   },
-  Moving: {
+  Moving in Operating: {
     StopMoving => stop_moving(self) -> Spinning {
       self.data.linear_move = 0;
     },
   },
+  Notaus: {
+    Acknowledge => acknowledge(self) -> Off {
+      *self.data = MillData::default();
+    },
+  },
+}
+
+impl EmergencyCommands for MillCommand {
+    const EMERGENCY: Self = MillCommand::Notaus;
+    const ACKNOWLEDGE: Self = MillCommand::Acknowledge;
+    const EMERGENCY_STATE: StateName = "Notaus";
+}
+
+impl SpindleCommands for MillCommand {
+    const STOP_SPINDLE: &'static [Self] = &[MillCommand::StopMoving, MillCommand::StopSpinning];
 }
 
 #[cfg(test)]
@@ -170,21 +212,98 @@ mod tests {
                 responses[2],
                 MillResponse::InvalidTransition {
                     current_state: "Moving",
-                    attempted_command: String::from("StopSpinning"),
+                    attempted_command: MillCommand::StopSpinning,
                 }
             );
 
             teardown_mill_controller(mill_controller);
         }
+
+        #[test]
+        fn active_states_follow_queued_commands() {
+            let mill_controller = setup_mill_controller();
+
+            mill_controller
+                .send_command(MillCommand::StartSpinning(800))
+                .unwrap();
+            mill_controller
+                .send_command(MillCommand::Move(-50))
+                .unwrap();
+
+            assert_eq!(mill_controller.active_states().unwrap(), ["Moving"]);
+
+            teardown_mill_controller(mill_controller);
+        }
+
+        #[test]
+        fn allowed_commands_follow_queued_commands() {
+            let mill_controller = setup_mill_controller();
+
+            mill_controller
+                .send_command(MillCommand::StartSpinning(800))
+                .unwrap();
+            mill_controller
+                .send_command(MillCommand::Move(-50))
+                .unwrap();
+
+            assert_eq!(
+                mill_controller.allowed_commands().unwrap(),
+                [MillCommandKind::StopMoving, MillCommandKind::Notaus]
+            );
+
+            teardown_mill_controller(mill_controller);
+        }
     }
 
     use super::*;
+    use crate::machines::property::Rng;
+
+    fn mill() -> FsmWrapper {
+        FsmWrapper::new(Box::default())
+    }
+
+    fn data(mill: &FsmWrapper) -> &MillData {
+        match mill {
+            FsmWrapper::Off(mill) => &mill.data,
+            FsmWrapper::Spinning(mill) => &mill.data,
+            FsmWrapper::Moving(mill) => &mill.data,
+            FsmWrapper::Notaus(mill) => &mill.data,
+        }
+    }
+
+    fn random_command(rng: &mut Rng) -> MillCommand {
+        match rng.below(6) {
+            0 => MillCommand::StartSpinning(rng.below(1000) as u32),
+            1 => MillCommand::StopSpinning,
+            2 => MillCommand::Move(rng.below(200) as i32 - 100),
+            3 => MillCommand::StopMoving,
+            4 => MillCommand::Notaus,
+            _ => MillCommand::Acknowledge,
+        }
+    }
+
+    fn commands(kind: MillCommandKind) -> Vec<MillCommand> {
+        match kind {
+            MillCommandKind::StartSpinning => {
+                vec![
+                    MillCommand::StartSpinning(0),
+                    MillCommand::StartSpinning(800),
+                ]
+            }
+            MillCommandKind::Move => vec![MillCommand::Move(0), MillCommand::Move(-50)],
+            MillCommandKind::StopSpinning => vec![MillCommand::StopSpinning],
+            MillCommandKind::StopMoving => vec![MillCommand::StopMoving],
+            MillCommandKind::Notaus => vec![MillCommand::Notaus],
+            MillCommandKind::Acknowledge => vec![MillCommand::Acknowledge],
+        }
+    }
+
     mod state_transitions {
         use super::*;
 
         fn setup() -> FSM<Off, MillData> {
             let data = Box::new(MillData::default());
-            FSM::new(data)
+            FSM::<Off, MillData>::new(data)
         }
 
         #[test]
@@ -238,4 +357,165 @@ mod tests {
             gen_fsm.print()
         }
     }
+
+    mod state_info {
+        use super::*;
+        use crate::machines::shared::state_diagram;
+
+        #[test]
+        fn allowed_commands_follow_state() {
+            let mill = FsmWrapper::new(Box::default());
+            assert_eq!(
+                mill.allowed_commands(),
+                &[MillCommandKind::StartSpinning, MillCommandKind::Notaus]
+            );
+
+            let (mill, _) = mill.handle_cmd(MillCommand::StartSpinning(100));
+
+            assert_eq!(mill.state_name(), "Spinning");
+            assert_eq!(
+                mill.allowed_commands(),
+                &[
+                    MillCommandKind::StopSpinning,
+                    MillCommandKind::Move,
+                    MillCommandKind::Notaus
+                ]
+            );
+        }
+
+        #[test]
+        fn transition_table_follows_declaration() {
+            let methods: Vec<&str> = FsmWrapper::TRANSITIONS
+                .iter()
+                .map(|transition| transition.method)
+                .collect();
+
+            assert_eq!(
+                methods,
+                [
+                    "start_spinning",
+                    "stop_spinning",
+                    "start_moving",
+                    "stop_moving",
+                    "acknowledge",
+                    "notaus"
+                ]
+            );
+            assert_eq!(
+                FsmWrapper::TRANSITIONS[2],
+                Transition {
+                    from: "Spinning",
+                    command: MillCommandKind::Move,
+                    to: "Moving",
+                    internal: false,
+                    history: None,
+                    method: "start_moving",
+                }
+            );
+        }
+
+        #[test]
+        fn state_diagram_lists_every_transition() {
+            let diagram = state_diagram("Off", FsmWrapper::TRANSITIONS);
+
+            assert_eq!(
+                diagram,
+                "stateDiagram-v2\n    [*] --> Off\n    Off --> Spinning : StartSpinning\n    \
+                 Spinning --> Off : StopSpinning\n    Spinning --> Moving : Move\n    \
+                 Moving --> Spinning : StopMoving\n    Notaus --> Off : Acknowledge\n    \
+                 Operating --> Notaus : Notaus\n"
+            );
+        }
+
+        #[test]
+        fn kind_drops_parameters() {
+            assert_eq!(MillCommand::Move(-3).kind(), MillCommandKind::Move);
+            assert_eq!(MillCommandKind::ALL.len(), 6);
+        }
+    }
+
+    mod safety {
+        use super::*;
+        use crate::machines::conformance::{self, SafetySubject};
+
+        impl SafetySubject for FsmWrapper {
+            type Command = MillCommand;
+            type Response = MillResponse;
+            type Abstract = (bool, bool);
+
+            const MACHINE: &'static str = "mill";
+
+            const PATHS: &'static [&'static [MillCommand]] = &[
+                &[],
+                &[MillCommand::StartSpinning(800)],
+                &[MillCommand::StartSpinning(800), MillCommand::Move(-50)],
+            ];
+
+            const KINDS: &'static [MillCommandKind] = MillCommandKind::ALL;
+
+            fn commands(kind: MillCommandKind) -> Vec<MillCommand> {
+                super::commands(kind)
+            }
+
+            fn random_command(rng: &mut Rng) -> MillCommand {
+                super::random_command(rng)
+            }
+
+            fn abstraction(&self) -> (bool, bool) {
+                (data(self).revs > 0, data(self).linear_move != 0)
+            }
+
+            fn fresh() -> Self {
+                mill()
+            }
+
+            fn is_reset(&self) -> bool {
+                self.state_name() == "Off" && *data(self) == MillData::default()
+            }
+        }
+
+        #[test]
+        fn emergency_stops_every_state() {
+            conformance::emergency_stops_every_state::<FsmWrapper>();
+        }
+
+        #[test]
+        fn acknowledge_resets_to_start() {
+            conformance::acknowledge_resets_to_start::<FsmWrapper>();
+        }
+
+        #[test]
+        fn emergency_only_accepts_acknowledge() {
+            conformance::emergency_only_accepts_acknowledge::<FsmWrapper>();
+        }
+
+        #[test]
+        fn paths_cover_every_state() {
+            conformance::paths_cover_every_state::<FsmWrapper>();
+        }
+
+        #[test]
+        fn safety_holds_for_random_sequences() {
+            conformance::property_safety::<FsmWrapper>();
+        }
+
+        #[test]
+        fn safety_holds_in_every_reachable_combination() {
+            assert_eq!(conformance::model_safety::<FsmWrapper>(), 11);
+        }
+    }
+
+    mod properties {
+        use super::*;
+        use crate::machines::property::Property;
+
+        #[test]
+        fn invariants_hold_for_random_sequences() {
+            Property::new(mill, random_command)
+                .invariant("move only while moving or stopped", |mill| {
+                    data(mill).linear_move == 0 || matches!(mill.state_name(), "Moving" | "Notaus")
+                })
+                .check();
+        }
+    }
 }
@@ -0,0 +1,249 @@
+//! Mill with parallel regions for its spindle, coolant and door
+//!
+//! Each concern keeps its own state, so the mill has three states at a time instead of one
+//! state for every combination. A command reaches every region that accepts it, e.g. an
+//! emergency stop halts the spindle and shuts off the coolant at once.
+
+use super::shared::{
+    FSM, MachineController, StateHandler, StateInfo, StateName, Transition, command_kind, fsm,
+};
+
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+/// Mill states - these are zero-sized types used for compile-time state tracking
+#[derive(Debug)]
+pub struct Stopped;
+#[derive(Debug)]
+pub struct Spinning;
+#[derive(Debug)]
+pub struct Dry;
+#[derive(Debug)]
+pub struct Flooding;
+#[derive(Debug)]
+pub struct Closed;
+#[derive(Debug)]
+pub struct Open;
+
+/// Business data shared by all regions of the mill
+#[derive(Default, Debug)]
+pub struct MillData {
+    rpm: u32,
+    /// Entry and exit actions in the order they ran
+    log: Vec<&'static str>,
+}
+
+/// Commands that can be sent to the mill
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MillCommand {
+    StartSpinning(u32),
+    CoolantOn,
+    OpenDoor,
+    CloseDoor,
+    Notaus,
+}
+
+command_kind! {
+    MillCommand => MillCommandKind { StartSpinning, CoolantOn, OpenDoor, CloseDoor, Notaus }
+}
+
+/// Responses returned by the mill, reporting the states of all regions
+#[derive(Debug, Clone, PartialEq)]
+pub enum MillResponse {
+    Status {
+        states: Vec<StateName>,
+    },
+    InvalidTransition {
+        current_states: Vec<StateName>,
+        attempted_command: MillCommand,
+    },
+}
+
+fsm! {
+    MachineData: MillData,
+    MachineCommand: MillCommand,
+    CommandKind: MillCommandKind,
+    MachineResponse: MillResponse,
+    StateHandlerTrait: StateHandler,
+    Controller: MachineController,
+    region spindle: Spindle = Stopped {
+        Stopped: {
+            StartSpinning(rpm: u32) => start_spinning(self) -> Spinning { self.data.rpm = rpm; },
+        },
+        Spinning: [exit(data) { data.log.push("exit Spinning"); }] {
+            Notaus => notaus(self) -> Stopped { self.data.rpm = 0; },
+        },
+    },
+    region coolant: Coolant = Dry {
+        Dry: {
+            CoolantOn => coolant_on(self) -> Flooding,
+        },
+        Flooding: [entry(data) { data.log.push("enter Flooding"); }] {
+            Notaus => notaus(self) -> Dry,
+        },
+    },
+    region door: Door = Closed {
+        Closed: {
+            OpenDoor => open_door(self) -> Open,
+        },
+        Open: {
+            CloseDoor => close_door(self) -> Closed,
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::shared::drive;
+
+    #[test]
+    fn regions_change_independently() {
+        let (machine, responses) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![MillCommand::StartSpinning(1200), MillCommand::OpenDoor],
+        );
+
+        assert_eq!(
+            responses,
+            vec![
+                MillResponse::Status {
+                    states: vec!["Spinning", "Dry", "Closed"]
+                },
+                MillResponse::Status {
+                    states: vec!["Spinning", "Dry", "Open"]
+                },
+            ]
+        );
+        assert_eq!(machine.data.rpm, 1200);
+    }
+
+    #[test]
+    fn command_reaches_every_accepting_region() {
+        let (machine, responses) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![
+                MillCommand::StartSpinning(1200),
+                MillCommand::CoolantOn,
+                MillCommand::Notaus,
+            ],
+        );
+
+        assert_eq!(
+            responses[2],
+            MillResponse::Status {
+                states: vec!["Stopped", "Dry", "Closed"]
+            }
+        );
+        assert_eq!(machine.data.rpm, 0);
+        assert_eq!(machine.data.log, vec!["enter Flooding", "exit Spinning"]);
+    }
+
+    #[test]
+    fn command_accepted_by_one_region() {
+        let (machine, responses) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![MillCommand::CoolantOn, MillCommand::Notaus],
+        );
+
+        assert_eq!(
+            responses[1],
+            MillResponse::Status {
+                states: vec!["Stopped", "Dry", "Closed"]
+            }
+        );
+        assert_eq!(machine.spindle, Spindle::Stopped);
+    }
+
+    #[test]
+    fn command_no_region_accepts() {
+        let (_, responses) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![MillCommand::CloseDoor],
+        );
+
+        assert_eq!(
+            responses[0],
+            MillResponse::InvalidTransition {
+                current_states: vec!["Stopped", "Dry", "Closed"],
+                attempted_command: MillCommand::CloseDoor,
+            }
+        );
+    }
+
+    #[test]
+    fn allowed_commands_of_all_regions() {
+        let (machine, _) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![MillCommand::StartSpinning(1200)],
+        );
+
+        assert_eq!(
+            machine.allowed_commands(),
+            vec![
+                MillCommandKind::Notaus,
+                MillCommandKind::CoolantOn,
+                MillCommandKind::OpenDoor
+            ]
+        );
+    }
+
+    #[test]
+    fn state_name_joins_all_regions() {
+        let (machine, _) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![MillCommand::StartSpinning(1200), MillCommand::OpenDoor],
+        );
+        let (other, _) = drive(
+            FsmWrapper::new(Box::default()),
+            vec![MillCommand::OpenDoor, MillCommand::StartSpinning(800)],
+        );
+
+        assert_eq!(machine.state_name(), "Spinning+Dry+Open");
+        assert!(std::ptr::eq(machine.state_name(), other.state_name()));
+    }
+
+    #[test]
+    fn states_of_all_regions() {
+        assert_eq!(
+            FsmWrapper::STATES,
+            ["Stopped", "Spinning", "Dry", "Flooding", "Closed", "Open"]
+        );
+    }
+
+    #[test]
+    fn transition_table_covers_all_regions() {
+        let states: Vec<StateName> = FsmWrapper::TRANSITIONS.iter().map(|t| t.from).collect();
+
+        assert_eq!(
+            states,
+            vec!["Stopped", "Spinning", "Dry", "Flooding", "Closed", "Open"]
+        );
+    }
+
+    #[test]
+    fn controller_runs_regions() {
+        let controller = FsmController::create(Box::default());
+
+        let response = controller.request(MillCommand::OpenDoor).unwrap();
+
+        assert_eq!(
+            response,
+            MillResponse::Status {
+                states: vec!["Stopped", "Dry", "Open"]
+            }
+        );
+        controller.shutdown().unwrap();
+    }
+
+    #[test]
+    fn controller_reports_active_states_of_all_regions() {
+        let controller = FsmController::create(Box::default());
+        controller.send_command(MillCommand::CoolantOn).unwrap();
+
+        let states = controller.active_states().unwrap();
+
+        assert_eq!(states, ["Stopped", "Flooding", "Closed"]);
+        controller.shutdown().unwrap();
+    }
+}
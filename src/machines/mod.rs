@@ -1,3 +1,14 @@
+#[cfg(test)]
+mod conformance;
+pub mod executor;
 pub mod lathe;
+pub mod lathe_fsm;
 pub mod mill;
+pub mod mill_regions;
+#[cfg(any(test, feature = "testing"))]
+pub mod model;
+#[cfg(any(test, feature = "testing"))]
+pub mod property;
 pub mod shared;
+#[cfg(doctest)]
+mod typestate;
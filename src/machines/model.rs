@@ -0,0 +1,229 @@
+//! Exhaustive exploration of small machines
+//!
+//! Random sequences may miss the one path that breaks a property. A [`ModelChecker`] visits
+//! every combination of state and abstracted data reachable within a number of commands,
+//! trying the commands the transition table allows in each state. The first broken invariant
+//! is reported with the shortest sequence leading to it, written as a scenario that can be
+//! pasted into a scenario test.
+//!
+//! Machines of other crates use it through the `testing` feature.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use serde::Serialize;
+
+use super::property::{Invariant, replay};
+use super::shared::{StateHandler, StateInfo, StateName, drive};
+use crate::cli::command::format_command;
+use crate::cli::scenario::step_line;
+
+/// Invariant broken on a reachable state
+#[derive(Debug)]
+pub struct Violation {
+    pub invariant: &'static str,
+    /// Shortest way to the broken invariant in scenario syntax
+    pub scenario: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invariant `{}` broken, reproduce with:", self.invariant)?;
+        write!(f, "{}", self.scenario)
+    }
+}
+
+/// Invariants of one machine checked on everything it reaches within `depth` commands
+pub struct ModelChecker<Wrapper: StateInfo, Command, Response, Abstract> {
+    /// Machine name heading the reported scenario, as `fsm run-scenario` knows it
+    machine: &'static str,
+    fresh: fn() -> Wrapper,
+    /// Commands standing in for a kind, e.g. a zero and a non-zero speed
+    commands: fn(Wrapper::CommandKind) -> Vec<Command>,
+    /// The part of the machine data that matters for the invariants
+    abstraction: fn(&Wrapper) -> Abstract,
+    invariants: Vec<Invariant<Wrapper, Command>>,
+    depth: usize,
+    response: PhantomData<fn() -> Response>,
+}
+
+impl<Wrapper, Command, Response, Abstract> ModelChecker<Wrapper, Command, Response, Abstract>
+where
+    Wrapper: StateHandler<Command, Response, Wrapper> + StateInfo,
+    Wrapper::CommandKind: Copy,
+    Command: Clone + Serialize,
+    Response: Serialize,
+    Abstract: Eq + Hash,
+{
+    pub fn new(
+        machine: &'static str,
+        fresh: fn() -> Wrapper,
+        commands: fn(Wrapper::CommandKind) -> Vec<Command>,
+        abstraction: fn(&Wrapper) -> Abstract,
+    ) -> Self {
+        Self {
+            machine,
+            fresh,
+            commands,
+            abstraction,
+            invariants: Vec::new(),
+            depth: 10,
+            response: PhantomData,
+        }
+    }
+
+    pub fn invariant(mut self, name: &'static str, holds: fn(&Wrapper) -> bool) -> Self {
+        self.invariants.push(Invariant::always(name, holds));
+        self
+    }
+
+    /// See [`Invariant::after`].
+    pub fn reachable(
+        mut self,
+        name: &'static str,
+        probe: &[Command],
+        holds: fn(&Wrapper) -> bool,
+    ) -> Self {
+        self.invariants.push(Invariant::after(name, probe, holds));
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Number of combinations reached, panics with a scenario reproducing the first broken
+    /// invariant.
+    pub fn check(&self) -> usize {
+        self.explore()
+            .unwrap_or_else(|violation| panic!("{violation}"))
+    }
+
+    /// Visits breadth first, so the reported sequence is a shortest one. Returns how many
+    /// combinations of state and abstracted data were reached.
+    pub fn explore(&self) -> Result<usize, Violation> {
+        let start = (self.fresh)();
+        self.verify(&start, &[])?;
+        let mut visited: HashSet<(StateName, Abstract)> = HashSet::new();
+        visited.insert((start.state_name(), (self.abstraction)(&start)));
+        let mut frontier: Vec<Vec<Command>> = vec![Vec::new()];
+
+        for _ in 0..self.depth {
+            let mut next = Vec::new();
+            for trace in &frontier {
+                for &kind in replay(self.fresh, trace).allowed_commands() {
+                    for cmd in (self.commands)(kind) {
+                        let extended = [trace.as_slice(), &[cmd]].concat();
+                        let machine = replay(self.fresh, &extended);
+                        if visited.insert((machine.state_name(), (self.abstraction)(&machine))) {
+                            self.verify(&machine, &extended)?;
+                            next.push(extended);
+                        }
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+        Ok(visited.len())
+    }
+
+    fn verify(&self, machine: &Wrapper, trace: &[Command]) -> Result<(), Violation> {
+        match self
+            .invariants
+            .iter()
+            .find(|invariant| !invariant.holds(machine, trace, |trace| replay(self.fresh, trace)))
+        {
+            Some(broken) => Err(Violation {
+                invariant: broken.name,
+                scenario: self.scenario(trace, broken),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Replays `trace` and the probe of `broken`, expecting what the machine actually did.
+    fn scenario(&self, trace: &[Command], broken: &Invariant<Wrapper, Command>) -> String {
+        let steps: Vec<Command> = trace.iter().chain(broken.probe()).cloned().collect();
+        let (_, responses) = drive((self.fresh)(), steps.iter().cloned());
+        let mut lines: Vec<String> = steps
+            .iter()
+            .zip(&responses)
+            .map(|(cmd, response)| step_line(&format_command(cmd), response))
+            .collect();
+        lines.insert(trace.len(), format!("# `{}` broken here", broken.name));
+        lines.insert(0, format!("machine {}", self.machine));
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::scenario::Scenario;
+    use crate::machines::lathe::tests::{commands, lathe};
+    use crate::machines::lathe::{LatheCommand, LatheController, LatheResponse, LatheWrapper};
+
+    fn checker() -> ModelChecker<LatheWrapper, LatheCommand, LatheResponse, ()> {
+        ModelChecker::new("lathe", lathe, commands, |_| ())
+    }
+
+    #[test]
+    fn every_state_is_reached() {
+        assert_eq!(checker().check(), 4);
+    }
+
+    #[test]
+    fn depth_limits_exploration() {
+        assert_eq!(checker().depth(1).check(), 3);
+    }
+
+    #[test]
+    fn violation_is_reported_as_shortest_scenario() {
+        let violation = checker()
+            .invariant("never feeding", |lathe| lathe.state_name() != "Feeding")
+            .explore()
+            .unwrap_err();
+
+        assert_eq!(violation.invariant, "never feeding");
+        assert_eq!(
+            violation.scenario,
+            "machine lathe\n\
+             start_spinning 0 -> Spinning\n\
+             feed 0 -> Feeding\n\
+             # `never feeding` broken here\n"
+        );
+    }
+
+    #[test]
+    fn violated_probe_replays_as_scenario() {
+        let violation = checker()
+            .reachable(
+                "acknowledge leads to off",
+                &[LatheCommand::Acknowledge],
+                |lathe| lathe.state_name() == "Off",
+            )
+            .explore()
+            .unwrap_err();
+        let scenario = Scenario::parse(&violation.scenario).unwrap();
+        let controller = LatheController::create(Box::default());
+
+        let mismatches = scenario.run(&controller);
+
+        assert_eq!(
+            violation.scenario,
+            "machine lathe\n\
+             start_spinning 0 -> Spinning\n\
+             # `acknowledge leads to off` broken here\n\
+             acknowledge -> invalid\n"
+        );
+        assert_eq!(scenario.machine.as_deref(), Some("lathe"));
+        assert_eq!(mismatches, vec![]);
+        controller.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! Random command sequences checked against invariants
+//!
+//! Hand-written tests only cover the sequences someone thought of. A [`Property`] runs
+//! thousands of generated sequences against a fresh machine each, checks its invariants after
+//! every command and shrinks the first failing sequence to a short reproducer.
+//!
+//! Machines of other crates use it through the `testing` feature.
+
+use std::fmt::{self, Debug};
+use std::marker::PhantomData;
+
+use super::shared::{StateHandler, drive};
+
+/// Small deterministic generator, so a failing run is reproduced by running the test again
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    /// A number below `bound`, which must not be zero
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+enum Check<Wrapper, Command> {
+    /// Holds for the machine itself
+    Always(fn(&Wrapper) -> bool),
+    /// Holds once the probe commands ran on a copy of the machine
+    After(Vec<Command>, fn(&Wrapper) -> bool),
+}
+
+/// Property a machine must keep in every state it reaches
+pub struct Invariant<Wrapper, Command> {
+    pub name: &'static str,
+    check: Check<Wrapper, Command>,
+}
+
+impl<Wrapper, Command: Clone> Invariant<Wrapper, Command> {
+    pub fn always(name: &'static str, holds: fn(&Wrapper) -> bool) -> Self {
+        Self {
+            name,
+            check: Check::Always(holds),
+        }
+    }
+
+    /// Invariant about where `probe` leads from any reachable state, like "Notaus can always be
+    /// reached". Machines can't be cloned, so the probe runs on a replay of the sequence.
+    pub fn after(name: &'static str, probe: &[Command], holds: fn(&Wrapper) -> bool) -> Self {
+        Self {
+            name,
+            check: Check::After(probe.to_vec(), holds),
+        }
+    }
+
+    /// Commands run on top of the sequence before checking, none for plain invariants
+    pub fn probe(&self) -> &[Command] {
+        match &self.check {
+            Check::Always(_) => &[],
+            Check::After(probe, _) => probe,
+        }
+    }
+
+    /// Whether the invariant holds for `machine`, reached by `trace` from a fresh machine.
+    pub fn holds(
+        &self,
+        machine: &Wrapper,
+        trace: &[Command],
+        replay: impl Fn(&[Command]) -> Wrapper,
+    ) -> bool {
+        match &self.check {
+            Check::Always(holds) => holds(machine),
+            Check::After(probe, holds) => holds(&replay(&[trace, probe].concat())),
+        }
+    }
+}
+
+/// The machine `trace` leads to from a fresh one, for checks that can't clone the machine
+pub(super) fn replay<Wrapper, Command, Response>(
+    fresh: fn() -> Wrapper,
+    trace: &[Command],
+) -> Wrapper
+where
+    Wrapper: StateHandler<Command, Response, Wrapper>,
+    Command: Clone,
+{
+    drive(fresh(), trace.iter().cloned()).0
+}
+
+/// Shortest sequence found that breaks an invariant
+#[derive(Debug)]
+pub struct Counterexample<Command> {
+    pub invariant: &'static str,
+    pub trace: Vec<Command>,
+}
+
+impl<Command: Debug> fmt::Display for Counterexample<Command> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invariant `{}` broken after:", self.invariant)?;
+        for cmd in &self.trace {
+            writeln!(f, "    {cmd:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Invariants of one machine under random command sequences
+pub struct Property<Wrapper, Command, Response> {
+    fresh: fn() -> Wrapper,
+    generate: fn(&mut Rng) -> Command,
+    invariants: Vec<Invariant<Wrapper, Command>>,
+    runs: usize,
+    length: usize,
+    response: PhantomData<fn() -> Response>,
+}
+
+impl<Wrapper, Command, Response> Property<Wrapper, Command, Response>
+where
+    Wrapper: StateHandler<Command, Response, Wrapper>,
+    Command: Clone + Debug,
+{
+    pub fn new(fresh: fn() -> Wrapper, generate: fn(&mut Rng) -> Command) -> Self {
+        Self {
+            fresh,
+            generate,
+            invariants: Vec::new(),
+            runs: 1000,
+            length: 30,
+            response: PhantomData,
+        }
+    }
+
+    pub fn invariant(mut self, name: &'static str, holds: fn(&Wrapper) -> bool) -> Self {
+        self.invariants.push(Invariant::always(name, holds));
+        self
+    }
+
+    /// See [`Invariant::after`].
+    pub fn reachable(
+        mut self,
+        name: &'static str,
+        probe: &[Command],
+        holds: fn(&Wrapper) -> bool,
+    ) -> Self {
+        self.invariants.push(Invariant::after(name, probe, holds));
+        self
+    }
+
+    pub fn runs(mut self, runs: usize) -> Self {
+        self.runs = runs;
+        self
+    }
+
+    /// Panics with a shrunk counterexample if an invariant breaks.
+    pub fn check(&self) {
+        if let Some(counterexample) = self.counterexample() {
+            panic!("{counterexample}");
+        }
+    }
+
+    pub fn counterexample(&self) -> Option<Counterexample<Command>> {
+        let mut rng = Rng::new(0x9E37_79B9_7F4A_7C15);
+        (0..self.runs).find_map(|_| {
+            let trace: Vec<Command> = (0..self.length)
+                .map(|_| (self.generate)(&mut rng))
+                .collect();
+            self.failure(&trace).map(|_| self.shrink(trace))
+        })
+    }
+
+    /// The broken invariant and how many commands it took
+    fn failure(&self, trace: &[Command]) -> Option<(&'static str, usize)> {
+        let mut machine = (self.fresh)();
+        for steps in 0..=trace.len() {
+            if steps > 0 {
+                machine = machine.handle_cmd(trace[steps - 1].clone()).0;
+            }
+            if let Some(broken) = self.invariants.iter().find(|invariant| {
+                !invariant.holds(&machine, &trace[..steps], |trace| replay(self.fresh, trace))
+            }) {
+                return Some((broken.name, steps));
+            }
+        }
+        None
+    }
+
+    /// Cuts the sequence after the failing command, then drops single commands for as long as
+    /// the same invariant still breaks.
+    fn shrink(&self, mut trace: Vec<Command>) -> Counterexample<Command> {
+        let (invariant, steps) = self.failure(&trace).expect("shrinking a passing sequence");
+        trace.truncate(steps);
+        let mut index = 0;
+        while index < trace.len() {
+            let mut candidate = trace.clone();
+            candidate.remove(index);
+            match self.failure(&candidate) {
+                Some((name, steps)) if name == invariant => {
+                    candidate.truncate(steps);
+                    trace = candidate;
+                }
+                _ => index += 1,
+            }
+        }
+        Counterexample { invariant, trace }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::tests::{lathe, random_command};
+    use crate::machines::lathe::{LatheCommand, LatheWrapper};
+    use crate::machines::shared::StateInfo;
+
+    #[test]
+    fn holding_invariants_pass() {
+        Property::new(lathe, random_command)
+            .invariant("known state", |lathe| {
+                LatheWrapper::STATES.contains(&lathe.state_name())
+            })
+            .reachable("notaus reachable", &[LatheCommand::Notaus], |lathe| {
+                lathe.state_name() == "Notaus"
+            })
+            .check();
+    }
+
+    #[test]
+    fn broken_invariant_shrinks_to_shortest_sequence() {
+        let counterexample = Property::new(lathe, random_command)
+            .invariant("never feeding", |lathe| lathe.state_name() != "Feeding")
+            .counterexample()
+            .unwrap();
+
+        assert_eq!(counterexample.invariant, "never feeding");
+        assert!(matches!(
+            counterexample.trace[..],
+            [LatheCommand::StartSpinning(_), LatheCommand::Feed(_)]
+        ));
+    }
+
+    #[test]
+    fn broken_reachability_reports_the_state_it_fails_from() {
+        let counterexample = Property::new(lathe, random_command)
+            .reachable(
+                "acknowledge leads to off",
+                &[LatheCommand::Acknowledge],
+                |lathe| lathe.state_name() == "Off",
+            )
+            .counterexample()
+            .unwrap();
+
+        assert!(matches!(
+            counterexample.trace[..],
+            [LatheCommand::StartSpinning(_)]
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant `always off` broken after:\n    ")]
+    fn check_panics_with_the_trace() {
+        Property::new(lathe, random_command)
+            .invariant("always off", |lathe| lathe.state_name() == "Off")
+            .runs(1)
+            .check();
+    }
+}
@@ -3,20 +3,49 @@
 ///
 /// The FSM is implemented using a type-state pattern where the state is represented by a generic parameter.
 /// This allows for compile-time checking of valid state transitions.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::marker::PhantomData;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock, mpsc};
 use std::thread::{self, JoinHandle};
 
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+use super::executor::{Executor, Runnable, Waker};
+
 /// Represents a Finite State Machine with a specific state and data.
 ///
 /// # Type Parameters
 /// * `State` - The current state of the FSM
 /// * `FsmData` - The data associated with the FSM
 pub struct FSM<State, FsmData> {
-    pub state: PhantomData<State>,
-    pub data: Box<FsmData>,
+    pub(crate) state: PhantomData<State>,
+    pub(crate) data: Box<FsmData>,
+    pub(crate) history: History,
+}
+
+impl<State, FsmData> FSM<State, FsmData>
+where
+    State: Nested<FsmData>,
+{
+    /// Leaves this state for its superstate, whose transitions then apply.
+    pub fn parent(mut self) -> FSM<State::Parent, FsmData> {
+        State::exit(&mut self.data);
+        FSM {
+            state: PhantomData,
+            data: self.data,
+            history: self.history,
+        }
+    }
 }
 
+/// Name of a state as reported in responses.
+///
+/// Spelling out `&'static str` in a response enum would tie its derived `Deserialize` to
+/// `'static` input.
+pub type StateName = &'static str;
+
 /// Macro for defining a Finite State Machine.
 ///
 /// This macro generates the necessary implementations for the FSM based on the provided states and transitions.
@@ -25,28 +54,758 @@ pub struct FSM<State, FsmData> {
 /// * `StartState` - The initial state of the FSM
 /// * `MachineData` - The type of data associated with the FSM
 /// * `MachineCommand` - The type of commands that are be sent to the FSM
+/// * `CommandKind` - The fieldless discriminant of `MachineCommand`, see `command_kind!`
 /// * `MachineResponse` - The type of responses that are returned by the FSM
 /// * `StateHandlerTrait` - The trait that defines the interface for handling commands
-/// * `Controller` - The type of controller for the FSM
+/// * `Controller` - The type of controller for the FSM, left out if another machine with the
+///   same command and response types already creates that controller
 /// * The rest of the parameters define the states and transitions of the FSM
+///
+/// # Superstates
+/// States can be grouped into a `superstate` with `in`. Commands a state doesn't handle bubble
+/// up to its superstate, so transitions shared by all substates are declared only once.
+/// Superstates may be nested and are only the target of a transition through their history.
+///
+/// Every state can declare `entry` and `exit` actions on the machine data, which run whenever a
+/// transition enters or leaves the state:
+/// ```text
+/// superstate Running: [entry(data) { data.coolant = true; }, exit(data) { data.coolant = false; }] {
+///     Notaus => notaus(self) -> Notaus,
+/// },
+/// Spinning in Running: {
+///     Feed(feed: u32) => feed(self) -> Feeding { self.data.feed = feed; },
+/// },
+/// ```
+///
+/// # Payloads
+/// With a `Payload: Type` declared after `MachineResponse`, every transition body evaluates to
+/// the payload its response reports as `Status { state, payload }` or `Updated { state, payload }`,
+/// e.g. the speed actually reached. Transitions without a body report `Payload::default()`, the
+/// typed transition methods return the payload next to the new state:
+/// ```text
+/// Off: {
+///     StartSpinning(rpm: u32) => start_spinning(self) -> Spinning { Reading::Rpm(rpm.min(3000)) },
+/// },
+/// ```
+///
+/// # Internal Transitions
+/// A transition marked `[internal]` has no target: it runs its body without leaving the state,
+/// so no exit or entry actions run and the response is `Updated { state }` instead of
+/// `Status { state }`. A transition to the state itself is external and does run them:
+/// ```text
+/// Spinning: {
+///     SetRevs(revs: u32) => set_revs(self) [internal] { self.data.revs = revs; },
+///     Restart => restart(self) -> Spinning,
+/// },
+/// ```
+///
+/// # History
+/// A transition to `Running[H]` returns to the direct substate `Running` was last left in,
+/// `Running[H*]` to the innermost one. A superstate that wasn't active yet, or a remembered
+/// superstate reached through `[H]`, is entered in the first state declared in it. The history
+/// travels with the machine data. A superstate without any state to resume rejects the command
+/// with `InvalidTransition` and the typed method returns the machine as `Err`, so parameters of
+/// history transitions must be `Clone`:
+/// ```text
+/// Paused: {
+///     Resume => resume(self) -> Running[H*],
+/// },
+/// ```
+///
+/// # Unhandled Commands
+/// A command neither a state nor its superstates handle is rejected with `InvalidTransition`.
+/// A state can choose a different policy after its entry and exit actions:
+/// * `unhandled(ignore)` keeps the state and responds `Ignored`
+/// * `unhandled(defer)` keeps the state and responds `Deferred`; a `MachineController` holds the
+///   command back and hands it over again after each later command until it is no longer
+///   deferred
+/// * `unhandled(escalate(Fault))` transitions to `Fault` and responds `Escalated`
+///
+/// All of them respond with `{ current_state, attempted_command }`, except `Escalated`, which
+/// reports the fault state as `state`:
+/// ```text
+/// RampingUp: [unhandled(defer)] {
+///     Reached => reached(self) -> Spinning,
+/// },
+/// ```
+///
+/// # Internal Events
+/// With `InternalEvents: field` declared after `MachineResponse`, the machine data holds an
+/// [`Outbox`] in `field`. Transition bodies and entry or exit actions post follow-up commands to
+/// it, which the machine thread handles before the next command, answering them in order:
+/// ```text
+/// RampingUp: [entry(data) { data.events.post(SpindleCommand::AtSpeed); }] {
+///     AtSpeed => at_speed(self) -> Spinning,
+/// },
+/// ```
+///
+/// Events that keep posting each other never let the machine get to the next command, so a
+/// command raising more than a thousand of them halts the machine and its controller fails.
+///
+/// # Child Machines
+/// A machine made of other machines keeps their wrappers as [`Child`]ren in its data. Internal
+/// transitions forward commands to them and get all responses the child gave, or an error
+/// once the child is lost. State changes of the child come back as internal events:
+/// ```text
+/// Lathe(cmd: LatheCommand) => lathe(self) [internal] {
+///     let data = &mut *self.data;
+///     Forwarded::Lathe(data.lathe.forward(cmd, &mut data.events))
+/// },
+/// ```
+///
+/// # Regions
+/// Independent concerns of a machine are declared as parallel regions instead of a
+/// `StartState`, so their states don't have to be multiplied out. Each region keeps its own
+/// state while all share the machine data. A command is handed to every region that accepts it
+/// and is only rejected if none does, so the response reports the states of all regions:
+/// ```text
+/// region spindle: Spindle = Stopped {
+///     Stopped: { StartSpinning(rpm: u32) => start_spinning(self) -> Spinning },
+///     Spinning: { Notaus => notaus(self) -> Stopped },
+/// },
+/// region door: Door = Closed { ... },
+/// ```
+/// The response needs `Status { states }` and `InvalidTransition { current_states, .. }`, see
+/// `mill_regions` for a complete machine.
+/// As a [`StateInfo`], the machine reports the states of all regions joined by `+`, e.g.
+/// `Spinning+Dry+Closed`, and the commands at least one region accepts.
+///
+/// State types must be unique across regions. Regions have no superstates, internal or history
+/// transitions, and their states no `unhandled(...)` policy. `Payload:` and `InternalEvents:`
+/// are not available either, the macro rejects all of them.
 macro_rules! fsm {
 (
     StartState: $start_state:ident,
     MachineData: $data:ident,
     MachineCommand: $command_type:ident,
+    CommandKind: $command_kind:ident,
+    MachineResponse: $response:ident,
+    $(Payload: $payload:ty,)?
+    $(InternalEvents: $events:ident,)?
+    StateHandlerTrait: $state_handler:ident,
+    Controller: $controller:ident,
+    $($states:tt)*
+) => {
+    fsm!(@sort
+        [$start_state, $data, [$($payload)?], [$($events)?], $command_type, $command_kind, $response, $state_handler, [$controller]]
+        [] []
+        $($states)*
+    );
+};
+
+// Without `Controller:` no `FsmController` is generated
+(
+    StartState: $start_state:ident,
+    MachineData: $data:ident,
+    MachineCommand: $command_type:ident,
+    CommandKind: $command_kind:ident,
+    MachineResponse: $response:ident,
+    $(Payload: $payload:ty,)?
+    $(InternalEvents: $events:ident,)?
+    StateHandlerTrait: $state_handler:ident,
+    $($states:tt)*
+) => {
+    fsm!(@sort
+        [$start_state, $data, [$($payload)?], [$($events)?], $command_type, $command_kind, $response, $state_handler, []]
+        [] []
+        $($states)*
+    );
+};
+
+// Regions share one response for all of them, so they have no payloads or events of their own
+(
+    MachineData: $data:ident,
+    MachineCommand: $command_type:ident,
+    CommandKind: $command_kind:ident,
+    MachineResponse: $response:ident,
+    Payload: $payload:ty,
+    $($rest:tt)*
+) => {
+    compile_error!("regions don't support `Payload:`");
+};
+
+(
+    MachineData: $data:ident,
+    MachineCommand: $command_type:ident,
+    CommandKind: $command_kind:ident,
+    MachineResponse: $response:ident,
+    InternalEvents: $events:ident,
+    $($rest:tt)*
+) => {
+    compile_error!("regions don't support `InternalEvents:`");
+};
+
+// Orthogonal regions, each with its own start state and typestate over the shared data
+(
+    MachineData: $data:ident,
+    MachineCommand: $command_type:ident,
+    CommandKind: $command_kind:ident,
     MachineResponse: $response:ident,
     StateHandlerTrait: $state_handler:ident,
     Controller: $controller:ident,
     $(
-        $from_state:ident: {
+        region $field:ident : $region:ident = $start_state:ident {
+            $(
+                $from_state:ident : $([$($options:tt)*])? {
+                    $(
+                        $command:ident $(($($param:ident: $param_type:ty),+))? => $method:ident($self:ident) -> $to_state:ident
+                        $({ $($body:tt)* })?
+                    ),* $(,)?
+                }
+            ),* $(,)?
+        }
+    ),+ $(,)?
+) => {
+  $(
+    $(
+        fsm!(@region_options $($($options)*)?);
+        fsm!(@state $data, [], $from_state, [], [$($($options)*)?], {
             $(
-               $command:ident $(($($param:ident: $param_type:ty),+))? => $method:ident($self:ident) -> $to_state:ident
-                $({ $($body:tt)* })?
-            ),*,
-        } ,
+                $command $(($($param: $param_type),+))? => $method($self) -> $to_state $({ $($body)* })?
+            ),*
+        });
     )*
+
+    /// States of one region of the machine
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum $region {
+        $($from_state,)*
+    }
+
+    impl $region {
+        /// Name of the state as reported in responses
+        pub fn state_name(&self) -> StateName {
+            match self {
+                $($region::$from_state => stringify!($from_state),)*
+            }
+        }
+
+        /// Commands this region accepts in its current state
+        pub fn allowed_commands(&self) -> &'static [$command_kind] {
+            match self {
+                $($region::$from_state => &[$($command_kind::$command),*],)*
+            }
+        }
+
+        /// Applies the command if the region accepts it, otherwise leaves the region as it is.
+        #[allow(unused_variables)]
+        fn handle_cmd(self, data: Box<$data>, cmd: &$command_type) -> (Self, Box<$data>, bool) {
+            match self {
+                $(
+                    $region::$from_state => match cmd {
+                        $(
+                            $command_type::$command$(($($param),+))? => {
+                                let machine = FSM::<$from_state, $data> {
+                                    state: PhantomData,
+                                    data,
+                                    history: Default::default(),
+                                };
+                                let new_fsm = machine.$method($($($param.clone()),+)?);
+                                ($region::$to_state, new_fsm.data, true)
+                            }
+                        )*
+                        #[allow(unreachable_patterns)]
+                        _ => (self, data, false),
+                    },
+                )*
+            }
+        }
+    }
+  )+
+
+  /// The machine with all of its regions.
+  ///
+  /// Every region holds its own state, the data is shared between them.
+pub struct FsmWrapper {
+    pub(crate) data: Box<$data>,
+    $(pub(crate) $field: $region,)+
+}
+
+  impl FsmWrapper {
+    /// Names of the states of all regions
+    pub const STATES: &'static [&'static str] = &[$($(stringify!($from_state)),*),+];
+
+    /// Creates a new machine with every region in its start state.
+    pub fn new(machine_data: Box<$data>) -> Self {
+        FsmWrapper {
+            data: machine_data,
+            $($field: $region::$start_state,)+
+        }
+    }
+
+    /// Current state of every region, in declaration order
+    pub fn states(&self) -> Vec<StateName> {
+        vec![$(self.$field.state_name()),+]
+    }
+
+    /// Hands the command to every region that accepts it.
+    ///
+    /// The command is only rejected if no region accepts it.
+    pub fn handle_cmd(self, cmd: $command_type) -> (FsmWrapper, $response) {
+        let FsmWrapper { mut data, $($field),+ } = self;
+        let mut accepted = false;
+        $(
+            let ($field, next_data, handled) = $field.handle_cmd(data, &cmd);
+            data = next_data;
+            accepted |= handled;
+        )+
+        let machine = FsmWrapper { data, $($field),+ };
+        let states = machine.states();
+        if accepted {
+            (machine, $response::Status { states })
+        } else {
+            (
+                machine,
+                $response::InvalidTransition {
+                    current_states: states,
+                    attempted_command: cmd,
+                },
+            )
+        }
+    }
+  }
+
+  impl StateInfo for FsmWrapper {
+    type CommandKind = $command_kind;
+
+    const TRANSITIONS: &'static [Transition<$command_kind>] = &[
+        $(
+            $(
+                $(
+                    Transition {
+                        from: stringify!($from_state),
+                        command: $command_kind::$command,
+                        to: stringify!($to_state),
+                        internal: false,
+                        history: None,
+                        method: stringify!($method),
+                    },
+                )*
+            )*
+        )+
+    ];
+
+    /// The states of all regions joined by `+`, e.g. `Spinning+Dry+Closed`
+    fn state_name(&self) -> StateName {
+        static NAMES: std::sync::Mutex<Vec<(Vec<StateName>, &'static str)>> =
+            std::sync::Mutex::new(Vec::new());
+        $crate::machines::shared::interned(&NAMES, self.states(), |states| {
+            states.join("+").into_boxed_str()
+        })
+    }
+
+    /// Commands at least one region accepts
+    fn allowed_commands(&self) -> &'static [$command_kind] {
+        static ALLOWED: std::sync::Mutex<Vec<(Vec<StateName>, &'static [$command_kind])>> =
+            std::sync::Mutex::new(Vec::new());
+        $crate::machines::shared::interned(&ALLOWED, self.states(), |_| {
+            let mut allowed = Vec::new();
+            $(
+                for kind in self.$field.allowed_commands() {
+                    if !allowed.contains(kind) {
+                        allowed.push(*kind);
+                    }
+                }
+            )+
+            allowed.into_boxed_slice()
+        })
+    }
+  }
+
+  impl From<Box<$data>> for FsmWrapper {
+    fn from(machine_data: Box<$data>) -> Self {
+        FsmWrapper::new(machine_data)
+    }
+  }
+
+  impl $state_handler<$command_type, $response, FsmWrapper> for FsmWrapper {
+    fn handle_cmd(self, cmd: $command_type) -> (FsmWrapper, $response) {
+        self.handle_cmd(cmd)
+    }
+
+    fn active_states(&self) -> Vec<StateName> {
+        self.states()
+    }
+
+    fn allowed_command_names(&self) -> Vec<&'static str> {
+        StateInfo::allowed_commands(self).iter().map($command_kind::name).collect()
+    }
+  }
+
+  fsm!(@controller [$controller] $data, $command_type, $response);
+};
+
+// Superstates are collected apart from the states, they never become a variant of `FsmWrapper`
+(@sort $header:tt [$($states:tt)*] [$($superstates:tt)*]
+    superstate $state:ident $(in $parent:ident)? : $([$($options:tt)*])? { $($body:tt)* } , $($rest:tt)*
+) => {
+    fsm!(@sort $header [$($states)*] [$($superstates)* ($state [$($parent)?] [$($($options)*)?] { $($body)* })] $($rest)*);
+};
+
+(@sort $header:tt [$($states:tt)*] [$($superstates:tt)*]
+    $state:ident $(in $parent:ident)? : $([$($options:tt)*])? { $($body:tt)* } , $($rest:tt)*
+) => {
+    fsm!(@sort $header [$($states)* ($state [$($parent)?] [$($($options)*)?] { $($body)* })] [$($superstates)*] $($rest)*);
+};
+
+(@sort $header:tt $states:tt $superstates:tt) => {
+    fsm!(@generate $header $states $superstates);
+};
+
+// A region only hands a command to the states that accept it, so there is no unhandled policy
+(@region_options
+    $(entry($entry_data:ident) { $($entry:tt)* } $(,)?)?
+    $(exit($exit_data:ident) { $($exit:tt)* } $(,)?)?
+) => {};
+
+(@region_options $($options:tt)*) => {
+    compile_error!("states within regions don't support `unhandled(...)`");
+};
+
+(@parent) => { $crate::machines::shared::Root };
+(@parent $parent:ident) => { $parent };
+
+// Hierarchy, entry/exit actions and typed transition methods of a single state or superstate
+(@state $data:ident, $payload:tt, $state:ident, [$($parent:ident)?],
+    [
+        $(entry($entry_data:ident) { $($entry:tt)* } $(,)?)?
+        $(exit($exit_data:ident) { $($exit:tt)* } $(,)?)?
+        $(unhandled($($policy:tt)*) $(,)?)?
+    ],
+    {
+        $(
+            $command:ident $(($($param:ident: $param_type:ty),+))? => $method:ident($self:ident) $(-> $to_state:ident)?
+            $([$($flags:tt)*])? $({ $($body:tt)* })?
+        ),* $(,)?
+    }
+) => {
+    impl $crate::machines::shared::Nested<$data> for $state {
+        type Parent = fsm!(@parent $($parent)?);
+        const NAME: $crate::machines::shared::StateName = stringify!($state);
+        $(
+            fn entry($entry_data: &mut $data) {
+                $($entry)*
+            }
+        )?
+        $(
+            fn exit($exit_data: &mut $data) {
+                $($exit)*
+            }
+        )?
+    }
+
+    impl FSM<$state, $data> {
+        $(
+            fsm!(@method $data, $payload, $state, $method($self $(, $($param: $param_type),+)?)
+                [$($to_state)?] [$($($flags)*)?] { $($($body)*)? });
+        )*
+    }
+};
+
+(@method $data:ident, $payload:tt, $state:ident, $method:ident($self:ident $(, $param:ident: $param_type:ty)*)
+    [$to_state:ident] [] { $($body:tt)* }
+) => {
+    /// Handles a command and transitions to a new state.
+    ///
+    /// # Arguments
+    /// * `self` - The current FSM instance
+    /// * The parameters for the command, if any
+    ///
+    /// # Returns
+    /// A new FSM instance with the updated state
+    pub fn $method(mut $self $(, $param: $param_type)*) -> fsm!(@returns $payload FSM<$to_state, $data>) {
+        let source = <$state as $crate::machines::shared::Nested<$data>>::path();
+        let target = <$to_state as $crate::machines::shared::Nested<$data>>::path();
+        $crate::machines::shared::exit_states(&mut *$self.data, &mut $self.history, &source, &target);
+        fsm!(@body payload $payload { $($body)* });
+        $crate::machines::shared::enter_states(&mut *$self.data, &source, &target);
+        let machine = FSM {
+           state: PhantomData,
+           data: $self.data,
+           history: $self.history,
+        };
+        fsm!(@output $payload machine, payload)
+    }
+};
+
+// Transition to the history of a superstate, whose active state is only known at runtime
+(@method $data:ident, $payload:tt, $state:ident, $method:ident($self:ident $(, $param:ident: $param_type:ty)*)
+    [$to_state:ident] [$($history:tt)+] { $($body:tt)* }
+) => {
+    /// Handles a command and resumes the superstate where it was left.
+    ///
+    /// # Returns
+    /// The FSM wrapper in the remembered state
+    ///
+    /// # Errors
+    /// The machine as it was, without running any action, if the superstate holds no state to
+    /// resume
+    pub fn $method(mut $self $(, $param: $param_type)*) -> Result<fsm!(@returns $payload FsmWrapper), Self> {
+        let source = <$state as $crate::machines::shared::Nested<$data>>::path();
+        let target = <$to_state as $crate::machines::shared::Nested<$data>>::path();
+        let Some(leaf) = $crate::machines::shared::resume_target::<FsmWrapper, $data>(
+            &$self.history,
+            &source,
+            &target,
+            fsm!(@deep $($history)+),
+        ) else {
+            return Err($self);
+        };
+        $crate::machines::shared::exit_states(&mut *$self.data, &mut $self.history, &source, &target);
+        fsm!(@body payload $payload { $($body)* });
+        let machine = $crate::machines::shared::resume($self.data, $self.history, &source, &target, leaf);
+        Ok(fsm!(@output $payload machine, payload))
+    }
+};
+
+// Internal transition, the state is neither left nor entered
+(@method $data:ident, $payload:tt, $state:ident, $method:ident($self:ident $(, $param:ident: $param_type:ty)*)
+    [] [internal] { $($body:tt)* }
+) => {
+    /// Handles a command without leaving the current state.
+    ///
+    /// # Returns
+    /// The FSM instance with the updated data
+    #[allow(unused_mut)]
+    pub fn $method(mut $self $(, $param: $param_type)*) -> fsm!(@returns $payload FSM<$state, $data>) {
+        fsm!(@body payload $payload { $($body)* });
+        fsm!(@output $payload $self, payload)
+    }
+};
+
+// With a payload declared, a transition body evaluates to the payload of its response
+(@returns [] $machine:ty) => { $machine };
+(@returns [$payload:ty] $machine:ty) => { ($machine, $payload) };
+
+(@body $name:ident [] { $($body:tt)* }) => { $($body)* };
+(@body $name:ident [$payload:ty] {}) => { let $name: $payload = Default::default(); };
+(@body $name:ident [$payload:ty] { $($body:tt)+ }) => { let $name: $payload = { $($body)+ }; };
+
+(@output [] $machine:expr, $name:ident) => { $machine };
+(@output [$payload:ty] $machine:expr, $name:ident) => { ($machine, $name) };
+
+(@split [] $call:expr) => { ($call, ()) };
+(@split [$payload:ty] $call:expr) => { $call };
+
+(@deep H) => { false };
+(@deep H*) => { true };
+
+(@to_name $state:ident) => { stringify!($state) };
+(@to_name $state:ident $to_state:ident) => { stringify!($to_state) };
+
+(@internal internal) => { true };
+(@internal $($flags:tt)*) => { false };
+
+(@history_kind H) => { Some($crate::machines::shared::HistoryKind::Shallow) };
+(@history_kind H*) => { Some($crate::machines::shared::HistoryKind::Deep) };
+(@history_kind $($flags:tt)*) => { None };
+
+// Runs a transition of the active state and responds with the state it leads to
+(@fire [H] $($args:tt)*) => { fsm!(@fire_history $($args)*) };
+(@fire [H*] $($args:tt)*) => { fsm!(@fire_history $($args)*) };
+(@fire [$($flags:tt)*] $data:ident, $payload:tt, $response:ident, $state:ident, [$($to_state:ident)?],
+    $machine:ident.$method:ident, $command_type:ident::$command:ident [$($param:ident),*]
+) => {{
+    let (machine, payload) = fsm!(@split $payload $machine.$method($($param),*));
+    let next = fsm!(@wrap $state [$($to_state)?] [$($flags)*] machine);
+    fsm!(@respond $response $payload [$($flags)*] next, payload)
+}};
+
+// A transition to a history without a state to resume is rejected, the machine stays as it is
+(@fire_history $data:ident, $payload:tt, $response:ident, $state:ident, [$to_state:ident],
+    $machine:ident.$method:ident, $command_type:ident::$command:ident [$($param:ident),*]
+) => {{
+    #[allow(clippy::clone_on_copy)]
+    let attempted_command = fsm!(@attempted $command_type::$command [$($param),*]);
+    match $machine.$method($($param),*) {
+        Ok(output) => {
+            let (next, payload) = fsm!(@split $payload output);
+            fsm!(@respond $response $payload [H] next, payload)
+        }
+        Err(machine) => {
+            let active = FsmWrapper::$state(machine);
+            fsm!(@policy reject; $data, $response, active, attempted_command)
+        }
+    }
+}};
+
+// Runs a transition a superstate handles on behalf of the active substate
+(@lift_fire [H] $($args:tt)*) => { fsm!(@lift_history false, $($args)*) };
+(@lift_fire [H*] $($args:tt)*) => { fsm!(@lift_history true, $($args)*) };
+(@lift_fire [$($flags:tt)*] $superstate:ident, $data:ident, $payload:tt, $response:ident, [$($to_state:ident)?],
+    $active:ident.$method:ident, $command_type:ident::$command:ident [$($param:ident),*]
+) => {{
+    let (next, payload) = fsm!(@lift $superstate, $data, $payload, $active, [$($to_state)?] [$($flags)*] $method($($param),*));
+    Ok(fsm!(@respond $response $payload [$($flags)*] next, payload))
+}};
+
+// Checked before the active substate is left, so a rejected transition runs no actions
+(@lift_history $deep:literal, $superstate:ident, $data:ident, $payload:tt, $response:ident, [$to_state:ident],
+    $active:ident.$method:ident, $command_type:ident::$command:ident [$($param:ident),*]
+) => {{
+    #[allow(clippy::clone_on_copy)]
+    let attempted_command = fsm!(@attempted $command_type::$command [$($param),*]);
+    let target = <$to_state as $crate::machines::shared::Nested<$data>>::path();
+    if !$crate::machines::shared::resumable::<$superstate, $data, FsmWrapper>(&$active, &target, $deep) {
+        return Ok(fsm!(@policy reject; $data, $response, $active, attempted_command));
+    }
+    let source = $crate::machines::shared::ActiveState::path(&$active);
+    let enter = $crate::machines::shared::ActiveState::reenter(&$active);
+    match $crate::machines::shared::lift::<$superstate, $data>($active).$method($($param),*) {
+        Ok(output) => {
+            let (next, payload) = fsm!(@split $payload output);
+            Ok(fsm!(@respond $response $payload [H] next, payload))
+        }
+        Err(machine) => {
+            let active = $crate::machines::shared::return_to(machine, &source, enter);
+            Ok(fsm!(@policy reject; $data, $response, active, attempted_command))
+        }
+    }
+}};
+
+// Parameters of history transitions are cloned, the command is answered if nothing is resumed
+(@attempted $command_type:ident::$command:ident []) => { $command_type::$command };
+(@attempted $command_type:ident::$command:ident [$($param:ident),+]) => {
+    $command_type::$command($($param.clone()),+)
+};
+
+(@wrap $state:ident [] [internal] $machine:expr) => { FsmWrapper::$state($machine) };
+(@wrap $state:ident [$to_state:ident] [] $machine:expr) => { FsmWrapper::$to_state($machine) };
+
+// A superstate's internal transition keeps the active substate, all others leave it first
+(@lift $superstate:ident, $data:ident, $payload:tt, $active:ident, [] [internal] $method:ident($($arg:ident),*)) => {
+    $crate::machines::shared::within::<$superstate, $data, _, _>($active, |machine| {
+        fsm!(@split $payload machine.$method($($arg),*))
+    })
+};
+(@lift $superstate:ident, $data:ident, $payload:tt, $active:ident, [$to_state:ident] [$($history:tt)*] $method:ident($($arg:ident),*)) => {{
+    let (machine, payload) = fsm!(@split $payload
+        $crate::machines::shared::lift::<$superstate, $data>($active).$method($($arg),*));
+    (fsm!(@wrap $superstate [$to_state] [$($history)*] machine), payload)
+}};
+
+(@respond $response:ident [] [internal] $next:ident, $payload:ident) => {{
+    let _ = $payload;
+    let state = StateInfo::state_name(&$next);
+    ($next, $response::Updated { state })
+}};
+(@respond $response:ident [] [$($flags:tt)*] $next:ident, $payload:ident) => {{
+    let _ = $payload;
+    let state = StateInfo::state_name(&$next);
+    ($next, $response::Status { state })
+}};
+(@respond $response:ident [$payload_type:ty] [internal] $next:ident, $payload:ident) => {{
+    let state = StateInfo::state_name(&$next);
+    ($next, $response::Updated { state, payload: $payload })
+}};
+(@respond $response:ident [$payload_type:ty] [$($flags:tt)*] $next:ident, $payload:ident) => {{
+    let state = StateInfo::state_name(&$next);
+    ($next, $response::Status { state, payload: $payload })
+}};
+
+// Policy of a state for commands neither it nor its superstates handle, `reject` by default
+(@unhandled [entry($entry_data:ident) { $($entry:tt)* } $(, $($options:tt)*)?] $($args:tt)*) => {
+    fsm!(@unhandled [$($($options)*)?] $($args)*)
+};
+(@unhandled [exit($exit_data:ident) { $($exit:tt)* } $(, $($options:tt)*)?] $($args:tt)*) => {
+    fsm!(@unhandled [$($($options)*)?] $($args)*)
+};
+(@unhandled [unhandled($($policy:tt)*) $(,)?] $($args:tt)*) => {
+    fsm!(@policy $($policy)*; $($args)*)
+};
+(@unhandled [] $($args:tt)*) => {
+    fsm!(@policy reject; $($args)*)
+};
+
+(@policy reject; $data:ident, $response:ident, $active:ident, $cmd:ident) => {{
+    let current_state = StateInfo::state_name(&$active);
+    ($active, $response::InvalidTransition { current_state, attempted_command: $cmd })
+}};
+(@policy ignore; $data:ident, $response:ident, $active:ident, $cmd:ident) => {{
+    let current_state = StateInfo::state_name(&$active);
+    ($active, $response::Ignored { current_state, attempted_command: $cmd })
+}};
+(@policy defer; $data:ident, $response:ident, $active:ident, $cmd:ident) => {{
+    let current_state = StateInfo::state_name(&$active);
+    ($active, $response::Deferred { current_state, attempted_command: $cmd })
+}};
+(@policy escalate($fault:ident); $data:ident, $response:ident, $active:ident, $cmd:ident) => {{
+    let machine = $crate::machines::shared::escalate::<$fault, $data>($active);
+    (
+        FsmWrapper::$fault(machine),
+        $response::Escalated { state: stringify!($fault), attempted_command: $cmd },
+    )
+}};
+
+// Only machines with a deferring state need a `Deferred` response
+(@deferred [unhandled(defer) $($options:tt)*] $command_type:ident, $response:ident) => {
+    fn deferred(response: &$response) -> Option<&$command_type> {
+        match response {
+            $response::Deferred { attempted_command, .. } => Some(attempted_command),
+            _ => None,
+        }
+    }
+};
+(@deferred [$option:tt $($options:tt)*] $($args:tt)*) => {
+    fsm!(@deferred [$($options)*] $($args)*);
+};
+(@deferred [] $($args:tt)*) => {};
+
+(@events [] $states:tt $command_type:ident) => {};
+(@events [$events:ident] [$($state:ident)*] $command_type:ident) => {
+    fn take_events(&mut self) -> Vec<$command_type> {
+        match self {
+            $(FsmWrapper::$state(machine) => machine.data.$events.take(),)*
+        }
+    }
+};
+
+(@controller [] $data:ident, $command_type:ident, $response:ident) => {};
+
+(@controller [$controller:ident] $data:ident, $command_type:ident, $response:ident) => {
+
+  /// Type alias for the FSM controller.
+pub type FsmController = $controller<$command_type, $response>;
+  impl FsmController {
+    /// Creates a new FSM controller with the given data.
+    ///
+    /// # Arguments
+    /// * `machine_data` - The data to associate with the FSM
+    ///
+    /// # Returns
+    /// A new FSM controller instance
+    pub fn create(machine_data: Box<$data>) -> Self {
+        $controller::new::<Box<$data>, FsmWrapper>(machine_data)
+    }
+
+    /// Creates an FSM controller running on the workers of `executor`.
+    pub fn create_on(executor: &$crate::machines::executor::Executor, machine_data: Box<$data>) -> Self {
+        $controller::spawn_on::<Box<$data>, FsmWrapper>(executor, machine_data)
+    }
+  }
+};
+
+(@generate
+    [$start_state:ident, $data:ident, $payload:tt, $events:tt, $command_type:ident, $command_kind:ident, $response:ident, $state_handler:ident, $controller:tt]
+    [$(
+        ($from_state:ident [$($from_parent:ident)?] [$($from_options:tt)*] {
+            $(
+               $command:ident $(($($param:ident: $param_type:ty),+))? => $method:ident($self:ident) $(-> $to_state:ident)?
+                $([$($flags:tt)*])? $({ $($body:tt)* })?
+            ),* $(,)?
+        })
+    )*]
+    [$(
+        ($superstate:ident [$($superstate_parent:ident)?] [$($superstate_options:tt)*] {
+            $(
+               $superstate_command:ident $(($($superstate_param:ident: $superstate_param_type:ty),+))?
+                => $superstate_method:ident($superstate_self:ident) $(-> $superstate_to_state:ident)?
+                $([$($superstate_flags:tt)*])? $({ $($superstate_body:tt)* })?
+            ),* $(,)?
+        })
+    )*]
 ) => {
-    impl <$start_state, $data> FSM<$start_state, $data>{
+    impl FSM<$start_state, $data> {
         /// Creates a new FSM with the given data.
         ///
         /// # Arguments
@@ -57,13 +816,14 @@ macro_rules! fsm {
         pub fn new(data: Box<$data>) -> FSM<$start_state, $data> {
             FSM{
                 state: PhantomData,
-                data
+                data,
+                history: Default::default(),
             }
         }
     }
 
 
-    impl<State, $data> FSM<State, $data>
+    impl<State> FSM<State, $data>
     where $data : std::fmt::Debug
     {
         /// Prints the current state and data of the FSM.
@@ -73,27 +833,22 @@ macro_rules! fsm {
     }
 
  $(
-    impl FSM<$from_state, $data> {
+    fsm!(@state $data, $payload, $from_state, [$($from_parent)?], [$($from_options)*], {
         $(
-            /// Handles a command and transitions to a new state.
-            ///
-            /// # Arguments
-            /// * `self` - The current FSM instance
-            /// * The parameters for the command, if any
-            ///
-            /// # Returns
-            /// A new FSM instance with the updated state
-            pub fn $method(mut $self $(, $($param: $param_type),+)?) -> FSM<$to_state, $data> {
-                $(
-                    $($body)*
-                )?
-                FSM {
-                   state: PhantomData,
-                   data: $self.data,
-                }
-            }
-        )*
-    }
+            $command $(($($param: $param_type),+))? => $method($self) $(-> $to_state)?
+                $([$($flags)*])? $({ $($body)* })?
+        ),*
+    });
+  )*
+
+ $(
+    fsm!(@state $data, $payload, $superstate, [$($superstate_parent)?], [$($superstate_options)*], {
+        $(
+            $superstate_command $(($($superstate_param: $superstate_param_type),+))?
+                => $superstate_method($superstate_self) $(-> $superstate_to_state)?
+                $([$($superstate_flags)*])? $({ $($superstate_body)* })?
+        ),*
+    });
   )*
 
 
@@ -108,6 +863,9 @@ pub enum FsmWrapper {
 
 
   impl FsmWrapper{
+    /// Names of all states as reported in responses
+    pub const STATES: &'static [&'static str] = &[$(stringify!($from_state)),*];
+
     /// Creates a new FSM wrapper with the given data.
     ///
     /// # Arguments
@@ -136,21 +894,127 @@ pub enum FsmWrapper {
     }
   }
 
-  impl From<Box<$data>> for FsmWrapper {
-    /// Converts the given data into an FSM wrapper.
-    ///
-    /// # Arguments
-    /// * `lathe_data` - The data to convert
-    ///
-    /// # Returns
-    /// An FSM wrapper instance
-    fn from(lathe_data: Box<$data>) -> Self {
-        FsmWrapper::Off(FSM::<$start_state, $data>::new(lathe_data))
+  impl $crate::machines::shared::ActiveState<$data> for FsmWrapper {
+    fn path(&self) -> Vec<$crate::machines::shared::StateNode<$data>> {
+        match self {
+            $(
+                FsmWrapper::$from_state(_) => <$from_state as $crate::machines::shared::Nested<$data>>::path(),
+            )*
+        }
     }
-  }
 
-  impl $state_handler<$command_type, $response, FsmWrapper> for FsmWrapper {
-    /// Handles a command and returns the new state and response.
+    fn into_parts(self) -> (Box<$data>, $crate::machines::shared::History) {
+        match self {
+            $(
+                FsmWrapper::$from_state(machine) => (machine.data, machine.history),
+            )*
+        }
+    }
+
+    fn history(&self) -> &$crate::machines::shared::History {
+        match self {
+            $(
+                FsmWrapper::$from_state(machine) => &machine.history,
+            )*
+        }
+    }
+
+    fn leaves() -> Vec<$crate::machines::shared::Leaf<$data, Self>> {
+        vec![$(
+            $crate::machines::shared::Leaf {
+                path: <$from_state as $crate::machines::shared::Nested<$data>>::path(),
+                enter: |data, history| FsmWrapper::$from_state(FSM {
+                    state: PhantomData,
+                    data,
+                    history,
+                }),
+            }
+        ),*]
+    }
+
+    fn reenter(&self) -> fn(Box<$data>, $crate::machines::shared::History) -> Self {
+        match self {
+            $(
+                FsmWrapper::$from_state(_) => |data, history| FsmWrapper::$from_state(FSM {
+                    state: PhantomData,
+                    data,
+                    history,
+                }),
+            )*
+        }
+    }
+  }
+
+  impl StateInfo for FsmWrapper {
+    type CommandKind = $command_kind;
+
+    const TRANSITIONS: &'static [Transition<$command_kind>] = &[
+        $(
+            $(
+                Transition {
+                    from: stringify!($from_state),
+                    command: $command_kind::$command,
+                    to: fsm!(@to_name $from_state $($to_state)?),
+                    internal: fsm!(@internal $($($flags)*)?),
+                    history: fsm!(@history_kind $($($flags)*)?),
+                    method: stringify!($method),
+                },
+            )*
+        )*
+        $(
+            $(
+                Transition {
+                    from: stringify!($superstate),
+                    command: $command_kind::$superstate_command,
+                    to: fsm!(@to_name $superstate $($superstate_to_state)?),
+                    internal: fsm!(@internal $($($superstate_flags)*)?),
+                    history: fsm!(@history_kind $($($superstate_flags)*)?),
+                    method: stringify!($superstate_method),
+                },
+            )*
+        )*
+    ];
+
+    fn state_name(&self) -> StateName {
+        match self {
+            $(
+                FsmWrapper::$from_state(_) => stringify!($from_state),
+            )*
+        }
+    }
+
+    fn allowed_commands(&self) -> &'static [$command_kind] {
+        match self {
+            $(
+                FsmWrapper::$from_state(_) => {
+                    static ALLOWED: std::sync::OnceLock<Vec<$command_kind>> = std::sync::OnceLock::new();
+                    ALLOWED.get_or_init(|| {
+                        $crate::machines::shared::allowed_in(
+                            Self::TRANSITIONS,
+                            &<$from_state as $crate::machines::shared::Nested<$data>>::path(),
+                        )
+                    })
+                }
+            )*
+        }
+    }
+  }
+
+  impl From<Box<$data>> for FsmWrapper {
+    /// Converts the given data into an FSM wrapper.
+    ///
+    /// # Arguments
+    /// * `machine_data` - The data to convert
+    ///
+    /// # Returns
+    /// An FSM wrapper instance
+    fn from(machine_data: Box<$data>) -> Self {
+        FsmWrapper::$start_state(FSM::<$start_state, $data>::new(machine_data))
+    }
+  }
+
+  impl $state_handler<$command_type, $response, FsmWrapper> for FsmWrapper {
+    /// Handles a command and returns the new state and response.
     ///
     /// # Arguments
     /// * `self` - The current FSM wrapper instance
@@ -161,25 +1025,23 @@ pub enum FsmWrapper {
     fn handle_cmd(self, cmd: $command_type) -> (FsmWrapper, $response) {
         self.handle_cmd(cmd)
     }
-  }
 
+    fsm!(@deferred [$($($from_options)*)*] $command_type, $response);
+    fsm!(@events $events [$($from_state)*] $command_type);
 
-  /// Type alias for the FSM controller.
-pub type FsmController = $controller<$command_type, $response>;
-  impl FsmController {
-    /// Creates a new FSM controller with the given data.
-    ///
-    /// # Arguments
-    /// * `lathe_data` - The data to associate with the FSM
-    ///
-    /// # Returns
-    /// A new FSM controller instance
-    pub fn create(lathe_data: Box<$data>) -> Self {
-        $controller::new::<Box<$data>, FsmWrapper>(lathe_data)
+    fn active_states(&self) -> Vec<StateName> {
+        vec![self.state_name()]
+    }
+
+    fn allowed_command_names(&self) -> Vec<&'static str> {
+        StateInfo::allowed_commands(self).iter().map($command_kind::name).collect()
     }
   }
 
 
+  fsm!(@controller $controller $data, $command_type, $response);
+
+
   $(
     impl $state_handler<$command_type, $response, FsmWrapper> for FSM<$from_state, $data>{
         /// Handles a command and returns the new state and response.
@@ -194,33 +1056,462 @@ pub type FsmController = $controller<$command_type, $response>;
             match cmd {
                 $(
                     $command_type::$command$(($($param),+))? => {
-                        let new_fsm = self.$method($($($param),+)?);
-                        (
-                            FsmWrapper::$to_state(new_fsm),
-                            $response::Status {state: stringify!($to_state)},
-                        )
+                        fsm!(@fire [$($($flags)*)?] $data, $payload, $response, $from_state, [$($to_state)?],
+                            self.$method, $command_type::$command [$($($param),+)?])
                     }
 
                 )*
-                _ => (
-                            FsmWrapper::$from_state(self),
-                            $response::InvalidTransition {
-                                current_state: stringify!($from_state),
-                                attempted_command: format!("{:?}", cmd),
-                            }
-                    )
-
+                _ => match <fsm!(@parent $($from_parent)?) as $crate::machines::shared::SuperstateHandler<
+                        $command_type,
+                        $response,
+                        FsmWrapper,
+                    >>::handle_cmd(FsmWrapper::$from_state(self), cmd) {
+                    Ok(handled) => handled,
+                    Err((active, cmd)) => fsm!(@unhandled [$($from_options)*] $data, $response, active, cmd),
+                },
             }
         }
     }
 
   )*
 
+  $(
+    impl $crate::machines::shared::SuperstateHandler<$command_type, $response, FsmWrapper> for $superstate {
+        fn handle_cmd(
+            active: FsmWrapper,
+            cmd: $command_type,
+        ) -> Result<(FsmWrapper, $response), (FsmWrapper, $command_type)> {
+            match cmd {
+                $(
+                    $command_type::$superstate_command$(($($superstate_param),+))? => {
+                        fsm!(@lift_fire [$($($superstate_flags)*)?] $superstate, $data, $payload, $response,
+                            [$($superstate_to_state)?], active.$superstate_method,
+                            $command_type::$superstate_command [$($($superstate_param),+)?])
+                    }
+                )*
+                _ => <fsm!(@parent $($superstate_parent)?) as $crate::machines::shared::SuperstateHandler<
+                        $command_type,
+                        $response,
+                        FsmWrapper,
+                    >>::handle_cmd(active, cmd),
+            }
+        }
+    }
+  )*
+
+  impl $crate::machines::shared::SuperstateHandler<$command_type, $response, FsmWrapper>
+    for $crate::machines::shared::Root
+  {
+    fn handle_cmd(
+        active: FsmWrapper,
+        cmd: $command_type,
+    ) -> Result<(FsmWrapper, $response), (FsmWrapper, $command_type)> {
+        Err((active, cmd))
+    }
+  }
+
 };
 
 }
 
-pub(in crate::machines) use fsm;
+pub(crate) use fsm;
+
+/// Macro for deriving a fieldless discriminant enum from a command enum.
+///
+/// Lets UIs and clients talk about a command without having to make up its parameters.
+///
+/// # Parameters
+/// * `MachineCommand` - The command enum
+/// * `CommandKind` - The name of the generated discriminant enum
+/// * The variant names of `MachineCommand`
+macro_rules! command_kind {
+    ($command_type:ident => $command_kind:ident { $($command:ident),* $(,)? }) => {
+        #[doc = concat!("Fieldless discriminant of [`", stringify!($command_type), "`]")]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        pub enum $command_kind {
+            $($command,)*
+        }
+
+        impl $command_kind {
+            /// All kinds in declaration order
+            pub const ALL: &'static [$command_kind] = &[$($command_kind::$command),*];
+
+            /// Name of the kind as reported by `allowed_command_names`
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $($command_kind::$command => stringify!($command),)*
+                }
+            }
+        }
+
+        impl $command_type {
+            /// The kind of this command without its parameters
+            pub fn kind(&self) -> $command_kind {
+                match self {
+                    $(
+                        $command_type::$command { .. } => $command_kind::$command,
+                    )*
+                }
+            }
+        }
+
+        impl $crate::machines::shared::KindedCommand for $command_type {
+            type Kind = $command_kind;
+
+            fn kind_named(name: &str) -> Option<$command_kind> {
+                $command_kind::ALL.iter().copied().find(|kind| kind.name() == name)
+            }
+
+            fn kind_name(kind: $command_kind) -> &'static str {
+                kind.name()
+            }
+        }
+    };
+}
+
+pub(crate) use command_kind;
+
+/// The implicit superstate of all top level states
+#[derive(Debug)]
+pub struct Root;
+
+/// Position of a state in the state hierarchy, implemented by `fsm!` for every state.
+///
+/// # Type Parameters
+/// * `Data` - The data the entry and exit actions work on
+pub trait Nested<Data> {
+    /// The superstate this state is declared `in`
+    type Parent: Nested<Data>;
+
+    const NAME: StateName;
+
+    /// Action run whenever a transition enters this state
+    fn entry(_data: &mut Data) {}
+
+    /// Action run whenever a transition leaves this state
+    fn exit(_data: &mut Data) {}
+
+    /// This state and its superstates, outermost first
+    fn path() -> Vec<StateNode<Data>> {
+        let mut path = Self::Parent::path();
+        path.push(StateNode {
+            name: Self::NAME,
+            entry: Self::entry,
+            exit: Self::exit,
+        });
+        path
+    }
+}
+
+impl<Data> Nested<Data> for Root {
+    type Parent = Root;
+
+    const NAME: StateName = "Root";
+
+    fn path() -> Vec<StateNode<Data>> {
+        Vec::new()
+    }
+}
+
+/// A state on a path through the state hierarchy
+pub struct StateNode<Data> {
+    pub name: StateName,
+    pub entry: fn(&mut Data),
+    pub exit: fn(&mut Data),
+}
+
+/// Access to the active state of a wrapper, whichever it is
+pub trait ActiveState<Data>: Sized {
+    /// Path of the active state, outermost superstate first
+    fn path(&self) -> Vec<StateNode<Data>>;
+
+    fn into_parts(self) -> (Box<Data>, History);
+
+    fn into_data(self) -> Box<Data> {
+        self.into_parts().0
+    }
+
+    fn history(&self) -> &History;
+
+    /// All states that can be active, in declaration order
+    fn leaves() -> Vec<Leaf<Data, Self>>;
+
+    /// Makes the active state active again on other data, without running any actions
+    fn reenter(&self) -> fn(Box<Data>, History) -> Self;
+}
+
+/// A state that can be active
+pub struct Leaf<Data, Wrapper> {
+    /// The state and its superstates, outermost first
+    pub path: Vec<StateNode<Data>>,
+    /// Makes the state active without running any actions
+    pub enter: fn(Box<Data>, History) -> Wrapper,
+}
+
+/// The states superstates were left in, for transitions to their history.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct History {
+    remembered: Vec<Remembered>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Remembered {
+    superstate: StateName,
+    child: StateName,
+    leaf: StateName,
+}
+
+impl History {
+    /// Direct substate active when `superstate` was last left
+    pub fn shallow(&self, superstate: StateName) -> Option<StateName> {
+        self.find(superstate).map(|remembered| remembered.child)
+    }
+
+    /// Innermost state active when `superstate` was last left
+    pub fn deep(&self, superstate: StateName) -> Option<StateName> {
+        self.find(superstate).map(|remembered| remembered.leaf)
+    }
+
+    fn find(&self, superstate: StateName) -> Option<&Remembered> {
+        self.remembered
+            .iter()
+            .find(|remembered| remembered.superstate == superstate)
+    }
+
+    /// Remembers the substates of the superstates on `path` from `depth` on.
+    fn record<Data>(&mut self, path: &[StateNode<Data>], depth: usize) {
+        let Some(leaf) = path.last() else { return };
+        for pair in path[depth.min(path.len())..].windows(2) {
+            let remembered = Remembered {
+                superstate: pair[0].name,
+                child: pair[1].name,
+                leaf: leaf.name,
+            };
+            match self
+                .remembered
+                .iter_mut()
+                .find(|known| known.superstate == remembered.superstate)
+            {
+                Some(known) => *known = remembered,
+                None => self.remembered.push(remembered),
+            }
+        }
+    }
+}
+
+/// Handles the commands a superstate accepts on behalf of its active substate.
+///
+/// Commands the superstate doesn't accept are passed on to its own superstate; `Root`
+/// finally hands them back, so the active substate can apply its policy for unhandled
+/// commands.
+pub trait SuperstateHandler<Command, Response, FsmWrapper> {
+    fn handle_cmd(
+        active: FsmWrapper,
+        cmd: Command,
+    ) -> Result<(FsmWrapper, Response), (FsmWrapper, Command)>;
+}
+
+/// Number of states a transition neither leaves nor enters.
+///
+/// A transition always leaves its source and enters its target, even if one contains the other.
+fn common_depth<Data>(source: &[StateNode<Data>], target: &[StateNode<Data>]) -> usize {
+    let shared = source
+        .iter()
+        .zip(target)
+        .take_while(|(from, to)| from.name == to.name)
+        .count();
+    shared.min(source.len().min(target.len()).saturating_sub(1))
+}
+
+/// Runs the exit actions of the states a transition leaves, innermost first, and remembers
+/// where the superstates were left.
+pub fn exit_states<Data>(
+    data: &mut Data,
+    history: &mut History,
+    source: &[StateNode<Data>],
+    target: &[StateNode<Data>],
+) {
+    let depth = common_depth(source, target);
+    history.record(source, depth);
+    for node in source[depth..].iter().rev() {
+        (node.exit)(data);
+    }
+}
+
+/// Runs the entry actions of the states a transition enters, outermost first.
+pub fn enter_states<Data>(data: &mut Data, source: &[StateNode<Data>], target: &[StateNode<Data>]) {
+    for node in &target[common_depth(source, target)..] {
+        (node.entry)(data);
+    }
+}
+
+/// Leaves the active substate of a wrapper up to `Superstate`, so a transition of the
+/// superstate can take over.
+pub fn lift<Superstate, Data>(active: impl ActiveState<Data>) -> FSM<Superstate, Data>
+where
+    Superstate: Nested<Data>,
+{
+    let source = active.path();
+    let (mut data, mut history) = active.into_parts();
+    let depth = Superstate::path().len();
+    history.record(&source, depth.saturating_sub(1));
+    for node in source[depth..].iter().rev() {
+        (node.exit)(&mut data);
+    }
+    FSM {
+        state: PhantomData,
+        data,
+        history,
+    }
+}
+
+/// Leaves the active state for `Target` when a command can't be handled.
+pub fn escalate<Target, Data>(active: impl ActiveState<Data>) -> FSM<Target, Data>
+where
+    Target: Nested<Data>,
+{
+    let source = active.path();
+    let target = Target::path();
+    let (mut data, mut history) = active.into_parts();
+    exit_states(&mut *data, &mut history, &source, &target);
+    enter_states(&mut *data, &source, &target);
+    FSM {
+        state: PhantomData,
+        data,
+        history,
+    }
+}
+
+/// Runs an internal transition of `Superstate` without leaving the active substate.
+pub fn within<Superstate, Data, Wrapper, Payload>(
+    active: Wrapper,
+    transition: impl FnOnce(FSM<Superstate, Data>) -> (FSM<Superstate, Data>, Payload),
+) -> (Wrapper, Payload)
+where
+    Wrapper: ActiveState<Data>,
+{
+    let enter = active.reenter();
+    let (data, history) = active.into_parts();
+    let (machine, payload) = transition(FSM {
+        state: PhantomData,
+        data,
+        history,
+    });
+    (enter(machine.data, machine.history), payload)
+}
+
+/// The state a transition from `source` to the history of `superstate` resumes: the state it
+/// was left in, or the first state declared in it if it wasn't active yet.
+///
+/// `None` if the superstate holds no state the machine can be in.
+pub fn resume_target<Wrapper, Data>(
+    history: &History,
+    source: &[StateNode<Data>],
+    superstate: &[StateNode<Data>],
+    deep: bool,
+) -> Option<Leaf<Data, Wrapper>>
+where
+    Wrapper: ActiveState<Data>,
+{
+    let name = superstate.last()?.name;
+    let mut history = history.clone();
+    history.record(source, common_depth(source, superstate));
+    let remembered = if deep {
+        history.deep(name)
+    } else {
+        history.shallow(name)
+    };
+    let wanted = remembered.unwrap_or(name);
+    Wrapper::leaves()
+        .into_iter()
+        .find(|leaf| leaf.path.iter().any(|node| node.name == wanted))
+}
+
+/// Whether a transition of `Superstate` to the history of `target` finds a state to resume once
+/// the active substate was left for the superstate.
+pub fn resumable<Superstate, Data, Wrapper>(
+    active: &Wrapper,
+    target: &[StateNode<Data>],
+    deep: bool,
+) -> bool
+where
+    Superstate: Nested<Data>,
+    Wrapper: ActiveState<Data>,
+{
+    let source = Superstate::path();
+    let mut history = active.history().clone();
+    history.record(&active.path(), source.len().saturating_sub(1));
+    resume_target::<Wrapper, Data>(&history, &source, target, deep).is_some()
+}
+
+/// Enters the substate a superstate transition left again, running its entry actions, for a
+/// transition that didn't fire.
+pub fn return_to<Superstate, Data, Wrapper>(
+    machine: FSM<Superstate, Data>,
+    source: &[StateNode<Data>],
+    enter: fn(Box<Data>, History) -> Wrapper,
+) -> Wrapper
+where
+    Superstate: Nested<Data>,
+{
+    let FSM {
+        mut data, history, ..
+    } = machine;
+    for node in &source[Superstate::path().len().min(source.len())..] {
+        (node.entry)(&mut data);
+    }
+    enter(data, history)
+}
+
+/// Enters a superstate in the state found by [`resume_target`].
+pub fn resume<Wrapper, Data>(
+    mut data: Box<Data>,
+    history: History,
+    source: &[StateNode<Data>],
+    superstate: &[StateNode<Data>],
+    leaf: Leaf<Data, Wrapper>,
+) -> Wrapper {
+    for node in &leaf.path[common_depth(source, superstate)..] {
+        (node.entry)(&mut data);
+    }
+    (leaf.enter)(data, history)
+}
+
+/// Commands accepted along a path, the innermost state's transitions first.
+pub fn allowed_in<CommandKind: Copy + PartialEq, Data>(
+    transitions: &[Transition<CommandKind>],
+    path: &[StateNode<Data>],
+) -> Vec<CommandKind> {
+    let mut allowed = Vec::new();
+    for node in path.iter().rev() {
+        for transition in transitions.iter().filter(|t| t.from == node.name) {
+            if !allowed.contains(&transition.command) {
+                allowed.push(transition.command);
+            }
+        }
+    }
+    allowed
+}
+
+/// Hands out the `'static` value built for `key`, building and leaking it on first use.
+///
+/// A region machine has one state name and one set of allowed commands per combination of its
+/// region states. There are only finitely many, so each is leaked once.
+pub fn interned<Key: PartialEq, Value: ?Sized>(
+    cache: &Mutex<Vec<(Key, &'static Value)>>,
+    key: Key,
+    build: impl FnOnce(&Key) -> Box<Value>,
+) -> &'static Value {
+    let mut cache = cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((_, value)) = cache.iter().find(|(cached, _)| *cached == key) {
+        return value;
+    }
+    let value: &'static Value = Box::leak(build(&key));
+    cache.push((key, value));
+    value
+}
 
 /// Trait for handling commands in the FSM.
 ///
@@ -238,55 +1529,476 @@ pub trait StateHandler<Command, Response, FsmWrapper> {
     /// # Returns
     /// A tuple containing the new FSM wrapper instance and the response
     fn handle_cmd(self, cmd: Command) -> (FsmWrapper, Response);
+
+    /// The command a response reports as deferred, which the machine thread hands over
+    /// again after the next transition.
+    fn deferred(_response: &Response) -> Option<&Command> {
+        None
+    }
+
+    /// Internal events raised by the last command, which the machine thread handles before
+    /// the next command.
+    fn take_events(&mut self) -> Vec<Command> {
+        Vec::new()
+    }
+
+    /// Names of the states the machine is in, one per region, for observers like interlocks.
+    fn active_states(&self) -> Vec<StateName> {
+        Vec::new()
+    }
+
+    /// Names of the command kinds the machine accepts right now, for clients asking what they
+    /// can do next.
+    fn allowed_command_names(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
 }
 
-/// Controller for managing an FSM in a separate thread.
+/// Events a machine raises for itself from transition bodies and entry or exit actions.
 ///
-/// # Type Parameters
-/// * `Command` - The type of commands that can be sent to the FSM
-/// * `Response` - The type of responses that can be returned by the FSM
-pub struct MachineController<Command, Response>
-where
-    Command: Send + 'static,
-    Response: Send + 'static,
-{
-    cmd_tx: mpsc::Sender<Command>,
-    response_rx: mpsc::Receiver<Response>,
-    thread_handle: JoinHandle<()>,
+/// The machine data holds the outbox; the machine thread runs the posted events to completion
+/// before it takes the next command.
+#[derive(Debug)]
+pub struct Outbox<Command> {
+    events: Vec<Command>,
 }
 
-impl<Command, Response> MachineController<Command, Response>
+impl<Command> Default for Outbox<Command> {
+    fn default() -> Self {
+        Self { events: Vec::new() }
+    }
+}
+
+impl<Command> Outbox<Command> {
+    pub fn post(&mut self, event: Command) {
+        self.events.push(event);
+    }
+
+    pub fn take(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// How a transition to the history of a superstate picks the state to resume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKind {
+    /// The direct substate the superstate was left in, `[H]`
+    Shallow,
+    /// The innermost state the superstate was left in, `[H*]`
+    Deep,
+}
+
+/// One edge of a machine's state diagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition<CommandKind> {
+    pub from: StateName,
+    pub command: CommandKind,
+    /// The target state, the source state itself for internal transitions and the superstate
+    /// for transitions to a history
+    pub to: StateName,
+    /// Whether the transition stays in its state without running exit and entry actions
+    pub internal: bool,
+    /// Set for transitions to the history of the superstate in `to`
+    pub history: Option<HistoryKind>,
+    /// The typestate method performing the transition
+    pub method: &'static str,
+}
+
+/// A machine owned by the data of another machine.
+///
+/// Commands are handled right away together with the internal events they raise, so the
+/// parent always sees the child's current state. A command the child defers is handed over
+/// again after each later command, like its controller would. Whenever the states of the child
+/// change, `notify` may turn them into an event for the parent.
+pub struct Child<Wrapper, Command, Event> {
+    dispatcher: Dispatcher<Wrapper, Command, ()>,
+    notify: fn(&[StateName]) -> Option<Event>,
+}
+
+impl<Wrapper, Command, Event> Child<Wrapper, Command, Event>
 where
-    Command: Send + 'static,
-    Response: Send + 'static,
+    Command: Clone,
 {
-    /// Creates a new FSM controller with the given data.
-    ///
-    /// # Arguments
-    /// * `machine_data` - The data to associate with the FSM
+    pub fn new(machine: Wrapper, notify: fn(&[StateName]) -> Option<Event>) -> Self {
+        Self {
+            dispatcher: Dispatcher::new(machine),
+            notify,
+        }
+    }
+
+    /// The child, `Err` once it was lost in a panic or halted for raising too many events
+    pub fn machine(&self) -> Result<&Wrapper, &'static str> {
+        self.dispatcher.machine()
+    }
+
+    /// Hands a command to the child.
     ///
     /// # Returns
-    /// A new FSM controller instance
-    pub fn new<MachineData, FsmWrapper>(machine_data: MachineData) -> Self
+    /// The responses in the order a controller would report them: the command's, those to the
+    /// events it raises and those to deferred commands handed over again
+    pub fn forward<Response>(
+        &mut self,
+        cmd: Command,
+        events: &mut Outbox<Event>,
+    ) -> Result<Vec<Response>, &'static str>
     where
-        FsmWrapper:
-            Send + 'static + StateHandler<Command, Response, FsmWrapper> + From<MachineData>,
+        Wrapper: StateHandler<Command, Response, Wrapper>,
     {
-        let fsm_wrapper = FsmWrapper::from(machine_data);
+        let before = self.machine()?.active_states();
+        let mut responses = Vec::new();
+        self.dispatcher
+            .dispatch((), cmd, &mut |(), response| responses.push(response))?;
 
-        let (cmd_tx, cmd_rx) = std::sync::mpsc::channel();
-        let (response_tx, response_rx) = std::sync::mpsc::channel();
-        let machine_thread = MachineThread::new(cmd_rx, response_tx, fsm_wrapper);
+        let after = self.machine()?.active_states();
+        if before != after
+            && let Some(event) = (self.notify)(&after)
+        {
+            events.post(event);
+        }
+        Ok(responses)
+    }
+}
 
-        let thread_handle = thread::spawn(move || {
-            machine_thread.run();
-        });
+impl<Wrapper, Command, Event> std::fmt::Debug for Child<Wrapper, Command, Event> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Child").finish_non_exhaustive()
+    }
+}
+
+/// Renders a transition table as a mermaid state diagram.
+///
+/// Internal transitions are drawn as self-transitions labelled `[internal]`, transitions to a
+/// history lead to the superstate and are labelled `[H]` or `[H*]`.
+pub fn state_diagram<CommandKind: std::fmt::Debug>(
+    start_state: StateName,
+    transitions: &[Transition<CommandKind>],
+) -> String {
+    let mut diagram = format!("stateDiagram-v2\n    [*] --> {}\n", start_state);
+    for transition in transitions {
+        let marker = match (transition.internal, transition.history) {
+            (true, _) => " [internal]",
+            (_, Some(HistoryKind::Shallow)) => " [H]",
+            (_, Some(HistoryKind::Deep)) => " [H*]",
+            (false, None) => "",
+        };
+        diagram.push_str(&format!(
+            "    {} --> {} : {:?}{}\n",
+            transition.from, transition.to, transition.command, marker
+        ));
+    }
+    diagram
+}
+
+/// Runtime view on the state a wrapper is in.
+///
+/// Lets operators and UIs see what a machine can do next without trying commands.
+pub trait StateInfo {
+    /// Fieldless discriminant of the machine's commands
+    type CommandKind: 'static;
+
+    /// Every transition of the machine, in declaration order
+    const TRANSITIONS: &'static [Transition<Self::CommandKind>];
+
+    /// Name of the current state as reported in responses
+    fn state_name(&self) -> StateName;
+
+    /// The commands the current state has a transition for
+    fn allowed_commands(&self) -> &'static [Self::CommandKind];
+}
+
+/// Commands with a fieldless discriminant, implemented by `command_kind!`
+pub trait KindedCommand {
+    type Kind: Copy + PartialEq + std::fmt::Debug + 'static;
+
+    /// The kind reported under `name` by `allowed_command_names`
+    fn kind_named(name: &str) -> Option<Self::Kind>;
+
+    /// The name of `kind`, which is also the variant name of its commands on the wire
+    fn kind_name(kind: Self::Kind) -> &'static str;
+}
+
+/// Commands of a machine with a spindle
+pub trait SpindleCommands: Sized + 'static {
+    /// Commands that bring the spindle to a standstill from any running state, in order.
+    ///
+    /// Steps that do not apply to the current state are rejected by the machine and skipped.
+    const STOP_SPINDLE: &'static [Self];
+}
+
+/// Commands of a machine with an emergency stop
+pub trait EmergencyCommands: Sized + 'static {
+    const EMERGENCY: Self;
+    const ACKNOWLEDGE: Self;
+    /// State the machine reports once `EMERGENCY` took effect
+    const EMERGENCY_STATE: StateName;
+}
+
+/// Responses of a machine whose state names are fixed, so names read from the wire can be
+/// mapped onto them instead of being allocated.
+pub trait KnownStates {
+    const STATES: &'static [StateName];
+}
+
+/// Maps a state name read from the wire onto the machine's own state names.
+///
+/// Responses carry `&'static str` state names, which cannot borrow from the incoming line.
+/// Used by the machines' `deserialize_with` helpers.
+pub fn known_state<'de, D>(
+    deserializer: D,
+    states: &'static [&'static str],
+) -> Result<&'static str, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    states
+        .iter()
+        .copied()
+        .find(|state| *state == name)
+        .ok_or_else(|| D::Error::unknown_variant(&name, states))
+}
+
+/// Maps the names reported by `allowed_command_names` onto the command kinds.
+pub fn kinds_named<Command: KindedCommand>(
+    names: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<Vec<Command::Kind>, &'static str> {
+    names
+        .into_iter()
+        .map(|name| Command::kind_named(name.as_ref()).ok_or("Unknown command kind"))
+        .collect()
+}
+
+/// Common interface of local and remote machine controllers.
+///
+/// Code written against this trait drives a machine regardless of whether it runs in a local
+/// thread or behind a server.
+///
+/// # Type Parameters
+/// * `Command` - The type of commands that can be sent to the FSM
+/// * `Response` - The type of responses that can be returned by the FSM
+pub trait Controller<Command, Response> {
+    /// Sends a command without waiting for its response.
+    fn send_command(&self, cmd: Command) -> Result<(), &'static str>;
+
+    /// Collects the responses that arrived since the last call.
+    fn check_responses(&self) -> Vec<Response>;
+
+    /// Sends a command and waits for its response.
+    fn request(&self, cmd: Command) -> Result<Response, &'static str>;
+
+    /// Sends a command that overtakes the commands still queued, like an emergency stop, and
+    /// waits for its response.
+    ///
+    /// Controllers without a priority lane send it like any other command.
+    fn request_priority(&self, cmd: Command) -> Result<Response, &'static str> {
+        self.request(cmd)
+    }
+
+    /// The states the machine is in once every command sent so far has been handled.
+    ///
+    /// Controllers that cannot observe the machine report no states.
+    fn active_states(&self) -> Result<Vec<StateName>, &'static str> {
+        Ok(Vec::new())
+    }
+
+    /// The commands the machine accepts once every command sent so far has been handled.
+    ///
+    /// Controllers that cannot observe the machine report no commands.
+    fn allowed_commands(&self) -> Result<Vec<Command::Kind>, &'static str>
+    where
+        Command: KindedCommand,
+    {
+        Ok(Vec::new())
+    }
+}
+
+/// Controller for managing an FSM in a separate thread.
+///
+/// # Type Parameters
+/// * `Command` - The type of commands that can be sent to the FSM
+/// * `Response` - The type of responses that can be returned by the FSM
+pub struct MachineController<Command, Response>
+where
+    Command: Send + 'static,
+    Response: Send + 'static,
+{
+    cmd_tx: mpsc::Sender<Envelope<Command>>,
+    priority: Arc<Mutex<VecDeque<(u64, Command)>>>,
+    response_rx: mpsc::Receiver<(u64, Response)>,
+    next_seq: Cell<u64>,
+    unclaimed: RefCell<Vec<(u64, Response)>>,
+    /// Why the machine stopped for good, if it did
+    fault: Arc<OnceLock<&'static str>>,
+    runner: Runner,
+}
+
+/// How a controller's machine is run
+enum Runner {
+    Thread(JoinHandle<()>),
+    /// `finished` disconnects once the machine handled its last message
+    Pooled {
+        waker: Waker,
+        finished: mpsc::Receiver<()>,
+    },
+}
+
+impl<Command, Response> MachineController<Command, Response>
+where
+    Command: Send + 'static,
+    Response: Send + 'static,
+{
+    /// Creates a new FSM controller with the given data.
+    ///
+    /// # Arguments
+    /// * `machine_data` - The data to associate with the FSM
+    ///
+    /// # Returns
+    /// A new FSM controller instance
+    pub fn new<MachineData, FsmWrapper>(machine_data: MachineData) -> Self
+    where
+        Command: Clone,
+        FsmWrapper:
+            Send + 'static + StateHandler<Command, Response, FsmWrapper> + From<MachineData>,
+    {
+        Self::start(FsmWrapper::from(machine_data), |machine_thread| {
+            Runner::Thread(thread::spawn(move || {
+                machine_thread.run();
+            }))
+        })
+    }
+
+    /// Creates a controller whose machine runs on the workers of `executor` instead of a
+    /// thread of its own.
+    pub fn spawn_on<MachineData, FsmWrapper>(executor: &Executor, machine_data: MachineData) -> Self
+    where
+        Command: Clone,
+        FsmWrapper:
+            Send + 'static + StateHandler<Command, Response, FsmWrapper> + From<MachineData>,
+    {
+        Self::start(FsmWrapper::from(machine_data), |mut machine_thread| {
+            let (finished_tx, finished) = mpsc::channel();
+            machine_thread.finished = Some(finished_tx);
+            Runner::Pooled {
+                waker: executor.spawn(Box::new(machine_thread)),
+                finished,
+            }
+        })
+    }
+
+    fn start<FsmWrapper>(
+        fsm_wrapper: FsmWrapper,
+        run: impl FnOnce(MachineThread<Command, Response, FsmWrapper>) -> Runner,
+    ) -> Self
+    where
+        Command: Clone,
+        FsmWrapper: StateHandler<Command, Response, FsmWrapper>,
+    {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (response_tx, response_rx) = mpsc::channel();
+        let priority = Arc::new(Mutex::new(VecDeque::new()));
+        let fault = Arc::new(OnceLock::new());
+        let machine_thread = MachineThread::new(
+            cmd_rx,
+            Arc::clone(&priority),
+            response_tx,
+            Arc::clone(&fault),
+            fsm_wrapper,
+        );
 
         Self {
             cmd_tx,
+            priority,
             response_rx,
-            thread_handle,
+            next_seq: Cell::new(0),
+            unclaimed: RefCell::new(Vec::new()),
+            fault,
+            runner: run(machine_thread),
+        }
+    }
+
+    fn post(&self, envelope: Envelope<Command>) -> Result<(), &'static str> {
+        self.cmd_tx
+            .send(envelope)
+            .map_err(|_| self.failure("Failed to send command"))?;
+        if let Runner::Pooled { waker, .. } = &self.runner {
+            waker.wake();
         }
+        Ok(())
+    }
+
+    /// Sends a command and returns the sequence number its responses carry.
+    pub(crate) fn send(&self, cmd: Command) -> Result<u64, &'static str> {
+        let seq = self.next_seq();
+        self.post(Envelope::Command(seq, cmd))?;
+        Ok(seq)
+    }
+
+    fn next_seq(&self) -> u64 {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq + 1);
+        seq
+    }
+
+    /// Sends a command that the machine handles before any command still queued.
+    ///
+    /// A command the machine is handling right now is finished first.
+    pub fn send_priority(&self, cmd: Command) -> Result<(), &'static str> {
+        self.push_priority(cmd).map(|_| ())
+    }
+
+    /// Sends a priority command and waits for its response.
+    pub fn request_priority(&self, cmd: Command) -> Result<Response, &'static str> {
+        let seq = self.push_priority(cmd)?;
+        self.wait(seq)
+    }
+
+    /// Asks the machine for its states once it has handled every command sent before.
+    pub fn active_states(&self) -> Result<Vec<StateName>, &'static str> {
+        let (states_tx, states_rx) = mpsc::channel();
+        self.post(Envelope::States(states_tx))?;
+        states_rx
+            .recv()
+            .map_err(|_| self.failure("Failed to receive states"))
+    }
+
+    /// Asks the machine for the sequence numbers of the commands it still holds back once it
+    /// has handled every command sent before, so their responses are still to come.
+    pub(crate) fn deferred_seqs(&self) -> Result<Vec<u64>, &'static str> {
+        let (seqs_tx, seqs_rx) = mpsc::channel();
+        self.post(Envelope::Deferred(seqs_tx))?;
+        seqs_rx
+            .recv()
+            .map_err(|_| self.failure("Failed to receive deferred commands"))
+    }
+
+    /// Asks the machine for the names of the command kinds it accepts once it has handled
+    /// every command sent before.
+    pub fn allowed_command_names(&self) -> Result<Vec<&'static str>, &'static str> {
+        let (names_tx, names_rx) = mpsc::channel();
+        self.post(Envelope::AllowedCommands(names_tx))?;
+        names_rx
+            .recv()
+            .map_err(|_| self.failure("Failed to receive allowed commands"))
+    }
+
+    /// Asks the machine for the commands it accepts once it has handled every command sent
+    /// before.
+    pub fn allowed_commands(&self) -> Result<Vec<Command::Kind>, &'static str>
+    where
+        Command: KindedCommand,
+    {
+        kinds_named::<Command>(self.allowed_command_names()?)
+    }
+
+    /// Sends a priority command and returns the sequence number its responses carry.
+    pub(crate) fn push_priority(&self, cmd: Command) -> Result<u64, &'static str> {
+        let seq = self.next_seq();
+        self.priority
+            .lock()
+            .map_err(|_| "Failed to send command")?
+            .push_back((seq, cmd));
+        self.post(Envelope::Priority)?;
+        Ok(seq)
     }
 
     /// Sends a command to the FSM.
@@ -297,7 +2009,34 @@ where
     /// # Returns
     /// `Ok(())` if the command was sent successfully, `Err` otherwise
     pub fn send_command(&self, cmd: Command) -> Result<(), &'static str> {
-        self.cmd_tx.send(cmd).map_err(|_| "Failed to send command")
+        self.send(cmd).map(|_| ())
+    }
+
+    /// Sends a command and waits for its response.
+    ///
+    /// Responses to other commands arriving meanwhile, like those of deferred commands, are
+    /// kept for `check_responses`.
+    pub fn request(&self, cmd: Command) -> Result<Response, &'static str> {
+        let seq = self.send(cmd)?;
+        self.wait(seq)
+    }
+
+    fn wait(&self, seq: u64) -> Result<Response, &'static str> {
+        loop {
+            let (response_seq, response) = self
+                .response_rx
+                .recv()
+                .map_err(|_| self.failure("Failed to receive response"))?;
+            if response_seq == seq {
+                return Ok(response);
+            }
+            self.unclaimed.borrow_mut().push((response_seq, response));
+        }
+    }
+
+    /// The reason the machine stopped for good, `otherwise` if it just went away
+    fn failure(&self, otherwise: &'static str) -> &'static str {
+        self.fault.get().copied().unwrap_or(otherwise)
     }
 
     /// Checks for any responses from the FSM.
@@ -305,9 +2044,18 @@ where
     /// # Returns
     /// A vector of responses
     pub fn check_responses(&self) -> Vec<Response> {
-        let mut responses = Vec::new();
-        while let Ok(response) = self.response_rx.try_recv() {
-            responses.push(response);
+        self.check_numbered()
+            .into_iter()
+            .map(|(_, response)| response)
+            .collect()
+    }
+
+    /// Checks for any responses, together with the sequence number of the command each one
+    /// answers.
+    pub(crate) fn check_numbered(&self) -> Vec<(u64, Response)> {
+        let mut responses = self.unclaimed.take();
+        while let Ok(numbered) = self.response_rx.try_recv() {
+            responses.push(numbered);
         }
         responses
     }
@@ -319,11 +2067,189 @@ where
     pub fn shutdown(self) -> Result<(), Box<dyn std::error::Error>> {
         drop(self.cmd_tx);
 
-        self.thread_handle
-            .join()
-            .map_err(|_| "Thread join failed")?;
+        match self.runner {
+            Runner::Thread(thread_handle) => {
+                thread_handle.join().map_err(|_| "Thread join failed")?;
+            }
+            Runner::Pooled { waker, finished } => {
+                waker.wake();
+                let _ = finished.recv();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Command, Response> Controller<Command, Response> for MachineController<Command, Response>
+where
+    Command: Send + 'static,
+    Response: Send + 'static,
+{
+    fn send_command(&self, cmd: Command) -> Result<(), &'static str> {
+        MachineController::send_command(self, cmd)
+    }
+
+    fn check_responses(&self) -> Vec<Response> {
+        MachineController::check_responses(self)
+    }
+
+    fn request(&self, cmd: Command) -> Result<Response, &'static str> {
+        MachineController::request(self, cmd)
+    }
+
+    fn request_priority(&self, cmd: Command) -> Result<Response, &'static str> {
+        MachineController::request_priority(self, cmd)
+    }
+
+    fn active_states(&self) -> Result<Vec<StateName>, &'static str> {
+        MachineController::active_states(self)
+    }
+
+    fn allowed_commands(&self) -> Result<Vec<Command::Kind>, &'static str>
+    where
+        Command: KindedCommand,
+    {
+        MachineController::allowed_commands(self)
+    }
+}
+
+/// Internal events one command may raise before its machine is taken for stuck in a loop
+const MAX_EVENTS_PER_COMMAND: usize = 1000;
+
+/// What a controller hands to its machine thread
+enum Envelope<Command> {
+    Command(u64, Command),
+    /// Wakes the thread up to look at the priority lane
+    Priority,
+    States(mpsc::Sender<Vec<StateName>>),
+    AllowedCommands(mpsc::Sender<Vec<&'static str>>),
+    Deferred(mpsc::Sender<Vec<u64>>),
+}
+
+/// A machine and the commands it holds back, run the way its controller promises.
+///
+/// The internal events a command raises are handled before anything else. A deferred command
+/// is acknowledged right away and held back. After every command that isn't deferred itself,
+/// the held commands are handed over again in arrival order and answered once the machine no
+/// longer defers them. Every response is reported with the `Tag` of the command that caused
+/// it.
+struct Dispatcher<Wrapper, Command, Tag> {
+    /// `None` once the machine was halted or lost in a panic
+    machine: Option<Wrapper>,
+    deferred: VecDeque<(Tag, Command)>,
+    /// Why the machine was halted, if it was
+    fault: Option<&'static str>,
+}
+
+impl<Wrapper, Command, Tag> Dispatcher<Wrapper, Command, Tag>
+where
+    Command: Clone,
+    Tag: Copy,
+{
+    fn new(machine: Wrapper) -> Self {
+        Self {
+            machine: Some(machine),
+            deferred: VecDeque::new(),
+            fault: None,
+        }
+    }
+
+    fn machine(&self) -> Result<&Wrapper, &'static str> {
+        self.machine.as_ref().ok_or_else(|| self.missing())
+    }
+
+    fn missing(&self) -> &'static str {
+        self.fault.unwrap_or("Machine lost in a panic")
+    }
+
+    /// Hands a command and the held commands it frees to the machine.
+    fn dispatch<Response>(
+        &mut self,
+        tag: Tag,
+        cmd: Command,
+        respond: &mut impl FnMut(Tag, Response),
+    ) -> Result<(), &'static str>
+    where
+        Wrapper: StateHandler<Command, Response, Wrapper>,
+    {
+        if !self.complete(tag, cmd, false, respond)? {
+            return Ok(());
+        }
+        for (tag, cmd) in std::mem::take(&mut self.deferred) {
+            self.complete(tag, cmd, true, respond)?;
+        }
         Ok(())
     }
+
+    /// Handles a command and the internal events it raises.
+    ///
+    /// The machine is halted once the command raised more than `MAX_EVENTS_PER_COMMAND` events.
+    ///
+    /// # Returns
+    /// `false` if the command was deferred
+    fn complete<Response>(
+        &mut self,
+        tag: Tag,
+        cmd: Command,
+        redispatched: bool,
+        respond: &mut impl FnMut(Tag, Response),
+    ) -> Result<bool, &'static str>
+    where
+        Wrapper: StateHandler<Command, Response, Wrapper>,
+    {
+        let response = self.apply(cmd)?;
+        if let Some(cmd) = Wrapper::deferred(&response).cloned() {
+            self.deferred.push_back((tag, cmd));
+            if !redispatched {
+                respond(tag, response);
+            }
+            return Ok(false);
+        }
+        respond(tag, response);
+
+        let mut events = VecDeque::from(self.take_events());
+        let mut handled = 0;
+        while let Some(event) = events.pop_front() {
+            handled += 1;
+            if handled > MAX_EVENTS_PER_COMMAND {
+                return Err(self.halt("Machine raised too many internal events"));
+            }
+            let response = self.apply(event)?;
+            if let Some(event) = Wrapper::deferred(&response).cloned() {
+                self.deferred.push_back((tag, event));
+            }
+            respond(tag, response);
+            events.extend(self.take_events());
+        }
+        Ok(true)
+    }
+
+    fn halt(&mut self, fault: &'static str) -> &'static str {
+        self.machine = None;
+        self.deferred.clear();
+        self.fault = Some(fault);
+        fault
+    }
+
+    fn take_events<Response>(&mut self) -> Vec<Command>
+    where
+        Wrapper: StateHandler<Command, Response, Wrapper>,
+    {
+        self.machine
+            .as_mut()
+            .map(StateHandler::take_events)
+            .unwrap_or_default()
+    }
+
+    fn apply<Response>(&mut self, cmd: Command) -> Result<Response, &'static str>
+    where
+        Wrapper: StateHandler<Command, Response, Wrapper>,
+    {
+        let machine = self.machine.take().ok_or_else(|| self.missing())?;
+        let (new_actor, response) = machine.handle_cmd(cmd);
+        self.machine = Some(new_actor);
+        Ok(response)
+    }
 }
 
 /// Thread for running the FSM.
@@ -333,42 +2259,1584 @@ where
 /// * `Response` - The type of responses that can be returned by the FSM
 /// * `FsmWrapper` - The type of FSM wrapper
 struct MachineThread<Command, Response, FsmWrapper> {
-    cmd_rx: mpsc::Receiver<Command>,
-    response_tx: mpsc::Sender<Response>,
-    fsm_wrapper: FsmWrapper,
+    cmd_rx: mpsc::Receiver<Envelope<Command>>,
+    priority: Arc<Mutex<VecDeque<(u64, Command)>>>,
+    response_tx: mpsc::Sender<(u64, Response)>,
+    fault: Arc<OnceLock<&'static str>>,
+    /// Answers under the sequence number of each command
+    dispatcher: Dispatcher<FsmWrapper, Command, u64>,
+    /// Dropped once the controller is gone, for controllers on an executor
+    finished: Option<mpsc::Sender<()>>,
 }
 
 impl<Command, Response, FsmWrapper> MachineThread<Command, Response, FsmWrapper>
 where
+    Command: Clone,
     FsmWrapper: StateHandler<Command, Response, FsmWrapper>,
 {
     /// Creates a new FSM thread.
     ///
     /// # Arguments
     /// * `cmd_rx` - The receiver for commands
+    /// * `priority` - Commands handled before the ones waiting in `cmd_rx`
     /// * `response_tx` - The sender for responses
+    /// * `fault` - Where the thread tells the controller why it halted the machine
     /// * `fsm_wrapper` - The FSM wrapper
     ///
     /// # Returns
     /// A new FSM thread instance
     fn new(
-        cmd_rx: mpsc::Receiver<Command>,
-        response_tx: mpsc::Sender<Response>,
+        cmd_rx: mpsc::Receiver<Envelope<Command>>,
+        priority: Arc<Mutex<VecDeque<(u64, Command)>>>,
+        response_tx: mpsc::Sender<(u64, Response)>,
+        fault: Arc<OnceLock<&'static str>>,
         fsm_wrapper: FsmWrapper,
     ) -> Self {
         Self {
             cmd_rx,
+            priority,
             response_tx,
-            fsm_wrapper,
+            fault,
+            dispatcher: Dispatcher::new(fsm_wrapper),
+            finished: None,
         }
     }
 
-    /// Runs the FSM thread.
+    /// Runs the FSM thread until its controller is gone.
     fn run(mut self) {
-        while let Ok(cmd) = self.cmd_rx.recv() {
-            let (new_actor, response) = self.fsm_wrapper.handle_cmd(cmd);
-            self.fsm_wrapper = new_actor;
-            let _ = self.response_tx.send(response);
+        while let Ok(next) = self.cmd_rx.recv() {
+            self.handle(next);
+        }
+    }
+
+    /// Handles one message from the controller.
+    ///
+    /// Commands are run to completion by the [`Dispatcher`]. Priority commands are handled
+    /// before every command taken from the queue.
+    fn handle(&mut self, next: Envelope<Command>) {
+        while let Some((seq, cmd)) = self.next_priority() {
+            self.dispatch(seq, cmd);
+        }
+        match next {
+            Envelope::Command(seq, cmd) => self.dispatch(seq, cmd),
+            Envelope::Priority => {}
+            Envelope::States(states_tx) => {
+                let states = self
+                    .dispatcher
+                    .machine()
+                    .map(StateHandler::active_states)
+                    .unwrap_or_default();
+                let _ = states_tx.send(states);
+            }
+            Envelope::AllowedCommands(names_tx) => {
+                let names = self
+                    .dispatcher
+                    .machine()
+                    .map(StateHandler::allowed_command_names)
+                    .unwrap_or_default();
+                let _ = names_tx.send(names);
+            }
+            Envelope::Deferred(seqs_tx) => {
+                let seqs = self.dispatcher.deferred.iter().map(|(seq, _)| *seq);
+                let _ = seqs_tx.send(seqs.collect());
+            }
+        }
+    }
+
+    fn next_priority(&self) -> Option<(u64, Command)> {
+        self.priority.lock().ok()?.pop_front()
+    }
+
+    fn dispatch(&mut self, seq: u64, cmd: Command) {
+        let response_tx = &self.response_tx;
+        let dispatched = self.dispatcher.dispatch(seq, cmd, &mut |seq, response| {
+            let _ = response_tx.send((seq, response));
+        });
+        if let Err(fault) = dispatched {
+            self.halt(fault);
+        }
+    }
+
+    /// Stops the machine for good. Dropping its channels makes every later call on the
+    /// controller fail with `fault` and lets go of a machine thread or executor worker.
+    fn halt(&mut self, fault: &'static str) {
+        let _ = self.fault.set(fault);
+        self.cmd_rx = mpsc::channel().1;
+        self.response_tx = mpsc::channel().0;
+    }
+}
+
+impl<Command, Response, FsmWrapper> Runnable for MachineThread<Command, Response, FsmWrapper>
+where
+    Command: Clone,
+    FsmWrapper: StateHandler<Command, Response, FsmWrapper>,
+{
+    fn run_one(&mut self) -> bool {
+        match self.cmd_rx.try_recv() {
+            Ok(next) => {
+                self.handle(next);
+                true
+            }
+            Err(mpsc::TryRecvError::Empty) => false,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.finished = None;
+                false
+            }
+        }
+    }
+}
+
+/// Feeds commands to a machine one after another and collects the responses
+#[cfg(any(test, feature = "testing"))]
+pub fn drive<Command, Response, Wrapper>(
+    machine: Wrapper,
+    commands: impl IntoIterator<Item = Command>,
+) -> (Wrapper, Vec<Response>)
+where
+    Wrapper: StateHandler<Command, Response, Wrapper>,
+{
+    let mut responses = Vec::new();
+    let machine = commands.into_iter().fold(machine, |machine, cmd| {
+        let (next, response) = machine.handle_cmd(cmd);
+        responses.push(response);
+        next
+    });
+    (machine, responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    mod superstates {
+        use super::*;
+
+        #[derive(Debug)]
+        pub struct Off;
+        #[derive(Debug)]
+        pub struct Running;
+        #[derive(Debug)]
+        pub struct Cutting;
+        #[derive(Debug)]
+        pub struct Spinning;
+        #[derive(Debug)]
+        pub struct Feeding;
+        #[derive(Debug)]
+        pub struct Boring;
+        #[derive(Debug)]
+        pub struct Halted;
+
+        #[derive(Default, Debug)]
+        pub struct LogData {
+            log: Vec<&'static str>,
+            feed: u32,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum LogCommand {
+            Start,
+            Feed(u32),
+            Bore,
+            Retract,
+            Notaus,
+            Acknowledge,
+            Resume,
+            Restart,
+            SetFeed(u32),
+            Respin,
+            Mark,
+        }
+
+        command_kind! {
+            LogCommand => LogCommandKind {
+                Start, Feed, Bore, Retract, Notaus, Acknowledge, Resume, Restart, SetFeed, Respin, Mark
+            }
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum LogResponse {
+            Status {
+                state: StateName,
+            },
+            Updated {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: LogCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Off,
+            MachineData: LogData,
+            MachineCommand: LogCommand,
+            CommandKind: LogCommandKind,
+            MachineResponse: LogResponse,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Off: {
+                Start => start(self) -> Spinning,
+            },
+            superstate Running: [entry(data) { data.log.push("enter Running"); }, exit(data) { data.log.push("exit Running"); }] {
+                Notaus => notaus(self) -> Halted,
+                Mark => mark(self) [internal] { self.data.log.push("mark"); },
+            },
+            superstate Cutting in Running: [exit(data) { data.log.push("exit Cutting"); }] {
+                Retract => retract(self) -> Spinning,
+            },
+            Spinning in Running: [entry(data) { data.log.push("enter Spinning"); }] {
+                Feed(feed: u32) => feed(self) -> Feeding { self.data.feed = feed; },
+                Bore => bore(self) -> Boring,
+                SetFeed(feed: u32) => set_feed(self) [internal] { self.data.feed = feed; },
+                Respin => respin(self) -> Spinning,
+            },
+            Feeding in Cutting: [entry(data) { data.log.push("enter Feeding"); }, exit(data) { data.log.push("exit Feeding"); }] {
+            },
+            Boring in Cutting: {
+            },
+            Halted: {
+                Acknowledge => acknowledge(self) -> Off,
+                Resume => resume(self) -> Running[H*],
+                Restart => restart(self) -> Running[H],
+            },
+        }
+
+        fn log(machine: FsmWrapper) -> Vec<&'static str> {
+            ActiveState::into_data(machine).log
+        }
+
+        #[test]
+        fn command_bubbles_to_superstate() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Notaus],
+            );
+
+            assert_eq!(responses[1], LogResponse::Status { state: "Halted" });
+            assert_eq!(machine.state_name(), "Halted");
+        }
+
+        #[test]
+        fn command_bubbles_through_nested_superstates() {
+            let (machine, _) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Feed(10), LogCommand::Notaus],
+            );
+
+            assert_eq!(
+                log(machine),
+                vec![
+                    "enter Running",
+                    "enter Spinning",
+                    "enter Feeding",
+                    "exit Feeding",
+                    "exit Cutting",
+                    "exit Running",
+                ]
+            );
+        }
+
+        #[test]
+        fn transition_within_superstate_keeps_it_active() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Feed(10), LogCommand::Retract],
+            );
+
+            assert_eq!(responses[2], LogResponse::Status { state: "Spinning" });
+            assert_eq!(
+                log(machine),
+                vec![
+                    "enter Running",
+                    "enter Spinning",
+                    "enter Feeding",
+                    "exit Feeding",
+                    "exit Cutting",
+                    "enter Spinning",
+                ]
+            );
+        }
+
+        #[test]
+        fn unhandled_command_is_rejected() {
+            let (machine, responses) =
+                drive(FsmWrapper::new(Box::default()), vec![LogCommand::Notaus]);
+
+            assert_eq!(
+                responses[0],
+                LogResponse::InvalidTransition {
+                    current_state: "Off",
+                    attempted_command: LogCommand::Notaus,
+                }
+            );
+            assert!(log(machine).is_empty());
+        }
+
+        #[test]
+        fn superstates_are_not_states() {
+            assert_eq!(
+                FsmWrapper::STATES,
+                &["Off", "Spinning", "Feeding", "Boring", "Halted"]
+            );
+        }
+
+        #[test]
+        fn allowed_commands_include_superstates() {
+            let (machine, _) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Feed(10)],
+            );
+
+            assert_eq!(
+                machine.allowed_commands(),
+                &[
+                    LogCommandKind::Retract,
+                    LogCommandKind::Notaus,
+                    LogCommandKind::Mark
+                ]
+            );
+        }
+
+        #[test]
+        fn internal_transition_keeps_state() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::SetFeed(20)],
+            );
+
+            assert_eq!(responses[1], LogResponse::Updated { state: "Spinning" });
+            let data = ActiveState::into_data(machine);
+            assert_eq!(data.feed, 20);
+            assert_eq!(data.log, vec!["enter Running", "enter Spinning"]);
+        }
+
+        #[test]
+        fn external_self_transition_reenters_state() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Respin],
+            );
+
+            assert_eq!(responses[1], LogResponse::Status { state: "Spinning" });
+            assert_eq!(
+                log(machine),
+                vec!["enter Running", "enter Spinning", "enter Spinning"]
+            );
+        }
+
+        #[test]
+        fn internal_transition_of_superstate_keeps_substate() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Feed(10), LogCommand::Mark],
+            );
+
+            assert_eq!(responses[2], LogResponse::Updated { state: "Feeding" });
+            assert_eq!(
+                log(machine),
+                vec!["enter Running", "enter Spinning", "enter Feeding", "mark"]
+            );
+        }
+
+        #[test]
+        fn internal_transition_in_table() {
+            let set_feed = FsmWrapper::TRANSITIONS
+                .iter()
+                .find(|t| t.command == LogCommandKind::SetFeed)
+                .unwrap();
+
+            assert_eq!((set_feed.from, set_feed.to), ("Spinning", "Spinning"));
+            assert!(set_feed.internal);
+        }
+
+        #[test]
+        fn self_transition_is_not_internal() {
+            let respin = FsmWrapper::TRANSITIONS
+                .iter()
+                .find(|t| t.command == LogCommandKind::Respin)
+                .unwrap();
+
+            assert_eq!((respin.from, respin.to), ("Spinning", "Spinning"));
+            assert!(!respin.internal);
+        }
+
+        #[test]
+        fn history_transition_in_table() {
+            let resume = FsmWrapper::TRANSITIONS
+                .iter()
+                .find(|t| t.command == LogCommandKind::Resume)
+                .unwrap();
+            let restart = FsmWrapper::TRANSITIONS
+                .iter()
+                .find(|t| t.command == LogCommandKind::Restart)
+                .unwrap();
+
+            assert_eq!(resume.to, "Running");
+            assert_eq!(resume.history, Some(HistoryKind::Deep));
+            assert_eq!(restart.history, Some(HistoryKind::Shallow));
+            assert!(!FsmWrapper::STATES.contains(&resume.to));
+        }
+
+        #[test]
+        fn state_diagram_marks_history_transitions() {
+            let diagram = state_diagram("Off", FsmWrapper::TRANSITIONS);
+
+            assert!(diagram.contains("    Halted --> Running : Resume [H*]\n"));
+            assert!(diagram.contains("    Halted --> Running : Restart [H]\n"));
+        }
+
+        #[test]
+        fn state_diagram_marks_internal_transitions() {
+            let diagram = state_diagram("Off", FsmWrapper::TRANSITIONS);
+
+            assert!(diagram.contains("    Spinning --> Spinning : SetFeed [internal]\n"));
+            assert!(diagram.contains("    Spinning --> Spinning : Respin\n"));
+            assert!(diagram.contains("    Running --> Running : Mark [internal]\n"));
+        }
+
+        #[test]
+        fn deep_history_resumes_innermost_state() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    LogCommand::Start,
+                    LogCommand::Bore,
+                    LogCommand::Notaus,
+                    LogCommand::Resume,
+                ],
+            );
+
+            assert_eq!(responses[3], LogResponse::Status { state: "Boring" });
+            assert_eq!(machine.state_name(), "Boring");
+        }
+
+        #[test]
+        fn shallow_history_resumes_direct_substate() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    LogCommand::Start,
+                    LogCommand::Bore,
+                    LogCommand::Notaus,
+                    LogCommand::Restart,
+                ],
+            );
+
+            assert_eq!(responses[3], LogResponse::Status { state: "Feeding" });
+            assert_eq!(machine.state_name(), "Feeding");
+        }
+
+        #[test]
+        fn history_runs_entry_actions() {
+            let (machine, _) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    LogCommand::Start,
+                    LogCommand::Feed(10),
+                    LogCommand::Notaus,
+                    LogCommand::Resume,
+                ],
+            );
+
+            assert_eq!(
+                log(machine),
+                vec![
+                    "enter Running",
+                    "enter Spinning",
+                    "enter Feeding",
+                    "exit Feeding",
+                    "exit Cutting",
+                    "exit Running",
+                    "enter Running",
+                    "enter Feeding",
+                ]
+            );
+        }
+
+        #[test]
+        fn history_defaults_to_first_state() {
+            let halted = FSM::<Halted, LogData> {
+                state: PhantomData,
+                data: Box::default(),
+                history: History::default(),
+            };
+
+            let Ok(machine) = halted.resume() else {
+                panic!("nothing resumed");
+            };
+
+            assert_eq!(machine.state_name(), "Spinning");
+            assert_eq!(log(machine), vec!["enter Running", "enter Spinning"]);
+        }
+
+        #[test]
+        fn history_is_kept_with_data() {
+            let (machine, _) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![LogCommand::Start, LogCommand::Bore, LogCommand::Notaus],
+            );
+
+            let (_, history) = machine.into_parts();
+
+            assert_eq!(history.shallow("Running"), Some("Cutting"));
+            assert_eq!(history.deep("Running"), Some("Boring"));
+            assert_eq!(history.deep("Cutting"), Some("Boring"));
+        }
+
+        #[test]
+        fn parent_runs_exit_action() {
+            let machine = FSM::<Off, LogData>::new(Box::default()).start().feed(10);
+
+            let cutting: FSM<Cutting, LogData> = machine.parent();
+
+            assert_eq!(cutting.data.feed, 10);
+            assert_eq!(
+                cutting.data.log,
+                vec![
+                    "enter Running",
+                    "enter Spinning",
+                    "enter Feeding",
+                    "exit Feeding"
+                ]
+            );
+        }
+    }
+
+    mod empty_history {
+        use super::*;
+
+        #[derive(Debug)]
+        pub struct Off;
+        #[derive(Debug)]
+        pub struct Idle;
+        #[derive(Debug)]
+        pub struct Program;
+        #[derive(Debug)]
+        pub struct Standby;
+
+        #[derive(Default, Debug)]
+        pub struct EmptyData {
+            log: Vec<&'static str>,
+            speed: u32,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum EmptyCommand {
+            Run(u32),
+            Continue,
+        }
+
+        command_kind! {
+            EmptyCommand => EmptyCommandKind { Run, Continue }
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum EmptyResponse {
+            Status {
+                state: StateName,
+            },
+            Updated {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: EmptyCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Off,
+            MachineData: EmptyData,
+            MachineCommand: EmptyCommand,
+            CommandKind: EmptyCommandKind,
+            MachineResponse: EmptyResponse,
+            StateHandlerTrait: StateHandler,
+            superstate Program: {},
+            superstate Standby: [exit(data) { data.log.push("exit Standby"); }] {
+                Continue => resume(self) -> Program[H],
+            },
+            Off: [exit(data) { data.log.push("exit Off"); }] {
+                Run(speed: u32) => run(self) -> Program[H*] { self.data.speed = speed; },
+            },
+            Idle in Standby: [exit(data) { data.log.push("exit Idle"); }] {},
+        }
+
+        #[test]
+        fn history_without_state_is_rejected() {
+            let machine = FsmWrapper::new(Box::default());
+
+            let (machine, response) = machine.handle_cmd(EmptyCommand::Run(5));
+
+            assert_eq!(
+                response,
+                EmptyResponse::InvalidTransition {
+                    current_state: "Off",
+                    attempted_command: EmptyCommand::Run(5),
+                }
+            );
+            assert_eq!(machine.state_name(), "Off");
+            assert!(ActiveState::into_data(machine).log.is_empty());
+        }
+
+        #[test]
+        fn superstate_history_without_state_is_rejected() {
+            let machine = FsmWrapper::Idle(FSM {
+                state: PhantomData,
+                data: Box::default(),
+                history: History::default(),
+            });
+
+            let (machine, response) = machine.handle_cmd(EmptyCommand::Continue);
+
+            assert_eq!(
+                response,
+                EmptyResponse::InvalidTransition {
+                    current_state: "Idle",
+                    attempted_command: EmptyCommand::Continue,
+                }
+            );
+            assert_eq!(machine.state_name(), "Idle");
+            assert!(ActiveState::into_data(machine).log.is_empty());
+        }
+
+        #[test]
+        fn typed_history_without_state_returns_machine() {
+            let Err(off) = FSM::<Off, EmptyData>::new(Box::default()).run(5) else {
+                panic!("resumed an empty superstate");
+            };
+
+            assert_eq!(off.data.speed, 0);
+            assert!(off.data.log.is_empty());
+        }
+    }
+
+    mod payloads {
+        use super::*;
+
+        #[derive(Debug)]
+        pub struct Off;
+        #[derive(Debug)]
+        pub struct Running;
+        #[derive(Debug)]
+        pub struct Spinning;
+
+        #[derive(Default, Debug)]
+        pub struct SpindleData {
+            rpm: u32,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum SpindleCommand {
+            StartSpinning(u32),
+            SetRevs(u32),
+            Probe,
+            Stop,
+        }
+
+        command_kind! {
+            SpindleCommand => SpindleCommandKind { StartSpinning, SetRevs, Probe, Stop }
+        }
+
+        #[derive(Debug, Default, PartialEq)]
+        pub enum Reading {
+            #[default]
+            Nothing,
+            Rpm(u32),
+            Warning(&'static str),
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum SpindleResponse {
+            Status {
+                state: StateName,
+                payload: Reading,
+            },
+            Updated {
+                state: StateName,
+                payload: Reading,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Off,
+            MachineData: SpindleData,
+            MachineCommand: SpindleCommand,
+            CommandKind: SpindleCommandKind,
+            MachineResponse: SpindleResponse,
+            Payload: Reading,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Off: {
+                StartSpinning(rpm: u32) => start_spinning(self) -> Spinning {
+                    self.data.rpm = rpm.min(3000);
+                    Reading::Rpm(self.data.rpm)
+                },
+            },
+            superstate Running: {
+                Probe => probe(self) [internal] { Reading::Rpm(self.data.rpm) },
+                Stop => stop(self) -> Off,
+            },
+            Spinning in Running: {
+                SetRevs(rpm: u32) => set_revs(self) [internal] {
+                    if rpm > 3000 {
+                        Reading::Warning("limited to 3000 rpm")
+                    } else {
+                        self.data.rpm = rpm;
+                        Reading::Rpm(rpm)
+                    }
+                },
+            },
+        }
+
+        #[test]
+        fn transition_reports_payload() {
+            let (_, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![SpindleCommand::StartSpinning(5000)],
+            );
+
+            assert_eq!(
+                responses[0],
+                SpindleResponse::Status {
+                    state: "Spinning",
+                    payload: Reading::Rpm(3000),
+                }
+            );
+        }
+
+        #[test]
+        fn internal_transition_reports_payload() {
+            let (_, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    SpindleCommand::StartSpinning(1000),
+                    SpindleCommand::SetRevs(4000),
+                    SpindleCommand::Probe,
+                ],
+            );
+
+            assert_eq!(
+                responses[1..],
+                [
+                    SpindleResponse::Updated {
+                        state: "Spinning",
+                        payload: Reading::Warning("limited to 3000 rpm"),
+                    },
+                    SpindleResponse::Updated {
+                        state: "Spinning",
+                        payload: Reading::Rpm(1000),
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn transition_without_body_reports_default() {
+            let (_, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![SpindleCommand::StartSpinning(1000), SpindleCommand::Stop],
+            );
+
+            assert_eq!(
+                responses[1],
+                SpindleResponse::Status {
+                    state: "Off",
+                    payload: Reading::Nothing,
+                }
+            );
+        }
+
+        #[test]
+        fn typed_transition_returns_payload() {
+            let (machine, reading) =
+                FSM::<Off, SpindleData>::new(Box::default()).start_spinning(800);
+
+            assert_eq!(reading, Reading::Rpm(800));
+            assert_eq!(machine.data.rpm, 800);
+        }
+    }
+
+    mod policies {
+        use super::*;
+
+        #[derive(Debug)]
+        pub struct Idle;
+        #[derive(Debug)]
+        pub struct RampingUp;
+        #[derive(Debug)]
+        pub struct Running;
+        #[derive(Debug)]
+        pub struct Spinning;
+        #[derive(Debug)]
+        pub struct Cooling;
+        #[derive(Debug)]
+        pub struct Fault;
+
+        #[derive(Default, Debug)]
+        pub struct SpindleData {
+            log: Vec<&'static str>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum SpindleCommand {
+            Start,
+            Reached,
+            Feed(u32),
+            Stop,
+            Cooled,
+            Reset,
+        }
+
+        command_kind! {
+            SpindleCommand => SpindleCommandKind { Start, Reached, Feed, Stop, Cooled, Reset }
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum SpindleResponse {
+            Status {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+            Ignored {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+            Deferred {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+            Escalated {
+                state: StateName,
+                attempted_command: SpindleCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Idle,
+            MachineData: SpindleData,
+            MachineCommand: SpindleCommand,
+            CommandKind: SpindleCommandKind,
+            MachineResponse: SpindleResponse,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Idle: {
+                Start => start(self) -> RampingUp,
+            },
+            RampingUp: [unhandled(defer)] {
+                Reached => reached(self) -> Spinning,
+            },
+            superstate Running: [exit(data) { data.log.push("exit Running"); }] {
+                Stop => stop(self) -> Cooling,
+            },
+            Spinning in Running: [unhandled(escalate(Fault))] {
+                Feed(_feed: u32) => feed(self) -> Spinning,
+            },
+            Cooling: [entry(data) { data.log.push("enter Cooling"); }, unhandled(ignore)] {
+                Cooled => cooled(self) -> Idle,
+            },
+            Fault: [entry(data) { data.log.push("enter Fault"); }] {
+                Reset => reset(self) -> Idle,
+            },
+        }
+
+        #[test]
+        fn reject_by_default() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![SpindleCommand::Feed(10)],
+            );
+
+            assert_eq!(
+                responses[0],
+                SpindleResponse::InvalidTransition {
+                    current_state: "Idle",
+                    attempted_command: SpindleCommand::Feed(10),
+                }
+            );
+            assert_eq!(machine.state_name(), "Idle");
+        }
+
+        #[test]
+        fn ignore_keeps_state() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    SpindleCommand::Start,
+                    SpindleCommand::Reached,
+                    SpindleCommand::Stop,
+                    SpindleCommand::Feed(10),
+                ],
+            );
+
+            assert_eq!(
+                responses[3],
+                SpindleResponse::Ignored {
+                    current_state: "Cooling",
+                    attempted_command: SpindleCommand::Feed(10),
+                }
+            );
+            assert_eq!(machine.state_name(), "Cooling");
+        }
+
+        #[test]
+        fn defer_hands_command_back() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![SpindleCommand::Start, SpindleCommand::Feed(10)],
+            );
+
+            assert_eq!(
+                responses[1],
+                SpindleResponse::Deferred {
+                    current_state: "RampingUp",
+                    attempted_command: SpindleCommand::Feed(10),
+                }
+            );
+            assert_eq!(machine.state_name(), "RampingUp");
+        }
+
+        #[test]
+        fn controller_redispatches_deferred_command() {
+            let controller = FsmController::create(Box::default());
+
+            controller.request(SpindleCommand::Start).unwrap();
+            let feed = controller.request(SpindleCommand::Feed(10)).unwrap();
+            let reached = controller.request(SpindleCommand::Reached).unwrap();
+            let stop = controller.request(SpindleCommand::Stop).unwrap();
+
+            assert_eq!(
+                feed,
+                SpindleResponse::Deferred {
+                    current_state: "RampingUp",
+                    attempted_command: SpindleCommand::Feed(10),
+                }
+            );
+            assert_eq!(reached, SpindleResponse::Status { state: "Spinning" });
+            assert_eq!(stop, SpindleResponse::Status { state: "Cooling" });
+            assert_eq!(
+                controller.check_responses(),
+                vec![SpindleResponse::Status { state: "Spinning" }]
+            );
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn deferred_commands_keep_their_order() {
+            let controller = FsmController::create(Box::default());
+
+            controller.send_command(SpindleCommand::Start).unwrap();
+            controller.send_command(SpindleCommand::Feed(10)).unwrap();
+            controller.send_command(SpindleCommand::Stop).unwrap();
+            controller.send_command(SpindleCommand::Reached).unwrap();
+            let cooled = controller.request(SpindleCommand::Cooled).unwrap();
+
+            assert_eq!(cooled, SpindleResponse::Status { state: "Idle" });
+            assert_eq!(
+                controller.check_responses(),
+                vec![
+                    SpindleResponse::Status { state: "RampingUp" },
+                    SpindleResponse::Deferred {
+                        current_state: "RampingUp",
+                        attempted_command: SpindleCommand::Feed(10),
+                    },
+                    SpindleResponse::Deferred {
+                        current_state: "RampingUp",
+                        attempted_command: SpindleCommand::Stop,
+                    },
+                    SpindleResponse::Status { state: "Spinning" },
+                    SpindleResponse::Status { state: "Spinning" },
+                    SpindleResponse::Status { state: "Cooling" },
+                ]
+            );
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn escalate_enters_fault_state() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    SpindleCommand::Start,
+                    SpindleCommand::Reached,
+                    SpindleCommand::Cooled,
+                ],
+            );
+
+            assert_eq!(
+                responses[2],
+                SpindleResponse::Escalated {
+                    state: "Fault",
+                    attempted_command: SpindleCommand::Cooled,
+                }
+            );
+            assert_eq!(machine.state_name(), "Fault");
+            assert_eq!(
+                ActiveState::into_data(machine).log,
+                vec!["exit Running", "enter Fault"]
+            );
+        }
+
+        #[test]
+        fn superstate_handles_before_policy() {
+            let (machine, responses) = drive(
+                FsmWrapper::new(Box::default()),
+                vec![
+                    SpindleCommand::Start,
+                    SpindleCommand::Reached,
+                    SpindleCommand::Stop,
+                ],
+            );
+
+            assert_eq!(responses[2], SpindleResponse::Status { state: "Cooling" });
+            assert_eq!(machine.state_name(), "Cooling");
+        }
+    }
+
+    mod events {
+        use super::*;
+
+        #[derive(Debug)]
+        pub struct Off;
+        #[derive(Debug)]
+        pub struct RampingUp;
+        #[derive(Debug)]
+        pub struct Spinning;
+
+        #[derive(Default, Debug)]
+        pub struct SpindleData {
+            rpm: u32,
+            events: Outbox<SpindleCommand>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum SpindleCommand {
+            StartSpinning(u32),
+            AtSpeed,
+            Ready,
+            Stop,
+        }
+
+        command_kind! {
+            SpindleCommand => SpindleCommandKind { StartSpinning, AtSpeed, Ready, Stop }
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum SpindleResponse {
+            Status {
+                state: StateName,
+            },
+            Updated {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Off,
+            MachineData: SpindleData,
+            MachineCommand: SpindleCommand,
+            CommandKind: SpindleCommandKind,
+            MachineResponse: SpindleResponse,
+            InternalEvents: events,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Off: {
+                StartSpinning(rpm: u32) => start_spinning(self) -> RampingUp { self.data.rpm = rpm; },
+            },
+            RampingUp: [entry(data) { data.events.post(SpindleCommand::AtSpeed); }] {
+                AtSpeed => at_speed(self) -> Spinning { self.data.events.post(SpindleCommand::Ready); },
+            },
+            Spinning: {
+                Ready => ready(self) [internal],
+                Stop => stop(self) -> Off,
+            },
+        }
+
+        #[test]
+        fn events_run_before_next_command() {
+            let controller = FsmController::create(Box::default());
+
+            controller
+                .send_command(SpindleCommand::StartSpinning(1000))
+                .unwrap();
+            let stop = controller.request(SpindleCommand::Stop).unwrap();
+
+            assert_eq!(stop, SpindleResponse::Status { state: "Off" });
+            assert_eq!(
+                controller.check_responses(),
+                vec![
+                    SpindleResponse::Status { state: "RampingUp" },
+                    SpindleResponse::Status { state: "Spinning" },
+                    SpindleResponse::Updated { state: "Spinning" },
+                ]
+            );
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn request_answers_the_command_itself() {
+            let controller = FsmController::create(Box::default());
+
+            let start = controller
+                .request(SpindleCommand::StartSpinning(1000))
+                .unwrap();
+            let stop = controller.request(SpindleCommand::Stop).unwrap();
+
+            assert_eq!(start, SpindleResponse::Status { state: "RampingUp" });
+            assert_eq!(stop, SpindleResponse::Status { state: "Off" });
+            assert_eq!(controller.check_responses().len(), 2);
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn wrapper_hands_out_posted_events_once() {
+            let mut machine = FsmWrapper::new(Box::default());
+
+            let (next, _) = machine.handle_cmd(SpindleCommand::StartSpinning(1000));
+            machine = next;
+
+            assert_eq!(machine.take_events(), vec![SpindleCommand::AtSpeed]);
+            assert_eq!(machine.take_events(), vec![]);
+        }
+    }
+
+    mod runaway {
+        use super::*;
+
+        #[derive(Debug)]
+        pub struct Echoing;
+        #[derive(Debug)]
+        pub struct Silent;
+
+        #[derive(Default, Debug)]
+        pub struct EchoData {
+            events: Outbox<EchoCommand>,
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum EchoCommand {
+            Ping,
+            Mute,
+        }
+
+        command_kind! {
+            EchoCommand => EchoCommandKind { Ping, Mute }
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum EchoResponse {
+            Status {
+                state: StateName,
+            },
+            Updated {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: EchoCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Echoing,
+            MachineData: EchoData,
+            MachineCommand: EchoCommand,
+            CommandKind: EchoCommandKind,
+            MachineResponse: EchoResponse,
+            InternalEvents: events,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Echoing: {
+                Ping => ping(self) [internal] { self.data.events.post(EchoCommand::Ping); },
+            },
+            Silent: {
+                Mute => mute(self) -> Silent,
+            },
+        }
+
+        #[test]
+        fn endless_events_halt_the_machine() {
+            let controller = FsmController::create(Box::default());
+
+            let first = controller.request(EchoCommand::Ping);
+            let second = controller.request(EchoCommand::Ping);
+
+            assert_eq!(first, Ok(EchoResponse::Updated { state: "Echoing" }));
+            assert_eq!(second, Err("Machine raised too many internal events"));
+            assert_eq!(controller.check_responses().len(), MAX_EVENTS_PER_COMMAND);
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn endless_events_free_the_worker() {
+            let executor = Executor::new(1);
+            let echo = FsmController::create_on(&executor, Box::default());
+            let lathe =
+                crate::machines::lathe::LatheController::create_on(&executor, Box::default());
+
+            echo.send_command(EchoCommand::Ping).unwrap();
+
+            assert_eq!(lathe.active_states().unwrap(), ["Off"]);
+            assert_eq!(
+                echo.active_states(),
+                Err("Machine raised too many internal events")
+            );
+            echo.shutdown().unwrap();
+            lathe.shutdown().unwrap();
+            executor.shutdown().unwrap();
+        }
+    }
+
+    mod priority {
+        use super::*;
+
+        pub struct Gated {
+            entered: mpsc::Sender<()>,
+            gate: mpsc::Receiver<()>,
+        }
+
+        impl From<(mpsc::Sender<()>, mpsc::Receiver<()>)> for Gated {
+            fn from((entered, gate): (mpsc::Sender<()>, mpsc::Receiver<()>)) -> Self {
+                Self { entered, gate }
+            }
+        }
+
+        impl StateHandler<u32, u32, Gated> for Gated {
+            fn handle_cmd(self, cmd: u32) -> (Gated, u32) {
+                if cmd == 0 {
+                    self.entered.send(()).unwrap();
+                    self.gate.recv().unwrap();
+                }
+                (self, cmd)
+            }
+        }
+
+        #[test]
+        fn priority_command_overtakes_queued_commands() {
+            let (entered_tx, entered_rx) = mpsc::channel();
+            let (gate_tx, gate_rx) = mpsc::channel();
+            let controller = MachineController::<u32, u32>::new::<_, Gated>((entered_tx, gate_rx));
+
+            controller.send_command(0).unwrap();
+            entered_rx.recv().unwrap();
+            controller.send_command(1).unwrap();
+            controller.send_command(2).unwrap();
+            controller.send_priority(9).unwrap();
+            gate_tx.send(()).unwrap();
+
+            assert_eq!(controller.request(3).unwrap(), 3);
+            assert_eq!(controller.check_responses(), [0, 9, 1, 2]);
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn priority_command_is_answered() {
+            let (entered_tx, _entered_rx) = mpsc::channel();
+            let (_gate_tx, gate_rx) = mpsc::channel();
+            let controller = MachineController::<u32, u32>::new::<_, Gated>((entered_tx, gate_rx));
+
+            controller.send_command(8).unwrap();
+
+            assert_eq!(controller.request_priority(7).unwrap(), 7);
+            assert_eq!(controller.request(9).unwrap(), 9);
+            assert_eq!(controller.check_responses(), [8]);
+        }
+    }
+
+    mod composition {
+        use super::priority::Gated;
+        use super::*;
+        use super::{events, policies, runaway};
+        use crate::machines::lathe::{LatheCommand, LatheResponse, LatheWrapper};
+        use crate::machines::mill::{FsmWrapper as MillWrapper, MillCommand, MillResponse};
+
+        #[derive(Debug)]
+        pub struct Idle;
+        #[derive(Debug)]
+        pub struct Halted;
+
+        #[derive(Debug)]
+        pub struct LineData {
+            lathe: Child<LatheWrapper, LatheCommand, LineCommand>,
+            mill: Child<MillWrapper, MillCommand, LineCommand>,
+            events: Outbox<LineCommand>,
+        }
+
+        impl Default for LineData {
+            fn default() -> Self {
+                Self {
+                    lathe: Child::new(LatheWrapper::from(Box::default()), |states| {
+                        (states == ["Notaus"]).then_some(LineCommand::Fault)
+                    }),
+                    mill: Child::new(MillWrapper::new(Box::default()), |_| None),
+                    events: Outbox::default(),
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum LineCommand {
+            Lathe(LatheCommand),
+            Mill(MillCommand),
+            Fault,
+            Reset,
+        }
+
+        command_kind! {
+            LineCommand => LineCommandKind { Lathe, Mill, Fault, Reset }
+        }
+
+        #[derive(Debug, Default, PartialEq)]
+        pub enum Forwarded {
+            #[default]
+            Nothing,
+            Lathe(Result<Vec<LatheResponse>, &'static str>),
+            Mill(Result<Vec<MillResponse>, &'static str>),
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum LineResponse {
+            Status {
+                state: StateName,
+                payload: Forwarded,
+            },
+            Updated {
+                state: StateName,
+                payload: Forwarded,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: LineCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Idle,
+            MachineData: LineData,
+            MachineCommand: LineCommand,
+            CommandKind: LineCommandKind,
+            MachineResponse: LineResponse,
+            Payload: Forwarded,
+            InternalEvents: events,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Idle: {
+                Lathe(cmd: LatheCommand) => lathe(self) [internal] {
+                    let data = &mut *self.data;
+                    Forwarded::Lathe(data.lathe.forward(cmd, &mut data.events))
+                },
+                Mill(cmd: MillCommand) => mill(self) [internal] {
+                    let data = &mut *self.data;
+                    Forwarded::Mill(data.mill.forward(cmd, &mut data.events))
+                },
+                Fault => halt(self) -> Halted {
+                    let data = &mut *self.data;
+                    let _ = data.mill.forward::<MillResponse>(MillCommand::StopMoving, &mut data.events);
+                    Forwarded::Mill(data.mill.forward(MillCommand::StopSpinning, &mut data.events))
+                },
+            },
+            Halted: {
+                Reset => reset(self) -> Idle {
+                    let data = &mut *self.data;
+                    Forwarded::Lathe(data.lathe.forward(LatheCommand::Acknowledge, &mut data.events))
+                },
+            },
+        }
+
+        fn drive(commands: Vec<LineCommand>) -> (FsmWrapper, Vec<LineResponse>) {
+            let mut machine = FsmWrapper::new(Box::default());
+            let mut responses = Vec::new();
+            let mut pending = VecDeque::from(commands);
+            while let Some(cmd) = pending.pop_front() {
+                let (mut next, response) = machine.handle_cmd(cmd);
+                for event in next.take_events().into_iter().rev() {
+                    pending.push_front(event);
+                }
+                machine = next;
+                responses.push(response);
+            }
+            (machine, responses)
+        }
+
+        #[test]
+        fn commands_are_forwarded_to_children() {
+            let (machine, responses) = drive(vec![
+                LineCommand::Lathe(LatheCommand::StartSpinning(1000)),
+                LineCommand::Mill(MillCommand::Move(5)),
+            ]);
+
+            assert_eq!(
+                responses,
+                [
+                    LineResponse::Updated {
+                        state: "Idle",
+                        payload: Forwarded::Lathe(Ok(vec![LatheResponse::Status {
+                            state: "Spinning"
+                        }])),
+                    },
+                    LineResponse::Updated {
+                        state: "Idle",
+                        payload: Forwarded::Mill(Ok(vec![MillResponse::InvalidTransition {
+                            current_state: "Off",
+                            attempted_command: MillCommand::Move(5),
+                        }])),
+                    },
+                ]
+            );
+            let (data, _) = machine.into_parts();
+            assert_eq!(data.lathe.machine().unwrap().state_name(), "Spinning");
+        }
+
+        #[test]
+        fn child_state_change_surfaces_as_event() {
+            let (machine, responses) = drive(vec![
+                LineCommand::Mill(MillCommand::StartSpinning(800)),
+                LineCommand::Mill(MillCommand::Move(5)),
+                LineCommand::Lathe(LatheCommand::Notaus),
+            ]);
+
+            assert_eq!(
+                responses[3],
+                LineResponse::Status {
+                    state: "Halted",
+                    payload: Forwarded::Mill(Ok(vec![MillResponse::Status { state: "Off" }])),
+                }
+            );
+            assert_eq!(machine.state_name(), "Halted");
+            assert_eq!(machine.allowed_commands(), &[LineCommandKind::Reset]);
+        }
+
+        #[test]
+        fn unchanged_child_state_raises_no_event() {
+            let (_, responses) = drive(vec![
+                LineCommand::Lathe(LatheCommand::Notaus),
+                LineCommand::Reset,
+                LineCommand::Lathe(LatheCommand::Feed(10)),
+            ]);
+
+            assert_eq!(responses.len(), 4);
+            assert_eq!(
+                responses[2],
+                LineResponse::Status {
+                    state: "Idle",
+                    payload: Forwarded::Lathe(Ok(vec![LatheResponse::Status { state: "Off" }])),
+                }
+            );
+        }
+
+        #[test]
+        fn controller_runs_child_events_to_completion() {
+            let controller = FsmController::create(Box::default());
+
+            controller
+                .request(LineCommand::Lathe(LatheCommand::Notaus))
+                .unwrap();
+
+            assert_eq!(controller.active_states().unwrap(), ["Halted"]);
+            assert_eq!(
+                controller.check_responses(),
+                [LineResponse::Status {
+                    state: "Halted",
+                    payload: Forwarded::Mill(Ok(vec![MillResponse::InvalidTransition {
+                        current_state: "Off",
+                        attempted_command: MillCommand::StopSpinning,
+                    }])),
+                }]
+            );
+            controller.shutdown().unwrap();
+        }
+
+        #[test]
+        fn child_events_are_answered() {
+            let mut child: Child<_, _, LineCommand> =
+                Child::new(events::FsmWrapper::new(Box::default()), |_| None);
+
+            let responses = child.forward(
+                events::SpindleCommand::StartSpinning(1000),
+                &mut Outbox::default(),
+            );
+
+            assert_eq!(
+                responses,
+                Ok(vec![
+                    events::SpindleResponse::Status { state: "RampingUp" },
+                    events::SpindleResponse::Status { state: "Spinning" },
+                    events::SpindleResponse::Updated { state: "Spinning" },
+                ])
+            );
+        }
+
+        #[test]
+        fn deferred_child_command_is_handed_over_again() {
+            let mut child: Child<_, _, LineCommand> =
+                Child::new(policies::FsmWrapper::new(Box::default()), |_| None);
+            let mut events = Outbox::default();
+            child
+                .forward::<policies::SpindleResponse>(policies::SpindleCommand::Start, &mut events)
+                .unwrap();
+
+            let feed = child.forward(policies::SpindleCommand::Feed(10), &mut events);
+            let reached = child.forward(policies::SpindleCommand::Reached, &mut events);
+
+            assert_eq!(
+                feed,
+                Ok(vec![policies::SpindleResponse::Deferred {
+                    current_state: "RampingUp",
+                    attempted_command: policies::SpindleCommand::Feed(10),
+                }])
+            );
+            assert_eq!(
+                reached,
+                Ok(vec![
+                    policies::SpindleResponse::Status { state: "Spinning" },
+                    policies::SpindleResponse::Status { state: "Spinning" },
+                ])
+            );
+        }
+
+        #[test]
+        fn child_stuck_in_an_event_loop_is_halted() {
+            let mut child: Child<_, _, LineCommand> =
+                Child::new(runaway::FsmWrapper::new(Box::default()), |_| None);
+
+            let responses = child.forward::<runaway::EchoResponse>(
+                runaway::EchoCommand::Ping,
+                &mut Outbox::default(),
+            );
+
+            assert_eq!(responses, Err("Machine raised too many internal events"));
+            assert!(child.machine().is_err());
+        }
+
+        #[test]
+        fn child_lost_in_a_panic_reports_an_error() {
+            let (entered_tx, _) = mpsc::channel();
+            let (_gate_tx, gate_rx) = mpsc::channel();
+            let mut child: Child<_, _, LineCommand> =
+                Child::new(Gated::from((entered_tx, gate_rx)), |_| None);
+            let mut events = Outbox::default();
+
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                child.forward::<u32>(0, &mut events)
+            }));
+
+            assert!(panicked.is_err());
+            assert_eq!(
+                child.forward::<u32>(1, &mut events),
+                Err("Machine lost in a panic")
+            );
         }
     }
 }
@@ -0,0 +1,160 @@
+//! Compile-time guarantees of the typestate machines
+//!
+//! A machine in the wrong state has no method for a command it must not accept, and its data
+//! can only be reached through transitions. The examples below are compiled by `cargo test`,
+//! so a refactoring of `Lathe` or of the `fsm!` macro that weakens a guarantee fails the test
+//! suite. Stable rustdoc doesn't check the error codes, so every rejected example only differs
+//! from an accepted one in the call it guards against.
+//!
+//! # Hand-written lathe
+//! Transitions follow the state diagram:
+//! ```
+//! use fsm::machines::lathe::{Lathe, Off};
+//!
+//! let lathe = Lathe::<Off>::new(Box::default())
+//!     .start_spinning(1000)
+//!     .feed(200)
+//!     .stop_feed()
+//!     .notaus()
+//!     .acknowledge();
+//! lathe.print();
+//! ```
+//! A lathe that is off cannot feed:
+//! ```compile_fail,E0599
+//! use fsm::machines::lathe::{Lathe, Off};
+//!
+//! Lathe::<Off>::new(Box::default()).feed(200);
+//! ```
+//! Only an emergency stop can be acknowledged:
+//! ```compile_fail,E0599
+//! use fsm::machines::lathe::{Lathe, Off};
+//!
+//! Lathe::<Off>::new(Box::default()).start_spinning(1000).acknowledge();
+//! ```
+//! A transition leaves its state for good:
+//! ```compile_fail,E0382
+//! use fsm::machines::lathe::{Lathe, Off};
+//!
+//! let off = Lathe::<Off>::new(Box::default());
+//! let _spinning = off.start_spinning(1000);
+//! off.start_spinning(500);
+//! ```
+//! The state of a transition's result is fixed:
+//! ```compile_fail,E0308
+//! use fsm::machines::lathe::{Feeding, Lathe, Off};
+//!
+//! let _feeding: Lathe<Feeding> = Lathe::<Off>::new(Box::default()).start_spinning(1000);
+//! ```
+//!
+//! # Machines generated by `fsm!`
+//! ```
+//! use fsm::machines::lathe::LatheData;
+//! use fsm::machines::lathe_fsm::Off;
+//! use fsm::machines::shared::FSM;
+//!
+//! FSM::<Off, LatheData>::new(Box::default())
+//!     .start_spinning(1000)
+//!     .feed(200)
+//!     .stop_feed();
+//! ```
+//! ```compile_fail,E0599
+//! use fsm::machines::lathe::LatheData;
+//! use fsm::machines::lathe_fsm::Off;
+//! use fsm::machines::shared::FSM;
+//!
+//! FSM::<Off, LatheData>::new(Box::default()).feed(200);
+//! ```
+//! ```compile_fail,E0599
+//! use fsm::machines::mill::{MillData, Off};
+//! use fsm::machines::shared::FSM;
+//!
+//! FSM::<Off, MillData>::new(Box::default()).start_moving(-50);
+//! ```
+//! Superstate transitions are only reachable from the superstate's substates:
+//! ```
+//! use fsm::machines::mill::{MillData, Off};
+//! use fsm::machines::shared::FSM;
+//!
+//! FSM::<Off, MillData>::new(Box::default()).parent().notaus().acknowledge();
+//! ```
+//! ```compile_fail,E0599
+//! use fsm::machines::mill::{MillData, Off};
+//! use fsm::machines::shared::FSM;
+//!
+//! FSM::<Off, MillData>::new(Box::default())
+//!     .parent()
+//!     .notaus()
+//!     .parent()
+//!     .notaus();
+//! ```
+//!
+//! # Private data
+//! A machine cannot be put into a state without taking its transitions:
+//! ```compile_fail,E0451
+//! use fsm::machines::lathe::{Feeding, Lathe};
+//! use std::marker::PhantomData;
+//!
+//! let _feeding: Lathe<Feeding> = Lathe {
+//!     state: PhantomData,
+//!     lathe_data: Box::default(),
+//! };
+//! ```
+//! ```compile_fail,E0451
+//! use fsm::machines::mill::{MillData, Moving};
+//! use fsm::machines::shared::{FSM, History};
+//! use std::marker::PhantomData;
+//!
+//! let _moving: FSM<Moving, MillData> = FSM {
+//!     state: PhantomData,
+//!     data: Box::default(),
+//!     history: History::default(),
+//! };
+//! ```
+//! The regions of a machine only change through its commands:
+//! ```
+//! use fsm::machines::mill_regions::{FsmWrapper, MillCommand};
+//!
+//! let (mill, _) = FsmWrapper::new(Box::default()).handle_cmd(MillCommand::OpenDoor);
+//! mill.states();
+//! ```
+//! ```compile_fail,E0616
+//! use fsm::machines::mill_regions::{Door, FsmWrapper};
+//!
+//! let mut mill = FsmWrapper::new(Box::default());
+//! mill.door = Door::Open;
+//! ```
+//! ```compile_fail,E0451
+//! use fsm::machines::mill_regions::{Coolant, Door, FsmWrapper, Spindle};
+//!
+//! let _open: FsmWrapper = FsmWrapper {
+//!     data: Box::default(),
+//!     spindle: Spindle::Stopped,
+//!     coolant: Coolant::Dry,
+//!     door: Door::Open,
+//! };
+//! ```
+//! Machine data only changes through transitions:
+//! ```compile_fail,E0451
+//! use fsm::machines::lathe::LatheData;
+//!
+//! let _data = LatheData { revs: 1000, feed: 200 };
+//! ```
+//! ```compile_fail,E0616
+//! use fsm::machines::lathe::{Lathe, Off};
+//!
+//! let lathe = Lathe::<Off>::new(Box::default());
+//! let _data = lathe.lathe_data;
+//! ```
+//! ```compile_fail,E0616
+//! use fsm::machines::mill::{MillData, Off};
+//! use fsm::machines::shared::FSM;
+//!
+//! let mut mill = FSM::<Off, MillData>::new(Box::default());
+//! mill.data.revs = 1000;
+//! ```
+//! ```compile_fail,E0616
+//! use fsm::machines::mill_regions::FsmWrapper;
+//!
+//! let mill = FsmWrapper::new(Box::default());
+//! let _data = mill.data;
+//! ```
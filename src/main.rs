@@ -1,13 +1,123 @@
-use fsm::machines::lathe::{LatheCommand, LatheController, LatheData};
-use fsm::machines::mill::{FsmController, MillCommand, MillData};
+use fsm::cli::repl;
+use fsm::cli::scenario::Scenario;
+use fsm::machines::lathe::{LatheCommand, LatheController, LatheData, LatheWrapper};
+use fsm::machines::mill::{FsmController, FsmWrapper, MillCommand, MillData};
 
+use std::io;
+use std::process::ExitCode;
 use std::thread;
 use std::time::Duration;
 
-fn main() {
-    run_lathe();
+const USAGE: &str =
+    "usage: fsm [demo | repl [--machine lathe|mill] | run-scenario [--machine lathe|mill] FILE]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        None | Some("demo") => {
+            run_lathe();
+
+            run_mill();
+            ExitCode::SUCCESS
+        }
+        Some("repl") => run_repl(&args[1..]),
+        Some("run-scenario") => run_scenario(&args[1..]),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    run_mill();
+fn run_repl(args: &[String]) -> ExitCode {
+    let machine = match args {
+        [] => "lathe",
+        [flag, machine] if flag == "--machine" => machine.as_str(),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let input = io::stdin().lock();
+    let output = io::stdout();
+    let result = match machine {
+        "lathe" => repl::run("lathe", LatheWrapper::new(Box::default()), input, output),
+        "mill" => repl::run("mill", FsmWrapper::new(Box::default()), input, output),
+        _ => {
+            eprintln!("unknown machine {}", machine);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_scenario(args: &[String]) -> ExitCode {
+    let (machine, path) = match args {
+        [path] => (None, path),
+        [flag, machine, path] if flag == "--machine" => (Some(machine.clone()), path),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let scenario = match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| Scenario::parse(&text).map_err(|err| err.to_string()))
+    {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mismatches = match machine.or(scenario.machine.clone()).as_deref() {
+        Some("lathe") => {
+            let controller = LatheController::create(Box::default());
+            scenario.run(&controller)
+        }
+        Some("mill") => {
+            let controller = FsmController::create(Box::default());
+            scenario.run(&controller)
+        }
+        Some(other) => {
+            eprintln!("{}: unknown machine {}", path, other);
+            return ExitCode::FAILURE;
+        }
+        None => {
+            eprintln!(
+                "{}: no machine given, use `machine lathe` or --machine",
+                path
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for mismatch in &mismatches {
+        println!("{}:{}: {}", path, mismatch.line, mismatch.message);
+    }
+    println!(
+        "{}: {} steps, {} mismatches",
+        path,
+        scenario.len(),
+        mismatches.len()
+    );
+
+    if mismatches.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }
 
 fn run_lathe() {
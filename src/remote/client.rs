@@ -0,0 +1,431 @@
+//! Controller for machines hosted by an `fsm-server`
+//!
+//! [`RemoteController`] implements the same [`Controller`] interface as the local
+//! `MachineController`, so switching a machine between local and remote only changes the
+//! constructor call.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::protocol::{Action, Outcome, ProtocolError, Query, Reply, Request, decode, encode};
+use crate::machines::lathe::{LatheCommand, LatheResponse};
+use crate::machines::mill::{MillCommand, MillResponse};
+use crate::machines::shared::{Controller, KindedCommand, KnownStates, StateName, kinds_named};
+
+/// Type alias for a lathe hosted by an `fsm-server`
+pub type RemoteLatheController = RemoteController<LatheCommand, LatheResponse>;
+
+/// Type alias for a mill hosted by an `fsm-server`
+pub type RemoteMillController = RemoteController<MillCommand, MillResponse>;
+
+/// Stream to a server that can be closed from the sending side
+trait Connection: Write + Send {
+    fn close(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+/// Controller for one named machine on a remote server.
+///
+/// # Type Parameters
+/// * `Command` - The type of commands that can be sent to the FSM
+/// * `Response` - The type of responses that can be returned by the FSM
+pub struct RemoteController<Command, Response> {
+    machine: String,
+    next_id: AtomicU64,
+    connection: Mutex<Box<dyn Connection>>,
+    reply_rx: mpsc::Receiver<Reply<Response>>,
+    /// Responses that arrived while waiting for another reply
+    unclaimed: RefCell<Vec<Response>>,
+    /// Errors the server reported for requests nobody waited for
+    errors: RefCell<Vec<ProtocolError>>,
+    reader_handle: JoinHandle<()>,
+    command: PhantomData<fn(Command)>,
+}
+
+impl<Command, Response> RemoteController<Command, Response>
+where
+    Command: Serialize,
+    Response: DeserializeOwned + Send + 'static,
+{
+    /// Connects to the machine registered as `machine` on a TCP server.
+    pub fn connect_tcp(address: impl ToSocketAddrs, machine: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self::with_connection(reader, Box::new(stream), machine))
+    }
+
+    /// Connects to the machine registered as `machine` on a Unix socket server.
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>, machine: &str) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self::with_connection(reader, Box::new(stream), machine))
+    }
+
+    fn with_connection(
+        reader: impl BufRead + Send + 'static,
+        connection: Box<dyn Connection>,
+        machine: &str,
+    ) -> Self {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let name = machine.to_string();
+        let reader_handle = thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                // Request ids start at 1, so an unreadable line is kept as an error
+                let reply = decode::<Reply<Response>>(&line).unwrap_or_else(|err| Reply {
+                    id: 0,
+                    machine: name.clone(),
+                    outcome: Outcome::Error(ProtocolError::Malformed(err.to_string())),
+                });
+                if reply_tx.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            machine: machine.to_string(),
+            next_id: AtomicU64::new(1),
+            connection: Mutex::new(connection),
+            reply_rx,
+            unclaimed: RefCell::new(Vec::new()),
+            errors: RefCell::new(Vec::new()),
+            reader_handle,
+            command: PhantomData,
+        }
+    }
+
+    fn send(&self, action: Action<Command>) -> Result<u64, &'static str> {
+        let request = Request {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            machine: self.machine.clone(),
+            action,
+        };
+        let line = encode(&request).map_err(|_| "Failed to encode command")?;
+        let mut connection = self.connection.lock().map_err(|_| "Connection poisoned")?;
+        writeln!(connection, "{}", line)
+            .and_then(|_| connection.flush())
+            .map_err(|_| "Failed to send command")?;
+        Ok(request.id)
+    }
+
+    /// Waits for the reply to the request sent under `id`, keeping the replies to other
+    /// requests for `check_responses` and `check_errors`.
+    fn wait(&self, id: u64) -> Result<Outcome<Response>, &'static str> {
+        loop {
+            let reply = self
+                .reply_rx
+                .recv()
+                .map_err(|_| "Failed to receive response")?;
+            if reply.id == id {
+                return Ok(reply.outcome);
+            }
+            self.keep(reply.outcome);
+        }
+    }
+
+    /// Keeps every reply that already arrived.
+    fn drain(&self) {
+        for reply in self.reply_rx.try_iter() {
+            self.keep(reply.outcome);
+        }
+    }
+
+    fn keep(&self, outcome: Outcome<Response>) {
+        match outcome {
+            Outcome::Response(response) => self.unclaimed.borrow_mut().push(response),
+            Outcome::Error(err) => self.errors.borrow_mut().push(err),
+            // Queries are always waited for, so these answers have no one left to read them
+            Outcome::AllowedCommands(_) | Outcome::ActiveStates(_) => {}
+        }
+    }
+
+    /// Collects the errors that arrived since the last call: those the server reported for
+    /// commands sent without waiting and replies that could not be read.
+    pub fn check_errors(&self) -> Vec<ProtocolError> {
+        self.drain();
+        self.errors.take()
+    }
+
+    /// Closes the connection to the server.
+    ///
+    /// # Returns
+    /// `Ok(())` if the connection was closed successfully, `Err` otherwise
+    pub fn shutdown(self) -> Result<(), Box<dyn std::error::Error>> {
+        self.connection
+            .lock()
+            .map_err(|_| "Connection poisoned")?
+            .close()?;
+        self.reader_handle
+            .join()
+            .map_err(|_| "Thread join failed")?;
+        Ok(())
+    }
+}
+
+impl<Command, Response> Controller<Command, Response> for RemoteController<Command, Response>
+where
+    Command: Serialize,
+    Response: DeserializeOwned + KnownStates + Send + 'static,
+{
+    fn send_command(&self, cmd: Command) -> Result<(), &'static str> {
+        self.send(Action::Cmd(cmd)).map(|_| ())
+    }
+
+    /// Requests the server could not hand to the machine yield no response here; their
+    /// errors are kept for `check_errors`.
+    fn check_responses(&self) -> Vec<Response> {
+        self.drain();
+        self.unclaimed.take()
+    }
+
+    fn request(&self, cmd: Command) -> Result<Response, &'static str> {
+        let id = self.send(Action::Cmd(cmd))?;
+        response(self.wait(id)?)
+    }
+
+    fn request_priority(&self, cmd: Command) -> Result<Response, &'static str> {
+        let id = self.send(Action::Priority(cmd))?;
+        response(self.wait(id)?)
+    }
+
+    /// A state the machine type doesn't have is an error.
+    fn active_states(&self) -> Result<Vec<StateName>, &'static str> {
+        let id = self.send(Action::Query(Query::ActiveStates))?;
+        match self.wait(id)? {
+            Outcome::ActiveStates(states) => states
+                .iter()
+                .map(|state| {
+                    Response::STATES
+                        .iter()
+                        .copied()
+                        .find(|known| known == state)
+                        .ok_or("Unknown state")
+                })
+                .collect(),
+            Outcome::Response(_) | Outcome::AllowedCommands(_) => Err("Unexpected reply"),
+            Outcome::Error(err) => Err(error_message(&err)),
+        }
+    }
+
+    fn allowed_commands(&self) -> Result<Vec<Command::Kind>, &'static str>
+    where
+        Command: KindedCommand,
+    {
+        let id = self.send(Action::Query(Query::AllowedCommands))?;
+        match self.wait(id)? {
+            Outcome::AllowedCommands(names) => kinds_named::<Command>(names),
+            Outcome::Response(_) | Outcome::ActiveStates(_) => Err("Unexpected reply"),
+            Outcome::Error(err) => Err(error_message(&err)),
+        }
+    }
+}
+
+fn response<Response>(outcome: Outcome<Response>) -> Result<Response, &'static str> {
+    match outcome {
+        Outcome::Response(response) => Ok(response),
+        Outcome::AllowedCommands(_) | Outcome::ActiveStates(_) => Err("Unexpected reply"),
+        Outcome::Error(err) => Err(error_message(&err)),
+    }
+}
+
+fn error_message(err: &ProtocolError) -> &'static str {
+    match err {
+        ProtocolError::UnknownMachine(_) => "Unknown machine",
+        ProtocolError::Malformed(_) => "Malformed request",
+        ProtocolError::MachineUnavailable => "Machine unavailable",
+        ProtocolError::UnknownZone(_) => "Unknown zone",
+        ProtocolError::ZoneTripped(_) => "Safety zone tripped",
+        ProtocolError::Interlocked(_) => "Interlocked",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::LatheController;
+    use crate::machines::mill::FsmController;
+    use crate::remote::server::{Registry, Server};
+    use std::net::{SocketAddr, TcpListener};
+
+    fn setup_server() -> SocketAddr {
+        let mut registry = Registry::new();
+        registry.add("lathe-1", LatheController::create(Box::default()));
+        registry.add("mill-1", FsmController::create(Box::default()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(registry);
+        thread::spawn(move || server.serve_tcp(listener));
+        address
+    }
+
+    fn spin_and_feed(
+        controller: &impl Controller<LatheCommand, LatheResponse>,
+    ) -> Vec<LatheResponse> {
+        vec![
+            controller
+                .request(LatheCommand::StartSpinning(800))
+                .unwrap(),
+            controller.request(LatheCommand::Feed(150)).unwrap(),
+            controller.request(LatheCommand::Acknowledge).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn remote_behaves_like_local() {
+        let address = setup_server();
+        let local = LatheController::create(Box::default());
+        let remote = RemoteLatheController::connect_tcp(address, "lathe-1").unwrap();
+
+        assert_eq!(spin_and_feed(&local), spin_and_feed(&remote));
+
+        local.shutdown().unwrap();
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn send_and_check_responses() {
+        let address = setup_server();
+        let remote = RemoteMillController::connect_tcp(address, "mill-1").unwrap();
+
+        remote
+            .send_command(MillCommand::StartSpinning(800))
+            .unwrap();
+        remote.send_command(MillCommand::Move(-50)).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let responses = remote.check_responses();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0], MillResponse::Status { state: "Spinning" });
+        assert_eq!(responses[1], MillResponse::Status { state: "Moving" });
+
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn responses_arriving_during_a_request_are_kept() {
+        let address = setup_server();
+        let remote = RemoteMillController::connect_tcp(address, "mill-1").unwrap();
+        remote
+            .send_command(MillCommand::StartSpinning(800))
+            .unwrap();
+
+        let moving = remote.request(MillCommand::Move(-50)).unwrap();
+
+        assert_eq!(moving, MillResponse::Status { state: "Moving" });
+        assert_eq!(
+            remote.check_responses(),
+            [MillResponse::Status { state: "Spinning" }]
+        );
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn errors_of_sent_commands_are_kept() {
+        let address = setup_server();
+        let remote = RemoteLatheController::connect_tcp(address, "lathe-9").unwrap();
+        remote.send_command(LatheCommand::Notaus).unwrap();
+
+        let result = remote.request(LatheCommand::Notaus);
+
+        assert_eq!(result, Err("Unknown machine"));
+        assert_eq!(remote.check_responses(), []);
+        assert_eq!(
+            remote.check_errors(),
+            [ProtocolError::UnknownMachine("lathe-9".to_string())]
+        );
+        assert_eq!(remote.check_errors(), []);
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn allowed_commands_like_local() {
+        let address = setup_server();
+        let local = LatheController::create(Box::default());
+        let remote = RemoteLatheController::connect_tcp(address, "lathe-1").unwrap();
+        spin_and_feed(&local);
+        spin_and_feed(&remote);
+
+        assert_eq!(
+            remote.allowed_commands().unwrap(),
+            local.allowed_commands().unwrap()
+        );
+
+        local.shutdown().unwrap();
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn active_states_like_local() {
+        let address = setup_server();
+        let local = LatheController::create(Box::default());
+        let remote = RemoteLatheController::connect_tcp(address, "lathe-1").unwrap();
+        local.request(LatheCommand::StartSpinning(800)).unwrap();
+        remote.request(LatheCommand::StartSpinning(800)).unwrap();
+
+        assert_eq!(
+            remote.active_states().unwrap(),
+            local.active_states().unwrap()
+        );
+
+        local.shutdown().unwrap();
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn unknown_state_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut requests = BufReader::new(stream.try_clone().unwrap()).lines();
+            requests.next();
+            writeln!(
+                &stream,
+                r#"{{"id":1,"machine":"lathe-1","active_states":["Flying"]}}"#
+            )
+            .unwrap();
+        });
+        let remote = RemoteLatheController::connect_tcp(address, "lathe-1").unwrap();
+
+        assert_eq!(remote.active_states(), Err("Unknown state"));
+        remote.shutdown().unwrap();
+    }
+
+    #[test]
+    fn unknown_machine() {
+        let address = setup_server();
+        let remote = RemoteLatheController::connect_tcp(address, "lathe-9").unwrap();
+
+        let result = remote.request(LatheCommand::Notaus);
+
+        assert_eq!(result, Err("Unknown machine"));
+        remote.shutdown().unwrap();
+    }
+}
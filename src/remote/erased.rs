@@ -0,0 +1,186 @@
+//! Controllers taking and giving JSON values
+//!
+//! A server's registry and a cell both hold machines with different command types side by
+//! side. [`Erased`] is the one place commands are decoded from JSON values, responses encoded
+//! to them and controller errors mapped onto [`ProtocolError`], so both report the same
+//! errors for the same mistakes.
+
+use std::any::TypeId;
+use std::marker::PhantomData;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::protocol::{Outcome, ProtocolError};
+use crate::machines::shared::{Controller, MachineController, StateName};
+
+/// Type-erased access to a controller, so machines with different command types can be kept
+/// together.
+pub trait ErasedController {
+    fn send_command(&self, cmd: Value) -> Result<(), ProtocolError>;
+
+    fn check_responses(&self) -> Vec<Value>;
+
+    fn request(&self, cmd: Value) -> Outcome<Value>;
+
+    /// Like `request`, but overtakes the commands still queued.
+    fn request_priority(&self, cmd: Value) -> Outcome<Value>;
+
+    /// The machine's states once every command sent so far has been handled
+    fn active_states(&self) -> Result<Vec<StateName>, ProtocolError>;
+
+    /// The type of the commands the machine takes
+    fn command_type(&self) -> TypeId;
+}
+
+/// A controller behind JSON values
+pub struct Erased<Ctrl, Command, Response> {
+    controller: Ctrl,
+    messages: PhantomData<fn(Command) -> Response>,
+}
+
+impl<Ctrl, Command, Response> Erased<Ctrl, Command, Response>
+where
+    Command: DeserializeOwned,
+    Response: Serialize,
+{
+    pub fn new(controller: Ctrl) -> Self {
+        Self {
+            controller,
+            messages: PhantomData,
+        }
+    }
+
+    fn decode(cmd: Value) -> Result<Command, ProtocolError> {
+        serde_json::from_value(cmd).map_err(|err| ProtocolError::Malformed(err.to_string()))
+    }
+
+    fn answer(response: Result<Response, &'static str>) -> Outcome<Value> {
+        match response {
+            Ok(response) => Outcome::Response(to_value(&response)),
+            Err(err) => Outcome::Error(unavailable(err)),
+        }
+    }
+}
+
+impl<Ctrl, Command, Response> ErasedController for Erased<Ctrl, Command, Response>
+where
+    Ctrl: Controller<Command, Response>,
+    Command: DeserializeOwned + 'static,
+    Response: Serialize,
+{
+    fn send_command(&self, cmd: Value) -> Result<(), ProtocolError> {
+        self.controller
+            .send_command(Self::decode(cmd)?)
+            .map_err(unavailable)
+    }
+
+    fn check_responses(&self) -> Vec<Value> {
+        self.controller
+            .check_responses()
+            .iter()
+            .map(to_value)
+            .collect()
+    }
+
+    fn request(&self, cmd: Value) -> Outcome<Value> {
+        match Self::decode(cmd) {
+            Err(err) => Outcome::Error(err),
+            Ok(cmd) => Self::answer(self.controller.request(cmd)),
+        }
+    }
+
+    fn request_priority(&self, cmd: Value) -> Outcome<Value> {
+        match Self::decode(cmd) {
+            Err(err) => Outcome::Error(err),
+            Ok(cmd) => Self::answer(self.controller.request_priority(cmd)),
+        }
+    }
+
+    fn active_states(&self) -> Result<Vec<StateName>, ProtocolError> {
+        self.controller.active_states().map_err(unavailable)
+    }
+
+    fn command_type(&self) -> TypeId {
+        TypeId::of::<Command>()
+    }
+}
+
+/// Sequence numbered access for streaming every response back to the client of its command
+impl<Command, Response> Erased<MachineController<Command, Response>, Command, Response>
+where
+    Command: DeserializeOwned + Send + 'static,
+    Response: Serialize + Send + 'static,
+{
+    /// Sends a command, ahead of the queued ones for `priority`, and returns its sequence
+    /// number.
+    pub(crate) fn send_numbered(&self, cmd: Value, priority: bool) -> Result<u64, ProtocolError> {
+        let cmd = Self::decode(cmd)?;
+        let sent = if priority {
+            self.controller.push_priority(cmd)
+        } else {
+            self.controller.send(cmd)
+        };
+        sent.map_err(unavailable)
+    }
+
+    /// Responses with the sequence number of their command, left typed so the reply keeps
+    /// the field order of the response.
+    pub(crate) fn check_numbered(&self) -> Vec<(u64, Response)> {
+        self.controller.check_numbered()
+    }
+
+    pub(crate) fn deferred_seqs(&self) -> Result<Vec<u64>, ProtocolError> {
+        self.controller.deferred_seqs().map_err(unavailable)
+    }
+
+    pub(crate) fn allowed_command_names(&self) -> Result<Vec<&'static str>, ProtocolError> {
+        self.controller.allowed_command_names().map_err(unavailable)
+    }
+}
+
+fn unavailable(_: &'static str) -> ProtocolError {
+    ProtocolError::MachineUnavailable
+}
+
+pub(crate) fn to_value<Message: Serialize>(message: &Message) -> Value {
+    serde_json::to_value(message).expect("machine messages serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::{LatheCommand, LatheController, LatheResponse};
+    use serde_json::json;
+
+    #[test]
+    fn command_of_another_type_is_malformed() {
+        let erased =
+            Erased::<_, LatheCommand, LatheResponse>::new(LatheController::create(Box::default()));
+
+        assert!(matches!(
+            erased.send_command(json!({"Move": 5})),
+            Err(ProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            erased.send_numbered(json!({"Move": 5}), true),
+            Err(ProtocolError::Malformed(_))
+        ));
+        assert_eq!(erased.check_numbered(), vec![]);
+    }
+
+    #[test]
+    fn responses_are_encoded() {
+        let erased =
+            Erased::<_, LatheCommand, LatheResponse>::new(LatheController::create(Box::default()));
+
+        let outcome = erased.request(json!({"StartSpinning": 800}));
+
+        assert_eq!(
+            outcome,
+            Outcome::Response(json!({"Status": {"state": "Spinning"}}))
+        );
+        assert_eq!(erased.active_states(), Ok(vec!["Spinning"]));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod client;
+pub mod erased;
+pub mod protocol;
+pub mod server;
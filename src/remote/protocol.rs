@@ -0,0 +1,282 @@
+//! Line-delimited JSON wire protocol
+//!
+//! Every message is a single JSON object terminated by a newline, so clients written in any
+//! language can talk to a machine without linking this crate.
+//!
+//! A request addresses one machine by name and carries one of its commands:
+//! ```text
+//! {"id":1,"machine":"lathe-1","cmd":{"StartSpinning":1000}}
+//! {"id":2,"machine":"lathe-1","cmd":"Notaus"}
+//! ```
+//!
+//! An emergency stop is sent as `priority` instead of `cmd`, so it overtakes the commands
+//! still queued for the machine:
+//! ```text
+//! {"id":7,"machine":"lathe-1","priority":"Notaus"}
+//! ```
+//!
+//! Instead of a command, a request can carry a query about the machine:
+//! ```text
+//! {"id":5,"machine":"lathe-1","query":"AllowedCommands"}
+//! {"id":6,"machine":"lathe-1","query":"ActiveStates"}
+//! ```
+//!
+//! Every request is answered by a reply with the same `id`. The reply either holds the
+//! machine's response, the answer to a query or a protocol error:
+//! ```text
+//! {"id":1,"machine":"lathe-1","response":{"Status":{"state":"Spinning"}}}
+//! {"id":3,"machine":"lathe-1","response":{"InvalidTransition":{"current_state":"Off","attempted_command":{"Feed":300}}}}
+//! {"id":4,"machine":"lathe-9","error":{"UnknownMachine":"lathe-9"}}
+//! {"id":5,"machine":"lathe-1","allowed_commands":["StartSpinning","Notaus"]}
+//! {"id":6,"machine":"lathe-1","active_states":["Off"]}
+//! ```
+//!
+//! A command can be answered more than once: a deferred command gets its final response once
+//! the machine takes it, and the internal events a command raises are answered under the
+//! command's `id` too. These replies arrive whenever the machine gets to them, in between the
+//! replies to later requests.
+//!
+//! Commands and responses use the serde representation of the machine's command and response
+//! enums: unit variants are plain strings, variants with a value are single-key objects.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// A command or query addressed to a named machine
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request<Command> {
+    pub id: u64,
+    pub machine: String,
+    #[serde(flatten)]
+    pub action: Action<Command>,
+}
+
+/// What a request asks the machine to do
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action<Command> {
+    Cmd(Command),
+    /// A command handled before the commands still queued
+    Priority(Command),
+    Query(Query),
+}
+
+/// Questions about a machine that leave it untouched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Query {
+    /// The kinds of the commands the machine accepts once the earlier requests are handled
+    AllowedCommands,
+    /// The states the machine is in once the earlier requests are handled
+    ActiveStates,
+}
+
+/// Answer to the [`Request`] with the same `id`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply<Response> {
+    pub id: u64,
+    pub machine: String,
+    #[serde(flatten)]
+    pub outcome: Outcome<Response>,
+}
+
+/// Either the machine answered, or the request never reached it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome<Response> {
+    Response(Response),
+    /// Names of the command kinds, answering [`Query::AllowedCommands`]
+    AllowedCommands(Vec<String>),
+    /// Names of the states, answering [`Query::ActiveStates`]
+    ActiveStates(Vec<String>),
+    Error(ProtocolError),
+}
+
+/// Reasons a request could not be handed to a machine
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProtocolError {
+    /// The line is not valid JSON or does not match the machine's command type
+    Malformed(String),
+    UnknownMachine(String),
+    /// The machine's thread is gone
+    MachineUnavailable,
+    UnknownZone(String),
+    /// The machine's safety zone is tripped and must be acknowledged as a whole
+    ZoneTripped(String),
+    /// The state of the named machine forbids the command
+    Interlocked(String),
+}
+
+/// Serializes a message into a single protocol line without the trailing newline.
+pub fn encode<T: Serialize>(message: &T) -> serde_json::Result<String> {
+    serde_json::to_string(message)
+}
+
+/// Parses a single protocol line.
+pub fn decode<T: DeserializeOwned>(line: &str) -> serde_json::Result<T> {
+    serde_json::from_str(line.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::{LatheCommand, LatheResponse};
+    use crate::machines::mill::{MillCommand, MillResponse};
+
+    #[test]
+    fn decode_documented_request() {
+        let request: Request<LatheCommand> =
+            decode(r#"{"id":1,"machine":"lathe-1","cmd":{"StartSpinning":1000}}"#).unwrap();
+
+        assert_eq!(
+            request,
+            Request {
+                id: 1,
+                machine: String::from("lathe-1"),
+                action: Action::Cmd(LatheCommand::StartSpinning(1000)),
+            }
+        );
+    }
+
+    #[test]
+    fn unit_command_is_plain_string() {
+        let request = Request {
+            id: 2,
+            machine: String::from("lathe-1"),
+            action: Action::Cmd(LatheCommand::Notaus),
+        };
+
+        assert_eq!(
+            encode(&request).unwrap(),
+            r#"{"id":2,"machine":"lathe-1","cmd":"Notaus"}"#
+        );
+    }
+
+    #[test]
+    fn priority_command_round_trip() {
+        let request = Request {
+            id: 7,
+            machine: String::from("lathe-1"),
+            action: Action::Priority(LatheCommand::Notaus),
+        };
+
+        let line = encode(&request).unwrap();
+
+        assert_eq!(line, r#"{"id":7,"machine":"lathe-1","priority":"Notaus"}"#);
+        assert_eq!(decode::<Request<LatheCommand>>(&line).unwrap(), request);
+    }
+
+    #[test]
+    fn query_round_trip() {
+        let request: Request<LatheCommand> = Request {
+            id: 5,
+            machine: String::from("lathe-1"),
+            action: Action::Query(Query::AllowedCommands),
+        };
+
+        let line = encode(&request).unwrap();
+
+        assert_eq!(
+            line,
+            r#"{"id":5,"machine":"lathe-1","query":"AllowedCommands"}"#
+        );
+        assert_eq!(decode::<Request<LatheCommand>>(&line).unwrap(), request);
+    }
+
+    #[test]
+    fn allowed_commands_reply_round_trip() {
+        let reply: Reply<LatheResponse> = Reply {
+            id: 5,
+            machine: String::from("lathe-1"),
+            outcome: Outcome::AllowedCommands(vec![
+                String::from("StartSpinning"),
+                String::from("Notaus"),
+            ]),
+        };
+
+        let line = encode(&reply).unwrap();
+
+        assert_eq!(
+            line,
+            r#"{"id":5,"machine":"lathe-1","allowed_commands":["StartSpinning","Notaus"]}"#
+        );
+        assert_eq!(decode::<Reply<LatheResponse>>(&line).unwrap(), reply);
+    }
+
+    #[test]
+    fn status_reply_round_trip() {
+        let reply = Reply {
+            id: 1,
+            machine: String::from("mill-1"),
+            outcome: Outcome::Response(MillResponse::Status { state: "Moving" }),
+        };
+
+        let line = encode(&reply).unwrap();
+
+        assert_eq!(
+            line,
+            r#"{"id":1,"machine":"mill-1","response":{"Status":{"state":"Moving"}}}"#
+        );
+        assert_eq!(decode::<Reply<MillResponse>>(&line).unwrap(), reply);
+    }
+
+    #[test]
+    fn invalid_transition_is_structured() {
+        let reply = Reply {
+            id: 3,
+            machine: String::from("lathe-1"),
+            outcome: Outcome::Response(LatheResponse::InvalidTransition {
+                current_state: "Off",
+                attempted_command: LatheCommand::Feed(300),
+            }),
+        };
+
+        let line = encode(&reply).unwrap();
+
+        assert_eq!(
+            line,
+            r#"{"id":3,"machine":"lathe-1","response":{"InvalidTransition":{"current_state":"Off","attempted_command":{"Feed":300}}}}"#
+        );
+        assert_eq!(decode::<Reply<LatheResponse>>(&line).unwrap(), reply);
+    }
+
+    #[test]
+    fn error_reply_round_trip() {
+        let reply: Reply<MillResponse> = Reply {
+            id: 4,
+            machine: String::from("mill-9"),
+            outcome: Outcome::Error(ProtocolError::UnknownMachine(String::from("mill-9"))),
+        };
+
+        let line = encode(&reply).unwrap();
+
+        assert_eq!(
+            line,
+            r#"{"id":4,"machine":"mill-9","error":{"UnknownMachine":"mill-9"}}"#
+        );
+        assert_eq!(decode::<Reply<MillResponse>>(&line).unwrap(), reply);
+    }
+
+    #[test]
+    fn unknown_state_is_rejected() {
+        let result = decode::<LatheResponse>(r#"{"Status":{"state":"Flying"}}"#);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn every_mill_command_round_trips() {
+        let commands = [
+            MillCommand::StartSpinning(800),
+            MillCommand::StopSpinning,
+            MillCommand::Move(-50),
+            MillCommand::StopMoving,
+            MillCommand::Notaus,
+            MillCommand::Acknowledge,
+        ];
+
+        for cmd in commands {
+            let line = encode(&cmd).unwrap();
+            assert_eq!(decode::<MillCommand>(&line).unwrap(), cmd);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+//! Socket server exposing machine controllers over the wire protocol
+//!
+//! Cell controllers connect over TCP or a Unix socket and address machines by the name they
+//! were registered under. Each connection is served by its own thread; requests to the same
+//! machine are serialized.
+//!
+//! Responses are streamed: every response a machine gives for a command goes back to the
+//! client that sent it under the request's `id`. Besides the first response these are the
+//! final response of a deferred command and the responses to the internal events a command
+//! raises, which arrive while the machine handles later requests.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::erased::{Erased, ErasedController};
+use super::protocol::{Action, Outcome, ProtocolError, Query, Reply, Request, decode, encode};
+use crate::machines::shared::MachineController;
+
+/// Named machines reachable through a [`Server`]
+#[derive(Default)]
+pub struct Registry {
+    machines: HashMap<String, Box<dyn Endpoint>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes a controller reachable under `name`, replacing any machine registered before.
+    pub fn add<Command, Response>(
+        &mut self,
+        name: &str,
+        controller: MachineController<Command, Response>,
+    ) where
+        Command: DeserializeOwned + Send + 'static,
+        Response: Serialize + Send + 'static,
+    {
+        self.machines.insert(
+            name.to_string(),
+            Box::new(Mutex::new(Served {
+                controller: Erased::new(controller),
+                waiting: HashMap::new(),
+            })),
+        );
+    }
+
+    /// Names of all registered machines
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.machines.keys().map(String::as_str)
+    }
+
+    /// Handles a single protocol line, sending the reply lines it causes to `replies`.
+    ///
+    /// Replies for commands other clients sent earlier go to the `replies` of those clients.
+    pub fn handle_line(
+        &self,
+        line: &str,
+        replies: &mpsc::Sender<String>,
+    ) -> serde_json::Result<()> {
+        let request = match decode::<Request<serde_json::Value>>(line) {
+            Ok(request) => request,
+            Err(err) => {
+                return reply::<()>(
+                    replies,
+                    0,
+                    "",
+                    Outcome::Error(ProtocolError::Malformed(err.to_string())),
+                );
+            }
+        };
+
+        match self.machines.get(&request.machine) {
+            Some(machine) => machine.dispatch(request, replies),
+            None => reply::<()>(
+                replies,
+                request.id,
+                &request.machine,
+                Outcome::Error(ProtocolError::UnknownMachine(request.machine.clone())),
+            ),
+        }
+    }
+}
+
+/// Sends one reply line, dropping it if the client is gone.
+fn reply<Response: Serialize>(
+    replies: &mpsc::Sender<String>,
+    id: u64,
+    machine: &str,
+    outcome: Outcome<Response>,
+) -> serde_json::Result<()> {
+    let line = encode(&Reply {
+        id,
+        machine: machine.to_string(),
+        outcome,
+    })?;
+    let _ = replies.send(line);
+    Ok(())
+}
+
+/// A registered machine answering the requests addressed to it
+trait Endpoint: Send + Sync {
+    fn dispatch(
+        &self,
+        request: Request<serde_json::Value>,
+        replies: &mpsc::Sender<String>,
+    ) -> serde_json::Result<()>;
+}
+
+/// A registered controller and the clients still waiting for its responses
+struct Served<Command, Response>
+where
+    Command: Send + 'static,
+    Response: Send + 'static,
+{
+    controller: Erased<MachineController<Command, Response>, Command, Response>,
+    /// Request id and client of every command that may still be answered, by sequence number
+    waiting: HashMap<u64, (u64, mpsc::Sender<String>)>,
+}
+
+impl<Command, Response> Served<Command, Response>
+where
+    Command: DeserializeOwned + Send + 'static,
+    Response: Serialize + Send + 'static,
+{
+    /// Registers the client of a command that was just sent and streams the responses.
+    fn stream(
+        &mut self,
+        id: u64,
+        machine: &str,
+        sent: Result<u64, ProtocolError>,
+        replies: &mpsc::Sender<String>,
+    ) -> serde_json::Result<()> {
+        match sent {
+            Ok(seq) => {
+                self.waiting.insert(seq, (id, replies.clone()));
+                self.settle(machine)
+            }
+            Err(err) => reply::<()>(replies, id, machine, Outcome::Error(err)),
+        }
+    }
+
+    /// Waits until the machine handled every command sent so far and hands each response to
+    /// the client of its command.
+    ///
+    /// Only the clients of deferred commands keep waiting. If the machine is gone, the clients
+    /// that got no response are told so instead.
+    fn settle(&mut self, machine: &str) -> serde_json::Result<()> {
+        let held = self.controller.deferred_seqs();
+        let mut answered = Vec::new();
+        for (seq, response) in self.controller.check_numbered() {
+            if let Some((id, replies)) = self.waiting.get(&seq) {
+                reply(replies, *id, machine, Outcome::Response(response))?;
+                answered.push(seq);
+            }
+        }
+        match held {
+            Ok(held) => self.waiting.retain(|seq, _| held.contains(seq)),
+            Err(_) => {
+                for (seq, (id, replies)) in self.waiting.drain() {
+                    if !answered.contains(&seq) {
+                        reply::<()>(
+                            &replies,
+                            id,
+                            machine,
+                            Outcome::Error(ProtocolError::MachineUnavailable),
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Command, Response> Endpoint for Mutex<Served<Command, Response>>
+where
+    Command: DeserializeOwned + Send + 'static,
+    Response: Serialize + Send + 'static,
+{
+    fn dispatch(
+        &self,
+        request: Request<serde_json::Value>,
+        replies: &mpsc::Sender<String>,
+    ) -> serde_json::Result<()> {
+        let (id, machine) = (request.id, request.machine.as_str());
+        let Ok(mut served) = self.lock() else {
+            return reply::<()>(
+                replies,
+                id,
+                machine,
+                Outcome::Error(ProtocolError::MachineUnavailable),
+            );
+        };
+        let outcome = match request.action {
+            Action::Cmd(cmd) => {
+                let sent = served.controller.send_numbered(cmd, false);
+                return served.stream(id, machine, sent, replies);
+            }
+            Action::Priority(cmd) => {
+                let sent = served.controller.send_numbered(cmd, true);
+                return served.stream(id, machine, sent, replies);
+            }
+            Action::Query(Query::AllowedCommands) => {
+                match served.controller.allowed_command_names() {
+                    Ok(names) => {
+                        Outcome::AllowedCommands(names.into_iter().map(String::from).collect())
+                    }
+                    Err(err) => Outcome::Error(err),
+                }
+            }
+            Action::Query(Query::ActiveStates) => match served.controller.active_states() {
+                Ok(states) => Outcome::ActiveStates(states.into_iter().map(String::from).collect()),
+                Err(err) => Outcome::Error(err),
+            },
+        };
+
+        reply::<Response>(replies, id, machine, outcome)
+    }
+}
+
+/// Accepts connections and forwards their requests to the registered machines
+#[derive(Clone)]
+pub struct Server {
+    registry: Arc<Registry>,
+}
+
+impl Server {
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry: Arc::new(registry),
+        }
+    }
+
+    /// Serves TCP clients until the listener fails.
+    pub fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let reader = BufReader::new(stream.try_clone()?);
+            self.spawn_connection(reader, stream);
+        }
+        Ok(())
+    }
+
+    /// Serves Unix socket clients until the listener fails.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let reader = BufReader::new(stream.try_clone()?);
+            self.spawn_connection(reader, stream);
+        }
+        Ok(())
+    }
+
+    fn spawn_connection<R, W>(&self, reader: R, writer: W)
+    where
+        R: BufRead + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let server = self.clone();
+        thread::spawn(move || {
+            let _ = server.serve_connection(reader, writer);
+        });
+    }
+
+    /// Answers every request line read from `reader` until the client hangs up.
+    ///
+    /// Replies are written by a thread of their own, so responses streamed for this client
+    /// while it sends nothing still reach it.
+    pub fn serve_connection<W>(&self, reader: impl BufRead, writer: W) -> io::Result<()>
+    where
+        W: Write + Send + 'static,
+    {
+        let (replies, outgoing) = mpsc::channel();
+        thread::spawn(move || write_replies(outgoing, writer));
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            self.registry
+                .handle_line(&line, &replies)
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes reply lines until no machine can answer the client anymore or the client is gone.
+fn write_replies(outgoing: mpsc::Receiver<String>, mut writer: impl Write) -> io::Result<()> {
+    for line in outgoing {
+        writeln!(writer, "{}", line)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machines::lathe::{LatheCommand, LatheController, LatheResponse};
+    use crate::machines::mill::{FsmController, MillCommand, MillResponse};
+    use std::net::TcpStream;
+
+    fn setup_registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.add("lathe-1", LatheController::create(Box::default()));
+        registry.add("mill-1", FsmController::create(Box::default()));
+        registry
+    }
+
+    fn answer(registry: &Registry, line: &str) -> String {
+        let (replies, outgoing) = mpsc::channel();
+        registry.handle_line(line, &replies).unwrap();
+        outgoing.try_recv().unwrap()
+    }
+
+    #[test]
+    fn routes_by_machine_name() {
+        let registry = setup_registry();
+
+        let lathe_reply = answer(
+            &registry,
+            r#"{"id":1,"machine":"lathe-1","cmd":{"StartSpinning":1000}}"#,
+        );
+        let mill_reply = answer(
+            &registry,
+            r#"{"id":2,"machine":"mill-1","cmd":"StopMoving"}"#,
+        );
+
+        assert_eq!(
+            decode::<Reply<LatheResponse>>(&lathe_reply)
+                .unwrap()
+                .outcome,
+            Outcome::Response(LatheResponse::Status { state: "Spinning" })
+        );
+        assert_eq!(
+            decode::<Reply<MillResponse>>(&mill_reply).unwrap().outcome,
+            Outcome::Response(MillResponse::InvalidTransition {
+                current_state: "Off",
+                attempted_command: MillCommand::StopMoving,
+            })
+        );
+    }
+
+    #[test]
+    fn priority_command_is_handled() {
+        let registry = setup_registry();
+
+        let reply = answer(
+            &registry,
+            r#"{"id":1,"machine":"lathe-1","priority":"Notaus"}"#,
+        );
+
+        assert_eq!(
+            reply,
+            r#"{"id":1,"machine":"lathe-1","response":{"Status":{"state":"Notaus"}}}"#
+        );
+    }
+
+    #[test]
+    fn allowed_commands_query() {
+        let registry = setup_registry();
+        answer(
+            &registry,
+            r#"{"id":1,"machine":"mill-1","cmd":{"StartSpinning":800}}"#,
+        );
+
+        let reply = answer(
+            &registry,
+            r#"{"id":2,"machine":"mill-1","query":"AllowedCommands"}"#,
+        );
+
+        assert_eq!(
+            reply,
+            r#"{"id":2,"machine":"mill-1","allowed_commands":["StopSpinning","Move","Notaus"]}"#
+        );
+    }
+
+    #[test]
+    fn active_states_query() {
+        let registry = setup_registry();
+        answer(
+            &registry,
+            r#"{"id":1,"machine":"lathe-1","cmd":{"StartSpinning":1000}}"#,
+        );
+
+        let reply = answer(
+            &registry,
+            r#"{"id":2,"machine":"lathe-1","query":"ActiveStates"}"#,
+        );
+
+        assert_eq!(
+            reply,
+            r#"{"id":2,"machine":"lathe-1","active_states":["Spinning"]}"#
+        );
+    }
+
+    #[test]
+    fn unknown_machine() {
+        let registry = setup_registry();
+
+        let reply = answer(&registry, r#"{"id":7,"machine":"drill-1","cmd":"Notaus"}"#);
+
+        assert_eq!(
+            reply,
+            r#"{"id":7,"machine":"drill-1","error":{"UnknownMachine":"drill-1"}}"#
+        );
+    }
+
+    #[test]
+    fn command_of_other_machine_is_malformed() {
+        let registry = setup_registry();
+
+        let reply = answer(
+            &registry,
+            r#"{"id":3,"machine":"mill-1","cmd":{"Feed":300}}"#,
+        );
+
+        let reply = decode::<Reply<MillResponse>>(&reply).unwrap();
+        assert_eq!(reply.id, 3);
+        assert!(matches!(
+            reply.outcome,
+            Outcome::Error(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn garbage_line_is_malformed() {
+        let registry = setup_registry();
+
+        let reply = answer(&registry, "StartSpinning 1000");
+
+        let reply = decode::<Reply<LatheResponse>>(&reply).unwrap();
+        assert_eq!(reply.id, 0);
+        assert!(matches!(
+            reply.outcome,
+            Outcome::Error(ProtocolError::Malformed(_))
+        ));
+    }
+
+    mod streaming {
+        use super::*;
+        use crate::machines::shared::{
+            FSM, Outbox, StateHandler, StateInfo, StateName, Transition, command_kind, fsm,
+        };
+        use serde::Deserialize;
+        use std::marker::PhantomData;
+
+        #[derive(Debug)]
+        pub struct Idle;
+        #[derive(Debug)]
+        pub struct RampingUp;
+        #[derive(Debug)]
+        pub struct Spinning;
+
+        #[derive(Default, Debug)]
+        pub struct SpindleData {
+            events: Outbox<SpindleCommand>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum SpindleCommand {
+            Start,
+            Reached,
+            Ready,
+            Feed(u32),
+        }
+
+        command_kind! {
+            SpindleCommand => SpindleCommandKind { Start, Reached, Ready, Feed }
+        }
+
+        #[derive(Debug, PartialEq, Serialize)]
+        pub enum SpindleResponse {
+            Status {
+                state: StateName,
+            },
+            Updated {
+                state: StateName,
+            },
+            InvalidTransition {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+            Deferred {
+                current_state: StateName,
+                attempted_command: SpindleCommand,
+            },
+        }
+
+        fsm! {
+            StartState: Idle,
+            MachineData: SpindleData,
+            MachineCommand: SpindleCommand,
+            CommandKind: SpindleCommandKind,
+            MachineResponse: SpindleResponse,
+            InternalEvents: events,
+            StateHandlerTrait: StateHandler,
+            Controller: MachineController,
+            Idle: {
+                Start => start(self) -> RampingUp,
+            },
+            RampingUp: [unhandled(defer)] {
+                Reached => reached(self) -> Spinning { self.data.events.post(SpindleCommand::Ready); },
+            },
+            Spinning: {
+                Ready => ready(self) [internal],
+                Feed(_feed: u32) => feed(self) -> Spinning,
+            },
+        }
+
+        #[test]
+        fn later_responses_reach_the_client_that_sent_the_command() {
+            let mut registry = Registry::new();
+            registry.add("spindle", FsmController::create(Box::default()));
+            let (first, first_replies) = mpsc::channel();
+            let (second, second_replies) = mpsc::channel();
+            registry
+                .handle_line(r#"{"id":1,"machine":"spindle","cmd":"Start"}"#, &first)
+                .unwrap();
+            registry
+                .handle_line(r#"{"id":2,"machine":"spindle","cmd":{"Feed":10}}"#, &first)
+                .unwrap();
+
+            registry
+                .handle_line(r#"{"id":1,"machine":"spindle","cmd":"Reached"}"#, &second)
+                .unwrap();
+
+            assert_eq!(
+                first_replies.try_iter().collect::<Vec<_>>(),
+                vec![
+                    r#"{"id":1,"machine":"spindle","response":{"Status":{"state":"RampingUp"}}}"#,
+                    r#"{"id":2,"machine":"spindle","response":{"Deferred":{"current_state":"RampingUp","attempted_command":{"Feed":10}}}}"#,
+                    r#"{"id":2,"machine":"spindle","response":{"Status":{"state":"Spinning"}}}"#,
+                ]
+            );
+            assert_eq!(
+                second_replies.try_iter().collect::<Vec<_>>(),
+                vec![
+                    r#"{"id":1,"machine":"spindle","response":{"Status":{"state":"Spinning"}}}"#,
+                    r#"{"id":1,"machine":"spindle","response":{"Updated":{"state":"Spinning"}}}"#,
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(setup_registry());
+        thread::spawn(move || server.serve_tcp(listener));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        let mut replies = BufReader::new(client.try_clone().unwrap()).lines();
+        let requests = [
+            Request {
+                id: 1,
+                machine: String::from("lathe-1"),
+                action: Action::Cmd(LatheCommand::StartSpinning(1000)),
+            },
+            Request {
+                id: 2,
+                machine: String::from("lathe-1"),
+                action: Action::Cmd(LatheCommand::Notaus),
+            },
+        ];
+        for request in &requests {
+            writeln!(client, "{}", encode(request).unwrap()).unwrap();
+        }
+
+        let first: Reply<LatheResponse> = decode(&replies.next().unwrap().unwrap()).unwrap();
+        let second: Reply<LatheResponse> = decode(&replies.next().unwrap().unwrap()).unwrap();
+
+        assert_eq!(first.id, 1);
+        assert_eq!(
+            first.outcome,
+            Outcome::Response(LatheResponse::Status { state: "Spinning" })
+        );
+        assert_eq!(second.id, 2);
+        assert_eq!(
+            second.outcome,
+            Outcome::Response(LatheResponse::Status { state: "Notaus" })
+        );
+    }
+}